@@ -1,4 +1,5 @@
 use clap::{App, Arg};
+use std::time::Instant;
 use traf_client::Client;
 
 #[macro_use]
@@ -21,6 +22,10 @@ async fn main() {
         .takes_value(true)
         .default_value("1"),
     )
+    // Only GETs in the loop - reads are served concurrently, writes one at a time.
+    .arg(Arg::with_name("reads_only").short("r"))
+    // All tasks share one connection (clones of one client) instead of connecting each.
+    .arg(Arg::with_name("shared").short("s"))
     .get_matches();
 
   let raw_iteration = matches.value_of("iteration").unwrap();
@@ -32,17 +37,43 @@ async fn main() {
   info!("Iterations: {}", iteration);
   info!("Concurrency: {}", concurrency);
 
+  let reads_only = matches.is_present("reads_only");
+  if reads_only {
+    let mut client = Client::connect("0.0.0.0:4567")
+      .await
+      .expect("Failed creating a client");
+    client.set("foo", 123u8).await.expect("Cannot set value");
+  }
+
+  let shared_client = if matches.is_present("shared") {
+    Some(
+      Client::connect("0.0.0.0:4567")
+        .await
+        .expect("Failed creating a client"),
+    )
+  } else {
+    None
+  };
+
+  let started_at = Instant::now();
+
   let mut join_handles = Vec::new();
 
   for c in 0..concurrency {
+    let shared_client = shared_client.clone();
     let join_handle = tokio::spawn(async move {
-      let mut client = Client::connect("0.0.0.0:4567")
-        .await
-        .expect("Failed creating a client");
+      let mut client = match shared_client {
+        Some(client) => client,
+        None => Client::connect("0.0.0.0:4567")
+          .await
+          .expect("Failed creating a client"),
+      };
 
       for i in 0..iteration {
-        client.set("foo", 123u8).await.expect("Cannot set value");
-        info!("data sent c:{} i:{}", c, i);
+        if !reads_only {
+          client.set("foo", 123u8).await.expect("Cannot set value");
+          info!("data sent c:{} i:{}", c, i);
+        }
 
         let get_result = client.get("foo").await.expect("Cannot get value");
         info!(
@@ -61,4 +92,14 @@ async fn main() {
   for join_handle in join_handles {
     join_handle.await.expect("Failed closing thread");
   }
+
+  let elapsed = started_at.elapsed();
+  let ops_per_iteration = if reads_only { 1 } else { 2 };
+  let ops = (iteration * concurrency * ops_per_iteration) as f64;
+  println!(
+    "{} ops in {:?} ({:.0} ops/sec)",
+    ops,
+    elapsed,
+    ops / elapsed.as_secs_f64()
+  );
 }
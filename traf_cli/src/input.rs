@@ -0,0 +1,320 @@
+use std::str::FromStr;
+use traf_lib::request::{Opcode, Request};
+
+// Parses a typed line (eg. `SET key "some value" EX 10`) into a binary request, see
+// `traf_lib::request`. Words are separated by spaces. Double quoted words may hold spaces, `\"`,
+// `\\` and `\xHH` stand for a quote, a backslash and any byte in them.
+// Returns the opcode of the command with it - the one within for `NS <namespace> <command>`. None
+// for lines that are not a valid command.
+pub fn parse(line: &str) -> Option<(Opcode, Request)> {
+  let mut words = Words(split_words(line)?.into_iter());
+  let parsed = parse_command(&mut words)?;
+  Some(parsed).filter(|_| words.is_empty())
+}
+
+fn parse_command(words: &mut Words) -> Option<(Opcode, Request)> {
+  let request = match &words.word()?[..] {
+    b"HELLO" => Request::new(Opcode::Hello).number(words.number()?),
+    b"MULTI" => Request::new(Opcode::Multi),
+    b"EXEC" => Request::new(Opcode::Exec),
+    b"DISCARD" => Request::new(Opcode::Discard),
+    b"WATCH" => Request::new(Opcode::Watch).fields(&words.rest()),
+    b"SELECT" => Request::new(Opcode::Select).fields(&words.take(1)?),
+    b"SUBSCRIBE" => Request::new(Opcode::Subscribe).fields(&words.take(1)?),
+    b"SNAPSHOT" if words.option(b"RELEASE") => Request::new(Opcode::SnapshotRelease),
+    b"SNAPSHOT" => Request::new(Opcode::Snapshot),
+    b"NS" => {
+      let namespace = words.word()?;
+      let (opcode, request) = parse_command(words)?;
+      let request = Request::new(Opcode::Namespaced)
+        .field(namespace)
+        .field(Vec::from(request));
+      return Some((opcode, request));
+    }
+    b"LAST_REPLICATION_ID" => Request::new(Opcode::LastReplicationId),
+    // `SET <key> <value>[ EX <seconds>| PXAT <unix ms>]`
+    b"SET" => {
+      let key_and_value = words.take(2)?;
+      if words.option(b"EX") {
+        Request::new(Opcode::SetEx)
+          .fields(&key_and_value)
+          .number(words.number()?)
+      } else if words.option(b"PXAT") {
+        Request::new(Opcode::Set)
+          .fields(&key_and_value)
+          .number(words.number()?)
+      } else {
+        Request::new(Opcode::Set).fields(&key_and_value)
+      }
+    }
+    // `GET <key>[ AT <position>]`
+    b"GET" => {
+      let request = Request::new(Opcode::Get).fields(&words.take(1)?);
+      if words.option(b"AT") {
+        request.number(words.number()?)
+      } else {
+        request
+      }
+    }
+    b"CAS" => Request::new(Opcode::Cas)
+      .fields(&words.take(1)?)
+      .number(words.number()?)
+      .fields(&words.take(1)?),
+    b"SETNX" => Request::new(Opcode::SetNx).fields(&words.take(2)?),
+    b"SETXX" => Request::new(Opcode::SetXx).fields(&words.take(2)?),
+    b"GETSET" => Request::new(Opcode::GetSet).fields(&words.take(2)?),
+    b"DELETE" => Request::new(Opcode::Delete).fields(&words.take(1)?),
+    // `RENAME <src> <dst>[ NX]`
+    b"RENAME" => {
+      let keys = words.take(2)?;
+      let opcode = if words.option(b"NX") {
+        Opcode::RenameNx
+      } else {
+        Opcode::Rename
+      };
+      Request::new(opcode).fields(&keys)
+    }
+    b"COPY" => Request::new(Opcode::Copy).fields(&words.take(2)?),
+    b"MOVE" => Request::new(Opcode::Move).fields(&words.take(2)?),
+    b"INCR" => Request::new(Opcode::Incr).fields(&words.take(1)?),
+    b"DECR" => Request::new(Opcode::Decr).fields(&words.take(1)?),
+    b"INCRBY" => Request::new(Opcode::IncrBy)
+      .fields(&words.take(1)?)
+      .signed_number(words.number()?),
+    b"MGET" => Request::new(Opcode::MGet).fields(&words.rest()),
+    b"MSET" => Request::new(Opcode::MSet).fields(&words.rest()),
+    b"MDEL" => Request::new(Opcode::MDel).fields(&words.rest()),
+    // `SCAN <cursor>[ PREFIX <prefix>][ COUNT <count>]`
+    b"SCAN" => {
+      let cursor = words.take(1)?;
+      let mut prefix = vec![];
+      let mut count = None;
+      while !words.is_empty() {
+        if words.option(b"PREFIX") {
+          prefix = words.word()?;
+        } else if words.option(b"COUNT") {
+          count = Some(words.number()?);
+        } else {
+          return None;
+        }
+      }
+
+      let request = Request::new(Opcode::Scan).fields(&cursor).field(prefix);
+      match count {
+        Some(count) => request.number(count),
+        None => request,
+      }
+    }
+    b"RANGE" => Request::new(Opcode::Range).fields(&words.take(2)?),
+    b"APPEND" => Request::new(Opcode::Append).fields(&words.take(2)?),
+    b"GETRANGE" => Request::new(Opcode::GetRange)
+      .fields(&words.take(1)?)
+      .signed_number(words.number()?)
+      .signed_number(words.number()?),
+    b"SETRANGE" => Request::new(Opcode::SetRange)
+      .fields(&words.take(1)?)
+      .number(words.number()?)
+      .fields(&words.take(1)?),
+    b"STRLEN" => Request::new(Opcode::StrLen).fields(&words.take(1)?),
+    b"LPUSH" => Request::new(Opcode::LPush).fields(&words.rest()),
+    b"RPUSH" => Request::new(Opcode::RPush).fields(&words.rest()),
+    b"LPOP" => Request::new(Opcode::LPop).fields(&words.take(1)?),
+    b"RPOP" => Request::new(Opcode::RPop).fields(&words.take(1)?),
+    b"LRANGE" => Request::new(Opcode::LRange)
+      .fields(&words.take(1)?)
+      .signed_number(words.number()?)
+      .signed_number(words.number()?),
+    b"HSET" => Request::new(Opcode::HSet).fields(&words.rest()),
+    b"HGET" => Request::new(Opcode::HGet).fields(&words.take(2)?),
+    b"HDEL" => Request::new(Opcode::HDel).fields(&words.rest()),
+    b"HGETALL" => Request::new(Opcode::HGetAll).fields(&words.take(1)?),
+    b"SADD" => Request::new(Opcode::SAdd).fields(&words.rest()),
+    b"SREM" => Request::new(Opcode::SRem).fields(&words.rest()),
+    b"SMEMBERS" => Request::new(Opcode::SMembers).fields(&words.take(1)?),
+    b"SISMEMBER" => Request::new(Opcode::SIsMember).fields(&words.take(2)?),
+    b"EXPIRE" => Request::new(Opcode::Expire)
+      .fields(&words.take(1)?)
+      .number(words.number()?),
+    b"PEXPIREAT" => Request::new(Opcode::PExpireAt)
+      .fields(&words.take(1)?)
+      .number(words.number()?),
+    b"TTL" => Request::new(Opcode::Ttl).fields(&words.take(1)?),
+    b"PERSIST" => Request::new(Opcode::Persist).fields(&words.take(1)?),
+    b"STAT" => Request::new(Opcode::Stat).fields(&words.take(1)?),
+    // `HISTORY <key>[ LIMIT <n>]`
+    b"HISTORY" => with_limit(Request::new(Opcode::History), words)?,
+    b"FLUSHNS" => Request::new(Opcode::FlushNs),
+    b"FLUSHALL" => Request::new(Opcode::FlushAll),
+    b"DBSIZE" => Request::new(Opcode::DbSize),
+    // `KEYS <pattern>[ LIMIT <n>]`
+    b"KEYS" => with_limit(Request::new(Opcode::Keys), words)?,
+    _ => return None,
+  };
+  Some((request.opcode, request))
+}
+
+// Adds `<key or pattern>[ LIMIT <n>]` to the request.
+fn with_limit(request: Request, words: &mut Words) -> Option<Request> {
+  let request = request.fields(&words.take(1)?);
+  if words.option(b"LIMIT") {
+    Some(request.number(words.number()?))
+  } else {
+    Some(request)
+  }
+}
+
+// The words of a line, taken in order.
+struct Words(std::vec::IntoIter<Vec<u8>>);
+
+impl Words {
+  fn word(&mut self) -> Option<Vec<u8>> {
+    self.0.next()
+  }
+
+  fn number<N: FromStr>(&mut self) -> Option<N> {
+    std::str::from_utf8(&self.0.next()?).ok()?.parse().ok()
+  }
+
+  // The next `count` words, None if there are fewer left.
+  fn take(&mut self, count: usize) -> Option<Vec<Vec<u8>>> {
+    let words: Vec<Vec<u8>> = self.0.by_ref().take(count).collect();
+    Some(words).filter(|words| words.len() == count)
+  }
+
+  fn rest(&mut self) -> Vec<Vec<u8>> {
+    self.0.by_ref().collect()
+  }
+
+  // Takes the next word if it's the `name` of an option, eg. `NX`.
+  fn option(&mut self, name: &[u8]) -> bool {
+    let is_option = self.0.as_slice().first().map(Vec::as_slice) == Some(name);
+    if is_option {
+      self.0.next();
+    }
+    is_option
+  }
+
+  fn is_empty(&self) -> bool {
+    self.0.len() == 0
+  }
+}
+
+// None if a quote is left open or an escape is invalid.
+fn split_words(line: &str) -> Option<Vec<Vec<u8>>> {
+  let mut words = vec![];
+  let mut bytes = line.bytes().peekable();
+
+  loop {
+    while bytes.peek() == Some(&b' ') {
+      bytes.next();
+    }
+
+    let mut word = vec![];
+    match bytes.peek() {
+      None => return Some(words),
+      Some(b'"') => {
+        bytes.next();
+        loop {
+          match bytes.next()? {
+            b'"' => break,
+            b'\\' => match bytes.next()? {
+              ch @ b'"' | ch @ b'\\' => word.push(ch),
+              b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                word.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+              }
+              _ => return None,
+            },
+            ch => word.push(ch),
+          }
+        }
+        // A closing quote ends the word.
+        if bytes.peek().is_some_and(|ch| ch != &b' ') {
+          return None;
+        }
+      }
+      Some(_) => {
+        while let Some(ch) = bytes.next_if(|ch| ch != &b' ') {
+          word.push(ch);
+        }
+      }
+    }
+    words.push(word);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(line: &str) -> Option<Request> {
+    parse(line).map(|(_, request)| request)
+  }
+
+  #[test]
+  fn quoted_words_hold_any_bytes() {
+    assert_eq!(
+      Some(vec![
+        b"SET".to_vec(),
+        b"a key".to_vec(),
+        b"".to_vec(),
+        b"\"\\\x00\xff".to_vec(),
+      ]),
+      split_words(r#"SET  "a key" "" "\"\\\x00\xff""#)
+    );
+    assert_eq!(None, split_words(r#"SET "a key"#));
+    assert_eq!(None, split_words(r#"SET "a"b"#));
+    assert_eq!(None, split_words(r#"SET "\n""#));
+  }
+
+  #[test]
+  fn commands_encode_to_requests() {
+    assert_eq!(
+      Some(
+        Request::new(Opcode::SetEx)
+          .field("k")
+          .field("some value")
+          .number(10)
+      ),
+      request(r#"SET k "some value" EX 10"#)
+    );
+    assert_eq!(
+      Some(Request::new(Opcode::Get).field("k").number(3)),
+      request("GET k AT 3")
+    );
+    assert_eq!(
+      Some(Request::new(Opcode::RenameNx).field("a").field("b")),
+      request("RENAME a b NX")
+    );
+    assert_eq!(
+      Some(Request::new(Opcode::Scan).field("0").field("").number(5)),
+      request("SCAN 0 COUNT 5")
+    );
+    assert_eq!(
+      Some(Request::new(Opcode::IncrBy).field("k").signed_number(-2)),
+      request("INCRBY k -2")
+    );
+  }
+
+  #[test]
+  fn namespaced_commands_tell_the_opcode_within() {
+    let (opcode, request) = parse("NS other DBSIZE").unwrap();
+    assert_eq!(Opcode::DbSize, opcode);
+    assert_eq!(
+      Request::new(Opcode::Namespaced)
+        .field("other")
+        .field(Vec::from(Request::new(Opcode::DbSize))),
+      request
+    );
+  }
+
+  #[test]
+  fn invalid_lines_are_rejected() {
+    assert_eq!(None, request(""));
+    assert_eq!(None, request("GET"));
+    assert_eq!(None, request("GET a b"));
+    assert_eq!(None, request("INCRBY k x"));
+    assert_eq!(None, request("SCAN 0 LIMIT 5"));
+    assert_eq!(None, request("NOPE k"));
+  }
+}
@@ -4,8 +4,11 @@ use std::io::{stdin, stdout};
 use tokio::io;
 use tokio::net::TcpStream;
 use traf_lib::frame_reader::FramedTcpStream;
+use traf_lib::request::Opcode;
 use traf_lib::response_frame::ResponseFrame;
 
+mod input;
+
 #[macro_use]
 extern crate log;
 
@@ -45,7 +48,16 @@ async fn main() -> io::Result<()> {
     match input {
       "q" => break,
       _ => {
-        framed_stream.write_frame(input.as_bytes().to_vec()).await?;
+        let (opcode, request) = match input::parse(input) {
+          Some(parsed) => parsed,
+          None => {
+            println!("[invalid command]");
+            continue;
+          }
+        };
+
+        // One request in flight at a time, no need to tell them apart by id.
+        framed_stream.write_frame(0, request.into()).await?;
         info!("data sent");
 
         let bytes_in = framed_stream
@@ -54,22 +66,140 @@ async fn main() -> io::Result<()> {
           .expect("Cannot read response");
 
         match ResponseFrame::try_from(bytes_in.bytes) {
-          Ok(response_frame) => match response_frame {
-            ResponseFrame::Success => println!("[success]"),
-            ResponseFrame::ErrorInvalidCommand => println!("[invalid command]"),
-            ResponseFrame::ValueMissing => println!("[value missing]"),
-            ResponseFrame::Value(v) => {
-              match String::from_utf8(v) {
-                Ok(s) => println!("{:?}", s),
-                Err(e) => println!("{:?}", e),
-              };
+          // The key count is a little endian u64, not printable as a value.
+          Ok(ResponseFrame::Value(count)) if opcode == Opcode::DbSize => {
+            match <[u8; 8]>::try_from(count) {
+              Ok(count) => println!("[{} keys]", u64::from_le_bytes(count)),
+              Err(count) => print_response_frame(ResponseFrame::Value(count)),
             }
-          },
+          }
+          Ok(response_frame) => print_response_frame(response_frame),
           Err(_) => break,
         }
+
+        // A subscribed connection only receives pushed changes from now on.
+        if opcode == Opcode::Subscribe {
+          while let Some(frame) = framed_stream.read_frame().await {
+            match ResponseFrame::try_from(frame.bytes) {
+              Ok(response_frame) => print_response_frame(response_frame),
+              Err(_) => break,
+            }
+          }
+          break;
+        }
       }
     };
   }
 
   Ok(())
 }
+
+fn print_response_frame(response_frame: ResponseFrame) {
+  match response_frame {
+    ResponseFrame::Success => println!("[success]"),
+    ResponseFrame::ErrorInvalidCommand => println!("[invalid command]"),
+    ResponseFrame::ValueMissing => println!("[value missing]"),
+    ResponseFrame::ErrorVersionMismatch => println!("[version mismatch]"),
+    ResponseFrame::ErrorWrongType => println!("[wrong type]"),
+    ResponseFrame::ErrorOutOfMemory => println!("[out of memory]"),
+    ResponseFrame::ErrorKeyExists => println!("[key exists]"),
+    ResponseFrame::ErrorVersionUnavailable => println!("[version unavailable]"),
+    ResponseFrame::Value(v) => {
+      match String::from_utf8(v) {
+        Ok(s) => println!("{:?}", s),
+        Err(e) => println!("{:?}", e),
+      };
+    }
+    ResponseFrame::VersionedValue(version, v) => {
+      match String::from_utf8(v) {
+        Ok(s) => println!("{:?} [version {}]", s, version),
+        Err(e) => println!("{:?} [version {}]", e, version),
+      };
+    }
+    ResponseFrame::Values(values) => {
+      for (i, value) in values.into_iter().enumerate() {
+        match value.map(String::from_utf8) {
+          Some(Ok(s)) => println!("{}) {:?}", i + 1, s),
+          Some(Err(e)) => println!("{}) {:?}", i + 1, e),
+          None => println!("{}) [value missing]", i + 1),
+        };
+      }
+    }
+    ResponseFrame::KeyValues(entries) => print_key_values(entries),
+    ResponseFrame::Elements(elements) => {
+      for (i, element) in elements.into_iter().enumerate() {
+        println!("{}) {:?}", i + 1, String::from_utf8_lossy(&element));
+      }
+    }
+    ResponseFrame::ScanPage(cursor, entries) => {
+      print_key_values(entries);
+      println!("[cursor {}]", String::from_utf8_lossy(&cursor));
+    }
+    ResponseFrame::Queued => println!("[queued]"),
+    ResponseFrame::Change(key, Some(value)) => println!(
+      "[change] {:?} => {:?}",
+      String::from_utf8_lossy(&key),
+      String::from_utf8_lossy(&value)
+    ),
+    ResponseFrame::Change(key, None) => {
+      println!("[change] {:?} deleted", String::from_utf8_lossy(&key))
+    }
+    ResponseFrame::Flushed => println!("[change] namespace flushed"),
+    ResponseFrame::Results(results) => {
+      for (i, result) in results.into_iter().enumerate() {
+        print!("{}) ", i + 1);
+        print_response_frame(result);
+      }
+    }
+    ResponseFrame::Stat(stat) => {
+      println!("created at:  {} ms", stat.created_at);
+      println!("modified at: {} ms", stat.modified_at);
+      println!("accessed at: {} ms", stat.accessed_at);
+      println!("event id:    {}", stat.version);
+      println!("size:        {} bytes", stat.size);
+    }
+    ResponseFrame::History(events) => {
+      for (number, value) in events {
+        match value {
+          Some(value) => println!("[event {}] {:?}", number, String::from_utf8_lossy(&value)),
+          None => println!("[event {}] deleted", number),
+        };
+      }
+    }
+    ResponseFrame::Hello(server_info) => {
+      println!("protocol:     {}", server_info.protocol_version);
+      println!("version:      {}", server_info.version);
+      println!("role:         {}", server_info.role);
+      match server_info.last_replication_id {
+        Some(id) => println!("last event:   {}", id),
+        None => println!("last event:   none"),
+      };
+      println!("capabilities: {}", server_info.capabilities.join(", "));
+    }
+    ResponseFrame::ErrorReadOnly => println!("[read only]"),
+    ResponseFrame::ErrorMalformed => println!("[malformed]"),
+    ResponseFrame::ErrorNotFound => println!("[not found]"),
+    ResponseFrame::ErrorTooLarge => println!("[too large]"),
+    ResponseFrame::ErrorInternal(message) => println!("[internal error] {}", message),
+    ResponseFrame::ErrorOverflow => println!("[overflow]"),
+    ResponseFrame::ErrorLagged => println!("[lagged, subscription ended]"),
+    ResponseFrame::Keys(keys, truncated) => {
+      for (i, key) in keys.into_iter().enumerate() {
+        println!("{}) {:?}", i + 1, String::from_utf8_lossy(&key));
+      }
+      if truncated {
+        println!("[stopped looking early, more keys may match]");
+      }
+    }
+  }
+}
+
+fn print_key_values(entries: Vec<(Vec<u8>, Vec<u8>)>) {
+  for (key, value) in entries {
+    println!(
+      "{:?} => {:?}",
+      String::from_utf8_lossy(&key),
+      String::from_utf8_lossy(&value)
+    );
+  }
+}
@@ -4,15 +4,25 @@ extern crate log;
 use serde::{Deserialize, Serialize};
 
 use bincode::{deserialize, serialize};
+use futures::stream::{self, Stream};
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::{self};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::spawn;
+use tokio::sync::{mpsc, oneshot};
 use traf_lib::{
-  frame_reader::{Frame, FramedTcpStream},
-  response_frame::ResponseFrame
+  frame_reader::FramedTcpStream,
+  request::{Opcode, Request},
 };
 
+pub use traf_lib::response_frame::{KeyStat, ResponseFrame, ServerInfo, PROTOCOL_VERSION};
+
 #[derive(Debug)]
 pub struct Get {
   bytes: Vec<u8>,
@@ -26,83 +36,1191 @@ impl Get {
   pub fn try_decode<'a, D: Deserialize<'a>>(&'a self) -> Option<D> {
     deserialize(&self.bytes[..]).ok()
   }
+
+  // The bytes as stored, eg. of values built with APPEND.
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+}
+
+const SCAN_PAGE_SIZE: usize = 100;
+
+// What a subscription streams.
+#[derive(Debug)]
+pub enum Change {
+  // A write of a matching key: its new value, None if the key was deleted (or renamed or moved
+  // away). Collections come as their elements length prefixed, see `traf_lib::length_prefixed`.
+  Key(Vec<u8>, Option<Get>),
+  // All keys of the namespace were removed (FLUSHNS or FLUSHALL).
+  Flush,
 }
 
 #[derive(Debug)]
 pub enum ClientError {
   IoError(io::Error),
   DataError,
+  // The server does not take the request where it was sent, eg. a nested MULTI or a SYNC sent to
+  // the writer.
   Failure,
+  VersionMismatch,
+  // The key holds a different type of value (eg. a list) than the operation works on.
+  WrongType,
+  // The server is over its memory limit and refuses writes that could grow it.
+  OutOfMemory,
+  // A set only if absent found the key already there.
+  KeyExists,
+  // A read at a point of the event log that no snapshot pins anymore.
+  VersionUnavailable,
+  // The server speaks another protocol version, None if it predates HELLO.
+  IncompatibleServer { protocol_version: Option<u64> },
+  // A write sent to a reader.
+  ReadOnly,
+  // The server could not decode the request.
+  Malformed,
+  // The key (or pinned snapshot) the operation works on does not exist.
+  NotFound,
+  // The operation would grow a value beyond what the server allows.
+  TooLarge,
+  // The server failed on its side, with what went wrong.
+  Internal(String),
+  // A counter update would go beyond the range of an i64.
+  Overflow,
+  // The subscriber fell behind and missed changes, its subscription is over.
+  Lagged,
 }
 
+// Clones share the connection - and with it the connection's state: the selected namespace, watched
+// keys and the pinned snapshot. Their requests are in flight at the same time, answers are routed
+// by request id.
+#[derive(Clone)]
 pub struct Client {
-  framed_stream: FramedTcpStream,
+  connection: Arc<Connection>,
+  server_info: ServerInfo,
+}
+
+// Where the background reader hands over the frames of a request.
+enum Route {
+  Response(oneshot::Sender<Vec<u8>>),
+  // The answer to SUBSCRIBE, then the pushed changes.
+  Subscription(mpsc::UnboundedSender<Vec<u8>>),
+}
+
+#[derive(Default)]
+struct Routes {
+  by_request_id: HashMap<u32, Route>,
+  // Set when the connection ended, no more frames are coming.
+  closed: bool,
+}
+
+struct Connection {
+  // Requests written together (eg. a transaction) hold the lock, so others cannot get in between.
+  writer: tokio::sync::Mutex<FramedTcpStream<OwnedWriteHalf>>,
+  next_request_id: AtomicU32,
+  routes: Arc<Mutex<Routes>>,
+}
+
+impl Connection {
+  fn new(framed_stream: FramedTcpStream) -> Self {
+    let (reader, writer) = framed_stream.into_split();
+    let routes = Arc::new(Mutex::new(Routes::default()));
+    spawn(route_frames(reader, routes.clone()));
+
+    Connection {
+      writer: tokio::sync::Mutex::new(writer),
+      // 0 is left for requests sent without a connection, eg. HELLO.
+      next_request_id: AtomicU32::new(1),
+      routes,
+    }
+  }
+
+  async fn write(
+    &self,
+    writer: &mut FramedTcpStream<OwnedWriteHalf>,
+    request: Request,
+    route: Route,
+  ) -> io::Result<()> {
+    let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+    {
+      let mut routes = self.routes.lock().unwrap();
+      if routes.closed {
+        return Err(connection_ended());
+      }
+      routes.by_request_id.insert(request_id, route);
+    }
+
+    let msg: Vec<u8> = request.into();
+    info!("{} bytes to send", msg.len());
+    writer.write_frame(request_id, msg).await
+  }
+
+  // Writes the requests in one go, their responses arrive on the returned channels.
+  async fn submit(&self, requests: Vec<Request>) -> io::Result<Vec<oneshot::Receiver<Vec<u8>>>> {
+    let mut writer = self.writer.lock().await;
+    let mut response_rxs = Vec::with_capacity(requests.len());
+    for request in requests {
+      let (response_tx, response_rx) = oneshot::channel();
+      self
+        .write(&mut writer, request, Route::Response(response_tx))
+        .await?;
+      response_rxs.push(response_rx);
+    }
+    Ok(response_rxs)
+  }
+}
+
+// Reads the frames of the connection and hands them over by request id, until it ends.
+async fn route_frames(mut reader: FramedTcpStream<OwnedReadHalf>, routes: Arc<Mutex<Routes>>) {
+  while let Some(frame) = reader.read_frame().await {
+    info!("{} bytes received", frame.bytes.len());
+
+    let mut routes = routes.lock().unwrap();
+    match routes.by_request_id.remove(&frame.request_id) {
+      // The request may have been given up on, nobody to tell then.
+      Some(Route::Response(response_tx)) => {
+        let _ = response_tx.send(frame.bytes);
+      }
+      // Kept as long as the subscriber listens.
+      Some(Route::Subscription(change_tx)) => {
+        if change_tx.send(frame.bytes).is_ok() {
+          routes
+            .by_request_id
+            .insert(frame.request_id, Route::Subscription(change_tx));
+        }
+      }
+      None => warn!("Frame for unknown request {}", frame.request_id),
+    }
+  }
+
+  // Dropping the routes lets everyone waiting know.
+  let mut routes = routes.lock().unwrap();
+  routes.closed = true;
+  routes.by_request_id.clear();
+}
+
+// Error responses become the matching error here, for all requests - they only handle their
+// answers.
+fn decode_response(bytes: Vec<u8>) -> Result<ResponseFrame, ClientError> {
+  match ResponseFrame::try_from(bytes) {
+    Ok(ResponseFrame::ErrorReadOnly) => Err(ClientError::ReadOnly),
+    Ok(ResponseFrame::ErrorMalformed) => Err(ClientError::Malformed),
+    Ok(ResponseFrame::ErrorNotFound) => Err(ClientError::NotFound),
+    Ok(ResponseFrame::ErrorTooLarge) => Err(ClientError::TooLarge),
+    Ok(ResponseFrame::ErrorInternal(message)) => Err(ClientError::Internal(message)),
+    Ok(ResponseFrame::ErrorWrongType) => Err(ClientError::WrongType),
+    Ok(ResponseFrame::ErrorOverflow) => Err(ClientError::Overflow),
+    Ok(ResponseFrame::ErrorLagged) => Err(ClientError::Lagged),
+    Ok(ResponseFrame::ErrorOutOfMemory) => Err(ClientError::OutOfMemory),
+    Ok(ResponseFrame::ErrorKeyExists) => Err(ClientError::KeyExists),
+    Ok(ResponseFrame::ErrorVersionUnavailable) => Err(ClientError::VersionUnavailable),
+    Ok(ResponseFrame::ErrorVersionMismatch) => Err(ClientError::VersionMismatch),
+    Ok(ResponseFrame::ErrorInvalidCommand) => Err(ClientError::Failure),
+    Ok(frame) => Ok(frame),
+    Err(_) => Err(ClientError::DataError),
+  }
+}
+
+fn length_of(frame: ResponseFrame) -> Result<u64, ClientError> {
+  match frame {
+    ResponseFrame::Value(bytes) => match bytes.try_into() {
+      Ok(length_bytes) => Ok(u64::from_le_bytes(length_bytes)),
+      Err(_) => Err(ClientError::DataError),
+    },
+    ResponseFrame::ValueMissing => Err(ClientError::NotFound),
+    _ => Err(ClientError::DataError),
+  }
+}
+
+async fn receive(response_rx: oneshot::Receiver<Vec<u8>>) -> io::Result<Vec<u8>> {
+  response_rx.await.map_err(|_| connection_ended())
+}
+
+fn connection_ended() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, "unexpected end")
 }
 
 impl Client {
-  pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+  // Connects and says HELLO. Fails with `ClientError::IncompatibleServer` if the server speaks
+  // another protocol version.
+  pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ClientError> {
+    let mut framed_stream = FramedTcpStream::new(
+      TcpStream::connect(addr)
+        .await
+        .map_err(ClientError::IoError)?,
+    );
+    framed_stream
+      .write_frame(
+        0,
+        Request::new(Opcode::Hello).number(PROTOCOL_VERSION).into(),
+      )
+      .await
+      .map_err(ClientError::IoError)?;
+    let frame = framed_stream
+      .read_frame()
+      .await
+      .ok_or(ClientError::DataError)?;
+
+    let server_info = match ResponseFrame::try_from(frame.bytes) {
+      Ok(ResponseFrame::Hello(server_info)) => server_info,
+      Ok(ResponseFrame::ErrorInvalidCommand) => {
+        return Err(ClientError::IncompatibleServer {
+          protocol_version: None,
+        })
+      }
+      _ => return Err(ClientError::DataError),
+    };
+    if server_info.protocol_version != PROTOCOL_VERSION {
+      return Err(ClientError::IncompatibleServer {
+        protocol_version: Some(server_info.protocol_version),
+      });
+    }
+
     Ok(Client {
-      framed_stream: FramedTcpStream::new(TcpStream::connect(addr).await?),
+      connection: Arc::new(Connection::new(framed_stream)),
+      server_info,
     })
   }
 
-  pub async fn set<S: Serialize>(&mut self, key: &str, val: S) -> Result<(), ClientError> {
-    let mut part_command: Vec<u8> = Vec::from(&b"SET "[..]);
-    part_command.append(&mut Vec::from(key));
-    part_command.push(b' ');
+  // What the server told about itself when connecting.
+  pub fn server_info(&self) -> &ServerInfo {
+    &self.server_info
+  }
 
-    let mut encoded = serialize(&val).unwrap();
-    part_command.append(&mut encoded);
+  pub async fn set<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    val: S,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Set)
+      .field(key)
+      .field(serialize(&val).unwrap());
 
     self
-      .send(part_command)
+      .send(request)
       .await
       .map_err(|e| ClientError::IoError(e))
-      .and_then(|bytes| ResponseFrame::try_from(bytes).map_err(|_| ClientError::DataError))
+      .and_then(decode_response)
       .and_then(|success| match success {
         ResponseFrame::Success => Ok(()),
-        _ => Err(ClientError::Failure),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Sets a value that the server removes after the given number of seconds.
+  pub async fn set_ex<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    val: S,
+    seconds: u64,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::SetEx)
+      .field(key)
+      .field(serialize(&val).unwrap())
+      .number(seconds);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|success| match success {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
       })
   }
 
-  pub async fn get(&mut self, key: &str) -> Result<Get, ClientError> {
-    let mut part_command: Vec<u8> = Vec::from(&b"GET "[..]);
-    part_command.append(&mut Vec::from(key));
+  pub async fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Get, ClientError> {
+    let request = Request::new(Opcode::Get).field(key);
 
     self
-      .send(part_command)
+      .send(request)
       .await
       .map_err(|e| ClientError::IoError(e))
-      .and_then(|bytes| ResponseFrame::try_from(bytes).map_err(|_| ClientError::DataError))
+      .and_then(decode_response)
       .and_then(|frame| match frame {
-        ResponseFrame::ValueMissing => Err(ClientError::Failure),
+        ResponseFrame::ValueMissing => Err(ClientError::NotFound),
         ResponseFrame::Value(v) => Ok(Get::new(v)),
+        ResponseFrame::VersionedValue(_, v) => Ok(Get::new(v)),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Get the value together with its version, to be used with `compare_and_set`.
+  pub async fn get_versioned(&mut self, key: impl AsRef<[u8]>) -> Result<(u64, Get), ClientError> {
+    let request = Request::new(Opcode::Get).field(key);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::ValueMissing => Err(ClientError::NotFound),
+        ResponseFrame::VersionedValue(version, v) => Ok((version, Get::new(v))),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Get the value as of a snapshot's position (see `snapshot`), from any connection.
+  pub async fn get_at(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    position: u64,
+  ) -> Result<(u64, Get), ClientError> {
+    let request = Request::new(Opcode::Get).field(key).number(position);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::ValueMissing => Err(ClientError::NotFound),
+        ResponseFrame::VersionedValue(version, v) => Ok((version, Get::new(v))),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Pins a consistent read view: following GETs and MGETs of this client read the storage as it
+  // is now, while writes go on. Returns the position of the view, for `get_at`.
+  pub async fn snapshot(&mut self) -> Result<u64, ClientError> {
+    self
+      .send(Request::new(Opcode::Snapshot))
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Value(bytes) => match bytes.try_into() {
+          Ok(position_bytes) => Ok(u64::from_be_bytes(position_bytes)),
+          Err(_) => Err(ClientError::DataError),
+        },
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Goes back to reading the latest state. Fails if there was no snapshot.
+  pub async fn release_snapshot(&mut self) -> Result<(), ClientError> {
+    self
+      .send(Request::new(Opcode::SnapshotRelease))
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
         _ => Err(ClientError::DataError),
       })
   }
 
-  pub async fn delete(&mut self, key: &str) -> Result<(), ClientError> {
-    let mut part_command: Vec<u8> = Vec::from(&b"DELETE "[..]);
-    part_command.append(&mut Vec::from(key));
+  // Past writes of the key from the writer's event log, newest first: the event number and the
+  // value written (None if deleted or flushed). Collections come as their elements length prefixed
+  // (hashes as field, value pairs), see `traf_lib::length_prefixed`. At most `limit` events if
+  // given.
+  pub async fn history(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    limit: Option<usize>,
+  ) -> Result<Vec<(u64, Option<Get>)>, ClientError> {
+    let mut request = Request::new(Opcode::History).field(key);
+    if let Some(limit) = limit {
+      request = request.number(limit as u64);
+    }
 
     self
-      .send(part_command)
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::History(events) => Ok(
+          events
+            .into_iter()
+            .map(|(number, value)| (number, value.map(Get::new)))
+            .collect(),
+        ),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Sets the value only if the key is still at `expected_version`. Fails with
+  // `ClientError::VersionMismatch` if it was changed in the meantime.
+  pub async fn compare_and_set<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    expected_version: u64,
+    val: S,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Cas)
+      .field(key)
+      .number(expected_version)
+      .field(serialize(&val).unwrap());
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Sets the value only if the key is missing. Fails with `ClientError::KeyExists` otherwise.
+  pub async fn set_if_absent<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    val: S,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::SetNx)
+      .field(key)
+      .field(serialize(&val).unwrap());
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Sets the value only if the key is present. Fails with `ClientError::NotFound` if it's missing.
+  pub async fn set_if_present<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    val: S,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::SetXx)
+      .field(key)
+      .field(serialize(&val).unwrap());
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Sets the value and returns the one it replaced, None if the key was missing.
+  pub async fn get_set<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    val: S,
+  ) -> Result<Option<Get>, ClientError> {
+    let request = Request::new(Opcode::GetSet)
+      .field(key)
+      .field(serialize(&val).unwrap());
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(None),
+        ResponseFrame::Value(v) => Ok(Some(Get::new(v))),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  pub async fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Delete).field(key);
+
+    self
+      .send(request)
       .await
       .map_err(|e| ClientError::IoError(e))
-      .and_then(|bytes| ResponseFrame::try_from(bytes).map_err(|_| ClientError::DataError))
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Moves the value (with its expiry) to `dst`, replacing any value there. Fails with
+  // `ClientError::NotFound` if `src` is missing.
+  pub async fn rename(
+    &mut self,
+    src: impl AsRef<[u8]>,
+    dst: impl AsRef<[u8]>,
+  ) -> Result<(), ClientError> {
+    self.send_rename(src, dst, false).await
+  }
+
+  // Like `rename`, but fails with `ClientError::KeyExists` if `dst` is present.
+  pub async fn rename_if_absent(
+    &mut self,
+    src: impl AsRef<[u8]>,
+    dst: impl AsRef<[u8]>,
+  ) -> Result<(), ClientError> {
+    self.send_rename(src, dst, true).await
+  }
+
+  async fn send_rename(
+    &mut self,
+    src: impl AsRef<[u8]>,
+    dst: impl AsRef<[u8]>,
+    nx: bool,
+  ) -> Result<(), ClientError> {
+    let opcode = if nx { Opcode::RenameNx } else { Opcode::Rename };
+    let request = Request::new(opcode).field(src).field(dst);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Copies the value (with its expiry) to `dst`. Fails with `ClientError::KeyExists` if `dst` is
+  // present and with `ClientError::NotFound` if `src` is missing.
+  pub async fn copy(
+    &mut self,
+    src: impl AsRef<[u8]>,
+    dst: impl AsRef<[u8]>,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Copy).field(src).field(dst);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Moves the key (with its expiry) from the current namespace to another one. Fails with
+  // `ClientError::KeyExists` if the key is present there and with `ClientError::NotFound` if it's
+  // missing.
+  pub async fn move_key(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    namespace: &str,
+  ) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Move).field(key).field(namespace);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Fetches many keys in one round trip. Missing keys are None, in the order of `keys`.
+  pub async fn mget(&mut self, keys: &[impl AsRef<[u8]>]) -> Result<Vec<Option<Get>>, ClientError> {
+    let request = Request::new(Opcode::MGet).fields(keys);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Values(values) => Ok(values.into_iter().map(|v| v.map(Get::new)).collect()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Sets all pairs atomically - readers never observe only a part of them.
+  pub async fn mset<K: AsRef<[u8]>, S: Serialize>(
+    &mut self,
+    pairs: &[(K, S)],
+  ) -> Result<(), ClientError> {
+    let mut fields: Vec<Vec<u8>> = vec![];
+    for (key, val) in pairs {
+      fields.push(key.as_ref().to_vec());
+      fields.push(serialize(val).unwrap());
+    }
+
+    let request = Request::new(Opcode::MSet).fields(&fields);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Deletes all given keys. Fails only if none of them existed.
+  pub async fn mdel(&mut self, keys: &[impl AsRef<[u8]>]) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::MDel).fields(keys);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Streams all key/value pairs with the given prefix in key order, fetching them page by page.
+  pub fn scan<'a>(
+    &'a mut self,
+    prefix: &'a [u8],
+  ) -> impl Stream<Item = Result<(Vec<u8>, Get), ClientError>> + 'a {
+    let buffer: VecDeque<(Vec<u8>, Get)> = VecDeque::new();
+    let cursor: Option<String> = Some("0".into());
+
+    stream::unfold(
+      (self, buffer, cursor),
+      move |(client, mut buffer, mut cursor)| async move {
+        loop {
+          if let Some(entry) = buffer.pop_front() {
+            return Some((Ok(entry), (client, buffer, cursor)));
+          }
+
+          // No cursor left means the last page was already fetched.
+          let page_cursor = cursor.take()?;
+          match client.scan_page(&page_cursor, prefix, SCAN_PAGE_SIZE).await {
+            Ok((next_cursor, entries)) => {
+              buffer.extend(entries);
+              cursor = next_cursor;
+            }
+            Err(err) => return Some((Err(err), (client, buffer, cursor))),
+          }
+        }
+      },
+    )
+  }
+
+  // Fetches a single page of a scan. Start with cursor "0" and continue with the returned cursor
+  // until it is None. The server lowers `count` to at most 1000.
+  pub async fn scan_page(
+    &mut self,
+    cursor: &str,
+    prefix: &[u8],
+    count: usize,
+  ) -> Result<(Option<String>, Vec<(Vec<u8>, Get)>), ClientError> {
+    let request = Request::new(Opcode::Scan)
+      .field(cursor)
+      .field(prefix)
+      .number(count as u64);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::ScanPage(cursor, entries) => {
+          let cursor = String::from_utf8(cursor).map_err(|_| ClientError::DataError)?;
+          let cursor = if cursor == "0" { None } else { Some(cursor) };
+          Ok((cursor, decode_entries(entries)))
+        }
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // All key/value pairs from `start` (inclusive) until `end` (exclusive), in key order.
+  pub async fn range(
+    &mut self,
+    start: impl AsRef<[u8]>,
+    end: impl AsRef<[u8]>,
+  ) -> Result<Vec<(Vec<u8>, Get)>, ClientError> {
+    let request = Request::new(Opcode::Range).field(start).field(end);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::KeyValues(entries) => Ok(decode_entries(entries)),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Atomically increments a counter by one and returns the new value. Missing keys start from 0.
+  pub async fn incr(&mut self, key: impl AsRef<[u8]>) -> Result<i64, ClientError> {
+    let request = Request::new(Opcode::Incr).field(key);
+
+    self.send_counter_command(request).await
+  }
+
+  // Atomically decrements a counter by one and returns the new value. Missing keys start from 0.
+  pub async fn decr(&mut self, key: impl AsRef<[u8]>) -> Result<i64, ClientError> {
+    let request = Request::new(Opcode::Decr).field(key);
+
+    self.send_counter_command(request).await
+  }
+
+  pub async fn incr_by(&mut self, key: impl AsRef<[u8]>, delta: i64) -> Result<i64, ClientError> {
+    let request = Request::new(Opcode::IncrBy).field(key).signed_number(delta);
+
+    self.send_counter_command(request).await
+  }
+
+  pub async fn expire(&mut self, key: impl AsRef<[u8]>, seconds: u64) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Expire).field(key).number(seconds);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Time left until the key expires. None if the key has no expiry.
+  pub async fn ttl(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Duration>, ClientError> {
+    let request = Request::new(Opcode::Ttl).field(key);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Value(bytes) => match bytes.try_into() {
+          Ok(ttl_bytes) => match i64::from_le_bytes(ttl_bytes) {
+            ttl if ttl < 0 => Ok(None),
+            ttl => Ok(Some(Duration::from_millis(ttl as u64))),
+          },
+          Err(_) => Err(ClientError::DataError),
+        },
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Metadata of a key: when it was created, modified and accessed, the replication event that last
+  // changed it and its size. Does not count as an access. Fails if the key does not exist.
+  pub async fn stat(&mut self, key: impl AsRef<[u8]>) -> Result<KeyStat, ClientError> {
+    let request = Request::new(Opcode::Stat).field(key);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Stat(stat) => Ok(stat),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Removes the expiry of a key. Fails if the key does not exist or has no expiry.
+  pub async fn persist(&mut self, key: impl AsRef<[u8]>) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Persist).field(key);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Byte-range operations work on the raw bytes of a value - unlike `set`, they do not serialize.
+
+  // Appends raw bytes to the value (creating it if missing) and returns the new length. Fails with
+  // `ClientError::TooLarge` if the value would grow beyond 512 MiB.
+  pub async fn append(&mut self, key: impl AsRef<[u8]>, bytes: &[u8]) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::Append).field(key).field(bytes);
+
+    self.send_length_command(request).await
+  }
+
+  // Raw bytes between the inclusive positions. Negative positions count from the end, so
+  // `get_range(key, 0, -1)` is the whole value.
+  pub async fn get_range(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    start: i64,
+    end: i64,
+  ) -> Result<Vec<u8>, ClientError> {
+    let request = Request::new(Opcode::GetRange)
+      .field(key)
+      .signed_number(start)
+      .signed_number(end);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Value(bytes) => Ok(bytes),
+        ResponseFrame::ValueMissing => Err(ClientError::NotFound),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Overwrites the value from `offset` with raw bytes and returns the new length. Fails with
+  // `ClientError::TooLarge` if the value would grow beyond 512 MiB.
+  pub async fn set_range(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    offset: u64,
+    bytes: &[u8],
+  ) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::SetRange)
+      .field(key)
+      .number(offset)
+      .field(bytes);
+
+    self.send_length_command(request).await
+  }
+
+  pub async fn strlen(&mut self, key: impl AsRef<[u8]>) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::StrLen).field(key);
+
+    self.send_length_command(request).await
+  }
+
+  // Pushes elements to the front of the list, so they end up in reverse order. Returns the new
+  // length of the list.
+  pub async fn lpush<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    elements: &[S],
+  ) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::LPush)
+      .field(key)
+      .fields(&serialize_all(elements));
+
+    self.send_length_command(request).await
+  }
+
+  // Pushes elements to the back of the list. Returns the new length of the list.
+  pub async fn rpush<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    elements: &[S],
+  ) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::RPush)
+      .field(key)
+      .fields(&serialize_all(elements));
+
+    self.send_length_command(request).await
+  }
+
+  // Removes and returns the first element of the list, None if it's empty.
+  pub async fn lpop(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Get>, ClientError> {
+    let request = Request::new(Opcode::LPop).field(key);
+
+    self.send_optional_value_command(request).await
+  }
+
+  // Removes and returns the last element of the list, None if it's empty.
+  pub async fn rpop(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Get>, ClientError> {
+    let request = Request::new(Opcode::RPop).field(key);
+
+    self.send_optional_value_command(request).await
+  }
+
+  // Elements between the inclusive positions, negative ones counting from the end of the list.
+  pub async fn lrange(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    start: i64,
+    stop: i64,
+  ) -> Result<Vec<Get>, ClientError> {
+    let request = Request::new(Opcode::LRange)
+      .field(key)
+      .signed_number(start)
+      .signed_number(stop);
+
+    self.send_elements_command(request).await
+  }
+
+  // Sets fields of the hash. Returns the number of fields that were not set before.
+  pub async fn hset<F: AsRef<[u8]>, S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    pairs: &[(F, S)],
+  ) -> Result<u64, ClientError> {
+    let mut fields: Vec<Vec<u8>> = vec![];
+    for (field, val) in pairs {
+      fields.push(field.as_ref().to_vec());
+      fields.push(serialize(val).unwrap());
+    }
+
+    let request = Request::new(Opcode::HSet).field(key).fields(&fields);
+
+    self.send_length_command(request).await
+  }
+
+  pub async fn hget(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    field: impl AsRef<[u8]>,
+  ) -> Result<Option<Get>, ClientError> {
+    let request = Request::new(Opcode::HGet).field(key).field(field);
+
+    self.send_optional_value_command(request).await
+  }
+
+  // Removes fields of the hash. Returns the number of fields removed.
+  pub async fn hdel(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    fields: &[impl AsRef<[u8]>],
+  ) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::HDel).field(key).fields(fields);
+
+    self.send_removal_command(request).await
+  }
+
+  // All field/value pairs of the hash, in field order.
+  pub async fn hgetall(
+    &mut self,
+    key: impl AsRef<[u8]>,
+  ) -> Result<Vec<(Vec<u8>, Get)>, ClientError> {
+    let request = Request::new(Opcode::HGetAll).field(key);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::KeyValues(entries) => Ok(decode_entries(entries)),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Adds members to the set. Returns the number of members that were not in the set before.
+  pub async fn sadd<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    members: &[S],
+  ) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::SAdd)
+      .field(key)
+      .fields(&serialize_all(members));
+
+    self.send_length_command(request).await
+  }
+
+  // Removes members from the set. Returns the number of members removed.
+  pub async fn srem<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    members: &[S],
+  ) -> Result<u64, ClientError> {
+    let request = Request::new(Opcode::SRem)
+      .field(key)
+      .fields(&serialize_all(members));
+
+    self.send_removal_command(request).await
+  }
+
+  // Members of the set, ordered by their serialized bytes.
+  pub async fn smembers(&mut self, key: impl AsRef<[u8]>) -> Result<Vec<Get>, ClientError> {
+    let request = Request::new(Opcode::SMembers).field(key);
+
+    self.send_elements_command(request).await
+  }
+
+  pub async fn sismember<S: Serialize>(
+    &mut self,
+    key: impl AsRef<[u8]>,
+    member: S,
+  ) -> Result<bool, ClientError> {
+    let request = Request::new(Opcode::SIsMember)
+      .field(key)
+      .field(serialize(&member).unwrap());
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Value(flag) => Ok(flag == [1]),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Switches this connection to the given namespace - a separate keyspace. Returns the client so
+  // calls can be chained, eg `client.namespace("team").await?.get("key")`.
+  // The namespace belongs to the connection, so it switches for all clones too. Use a separate
+  // `Client::connect` for each namespace used at the same time.
+  pub async fn namespace(&mut self, namespace: &str) -> Result<&mut Self, ClientError> {
+    let request = Request::new(Opcode::Select).field(namespace);
+
+    let frame = self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)?;
+
+    match frame {
+      ResponseFrame::Success => Ok(self),
+      _ => Err(ClientError::DataError),
+    }
+  }
+
+  // Removes all keys of the current namespace.
+  pub async fn flush_namespace(&mut self) -> Result<(), ClientError> {
+    self
+      .send(Request::new(Opcode::FlushNs))
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Removes all keys of all namespaces.
+  pub async fn flush_all(&mut self) -> Result<(), ClientError> {
+    self
+      .send(Request::new(Opcode::FlushAll))
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
       .and_then(|frame| match frame {
-        ResponseFrame::ValueMissing => Err(ClientError::Failure),
         ResponseFrame::Success => Ok(()),
         _ => Err(ClientError::DataError),
       })
   }
 
+  // The number of keys in the current namespace.
+  pub async fn dbsize(&mut self) -> Result<u64, ClientError> {
+    self.send_length_command(Request::new(Opcode::DbSize)).await
+  }
+
+  // Keys of the current namespace matching the glob `pattern`, in key order, and whether the server
+  // stopped looking early - more keys may match then. It stops at `limit` matches (the server's
+  // default if None, and never more than 10000) or after looking at 100000 keys, use `scan` to go
+  // through all keys.
+  pub async fn keys(
+    &mut self,
+    pattern: impl AsRef<[u8]>,
+    limit: Option<usize>,
+  ) -> Result<(Vec<Vec<u8>>, bool), ClientError> {
+    let mut request = Request::new(Opcode::Keys).field(pattern);
+    if let Some(limit) = limit {
+      request = request.number(limit as u64);
+    }
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Keys(keys, truncated) => Ok((keys, truncated)),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Streams the writes of keys matching the glob `pattern` in the current namespace, and flushes of
+  // it. A subscriber falling too far behind gets `ClientError::Lagged` and the stream ends. Clones
+  // can go on using the connection meanwhile.
+  pub async fn subscribe(
+    self,
+    pattern: impl AsRef<[u8]>,
+  ) -> Result<impl Stream<Item = Result<Change, ClientError>>, ClientError> {
+    let request = Request::new(Opcode::Subscribe).field(pattern);
+    let (change_tx, mut change_rx) = mpsc::unbounded_channel();
+
+    {
+      let mut writer = self.connection.writer.lock().await;
+      self
+        .connection
+        .write(&mut writer, request, Route::Subscription(change_tx))
+        .await
+        .map_err(ClientError::IoError)?;
+    }
+
+    let frame = change_rx
+      .recv()
+      .await
+      .ok_or_else(connection_ended)
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)?;
+    if frame != ResponseFrame::Success {
+      return Err(ClientError::DataError);
+    }
+
+    // The stream holds on to the client, keeping the connection open.
+    Ok(stream::unfold(
+      Some((self, change_rx)),
+      |state| async move {
+        let (client, mut change_rx) = state?;
+        let bytes = change_rx.recv().await?;
+        let change = match ResponseFrame::try_from(bytes) {
+          Ok(ResponseFrame::Change(key, value)) => Ok(Change::Key(key, value.map(Get::new))),
+          Ok(ResponseFrame::Flushed) => Ok(Change::Flush),
+          // The server ended the subscription.
+          Ok(ResponseFrame::ErrorLagged) => return Some((Err(ClientError::Lagged), None)),
+          _ => Err(ClientError::DataError),
+        };
+        Some((change, Some((client, change_rx))))
+      },
+    ))
+  }
+
+  // Watched keys make the next transaction of this connection abort if any of them gets changed
+  // before EXEC.
+  pub async fn watch(&mut self, keys: &[impl AsRef<[u8]>]) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Watch).fields(keys);
+
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Success => Ok(()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  // Collects commands to be executed atomically with `Transaction::exec`.
+  pub fn transaction(&mut self) -> Transaction<'_> {
+    Transaction {
+      client: self,
+      commands: vec![],
+    }
+  }
+
+  // Collects commands sent with `Pipeline::exec` without waiting for each response in between.
+  pub fn pipeline(&mut self) -> Pipeline<'_> {
+    Pipeline {
+      client: self,
+      commands: vec![],
+    }
+  }
+
   pub async fn last_replication_id(&mut self) -> Result<Option<u64>, ClientError> {
     self
-      .send(Vec::from(&b"LAST_REPLICATION_ID"[..]))
+      .send(Request::new(Opcode::LastReplicationId))
       .await
       .map_err(|e| ClientError::IoError(e))
-      .and_then(|bytes| ResponseFrame::try_from(bytes).map_err(|_| ClientError::DataError))
+      .and_then(decode_response)
       .and_then(|frame| match frame {
         ResponseFrame::ValueMissing => Ok(None),
         ResponseFrame::Value(bytes) => match bytes.try_into() {
@@ -120,36 +1238,229 @@ impl Client {
   // Request a batch sync from the server.
   //
   // param blob Byte sequence of list of commands: ([8 bytes: u64 size of command][bytes: command])*
-  pub async fn batch_sync(&mut self, mut blob: Vec<u8>) -> Result<(), ClientError> {
-    let mut part_command: Vec<u8> = Vec::from(&b"SYNC "[..]);
-    part_command.append(&mut blob);
+  pub async fn batch_sync(&mut self, blob: Vec<u8>) -> Result<(), ClientError> {
+    let request = Request::new(Opcode::Sync).field(blob);
 
     self
-      .send(part_command)
+      .send(request)
       .await
       .map_err(|e| ClientError::IoError(e))
-      .and_then(|bytes| ResponseFrame::try_from(bytes).map_err(|_| ClientError::DataError))
+      .and_then(decode_response)
       .and_then(|frame| match frame {
         ResponseFrame::Success => Ok(()),
         _ => Err(ClientError::DataError),
       })
   }
 
-  async fn send(&mut self, msg: Vec<u8>) -> io::Result<Vec<u8>> {
-    info!("{} bytes to send", msg.len());
-    self.framed_stream.write_frame(msg).await?;
+  async fn send_counter_command(&mut self, request: Request) -> Result<i64, ClientError> {
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Value(bytes) => match bytes.try_into() {
+          Ok(counter_bytes) => Ok(i64::from_le_bytes(counter_bytes)),
+          Err(_) => Err(ClientError::DataError),
+        },
+        _ => Err(ClientError::DataError),
+      })
+  }
 
-    let msg_in: Frame = self
-      .framed_stream
-      .read_frame()
+  async fn send_length_command(&mut self, request: Request) -> Result<u64, ClientError> {
+    self
+      .send(request)
       .await
-      .ok_or(io::Error::new(io::ErrorKind::InvalidData, "unexpected end"))?;
-    info!("{} bytes received", msg_in.bytes.len());
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(length_of)
+  }
 
-    Ok(msg_in.bytes)
+  // Removals that found nothing to remove answer with ValueMissing, they removed 0.
+  async fn send_removal_command(&mut self, request: Request) -> Result<u64, ClientError> {
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::ValueMissing => Ok(0),
+        frame => length_of(frame),
+      })
+  }
+
+  async fn send_optional_value_command(
+    &mut self,
+    request: Request,
+  ) -> Result<Option<Get>, ClientError> {
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Value(v) => Ok(Some(Get::new(v))),
+        ResponseFrame::ValueMissing => Ok(None),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  async fn send_elements_command(&mut self, request: Request) -> Result<Vec<Get>, ClientError> {
+    self
+      .send(request)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Elements(elements) => Ok(elements.into_iter().map(Get::new).collect()),
+        _ => Err(ClientError::DataError),
+      })
+  }
+
+  async fn send(&mut self, request: Request) -> io::Result<Vec<u8>> {
+    let mut response_rxs = self.connection.submit(vec![request]).await?;
+    receive(response_rxs.pop().unwrap()).await
   }
 }
 
+pub struct Transaction<'a> {
+  client: &'a mut Client,
+  commands: Vec<Request>,
+}
+
+impl<'a> Transaction<'a> {
+  pub fn set<S: Serialize>(mut self, key: impl AsRef<[u8]>, val: S) -> Self {
+    let request = Request::new(Opcode::Set)
+      .field(key)
+      .field(serialize(&val).unwrap());
+
+    self.commands.push(request);
+    self
+  }
+
+  pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+    let request = Request::new(Opcode::Delete).field(key);
+
+    self.commands.push(request);
+    self
+  }
+
+  pub fn incr_by(mut self, key: impl AsRef<[u8]>, delta: i64) -> Self {
+    let request = Request::new(Opcode::IncrBy).field(key).signed_number(delta);
+
+    self.commands.push(request);
+    self
+  }
+
+  pub fn flush_namespace(mut self) -> Self {
+    self.commands.push(Request::new(Opcode::FlushNs));
+    self
+  }
+
+  // Runs all collected commands as one unit and returns their responses in order. Fails with
+  // `ClientError::VersionMismatch` (and runs nothing) if a watched key was changed.
+  pub async fn exec(self) -> Result<Vec<ResponseFrame>, ClientError> {
+    let mut requests = vec![Request::new(Opcode::Multi)];
+    requests.extend(self.commands);
+    requests.push(Request::new(Opcode::Exec));
+
+    // Written in one go, so requests of clones cannot end up in the transaction.
+    let mut response_rxs = self
+      .client
+      .connection
+      .submit(requests)
+      .await
+      .map_err(ClientError::IoError)?;
+    let exec_rx = response_rxs.pop().unwrap();
+
+    let mut expected_frame = ResponseFrame::Success;
+    let mut all_queued = true;
+    for response_rx in response_rxs {
+      let bytes = receive(response_rx).await.map_err(ClientError::IoError)?;
+      all_queued &= ResponseFrame::try_from(bytes) == Ok(expected_frame);
+      expected_frame = ResponseFrame::Queued;
+    }
+
+    receive(exec_rx)
+      .await
+      .map_err(ClientError::IoError)
+      .and_then(decode_response)
+      .and_then(|frame| match frame {
+        ResponseFrame::Results(results) if all_queued => Ok(results),
+        _ => Err(ClientError::DataError),
+      })
+  }
+}
+
+pub struct Pipeline<'a> {
+  client: &'a mut Client,
+  commands: Vec<Request>,
+}
+
+impl<'a> Pipeline<'a> {
+  pub fn set<S: Serialize>(mut self, key: impl AsRef<[u8]>, val: S) -> Self {
+    let request = Request::new(Opcode::Set)
+      .field(key)
+      .field(serialize(&val).unwrap());
+
+    self.commands.push(request);
+    self
+  }
+
+  pub fn get(mut self, key: impl AsRef<[u8]>) -> Self {
+    let request = Request::new(Opcode::Get).field(key);
+
+    self.commands.push(request);
+    self
+  }
+
+  pub fn delete(mut self, key: impl AsRef<[u8]>) -> Self {
+    let request = Request::new(Opcode::Delete).field(key);
+
+    self.commands.push(request);
+    self
+  }
+
+  pub fn incr_by(mut self, key: impl AsRef<[u8]>, delta: i64) -> Self {
+    let request = Request::new(Opcode::IncrBy).field(key).signed_number(delta);
+
+    self.commands.push(request);
+    self
+  }
+
+  // Sends all collected commands and returns their responses in order. Unlike a transaction the
+  // commands run one by one, a failing command does not stop the ones after it.
+  pub async fn exec(self) -> Result<Vec<ResponseFrame>, ClientError> {
+    let response_rxs = self
+      .client
+      .connection
+      .submit(self.commands)
+      .await
+      .map_err(ClientError::IoError)?;
+
+    let mut responses = Vec::with_capacity(response_rxs.len());
+    for response_rx in response_rxs {
+      let bytes = receive(response_rx).await.map_err(ClientError::IoError)?;
+      responses.push(ResponseFrame::try_from(bytes).map_err(|_| ClientError::DataError)?);
+    }
+    Ok(responses)
+  }
+}
+
+fn serialize_all<S: Serialize>(values: &[S]) -> Vec<Vec<u8>> {
+  values
+    .iter()
+    .map(|value| serialize(value).unwrap())
+    .collect()
+}
+
+fn decode_entries(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(Vec<u8>, Get)> {
+  entries
+    .into_iter()
+    .map(|(key, value)| (key, Get::new(value)))
+    .collect()
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -163,4 +1474,29 @@ mod tests {
     let decoded: i32 = get.try_decode().unwrap();
     assert_eq!(123i32, decoded);
   }
+
+  #[test]
+  fn error_frames_become_errors() {
+    let errors = vec![
+      ResponseFrame::ErrorInvalidCommand,
+      ResponseFrame::ErrorVersionMismatch,
+      ResponseFrame::ErrorWrongType,
+      ResponseFrame::ErrorOutOfMemory,
+      ResponseFrame::ErrorKeyExists,
+      ResponseFrame::ErrorVersionUnavailable,
+      ResponseFrame::ErrorReadOnly,
+      ResponseFrame::ErrorMalformed,
+      ResponseFrame::ErrorNotFound,
+      ResponseFrame::ErrorTooLarge,
+      ResponseFrame::ErrorInternal("disk full".into()),
+      ResponseFrame::ErrorOverflow,
+      ResponseFrame::ErrorLagged,
+    ];
+    for error in errors {
+      assert!(!matches!(
+        decode_response(error.into()),
+        Ok(_) | Err(ClientError::DataError)
+      ));
+    }
+  }
 }
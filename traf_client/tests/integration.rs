@@ -1,4 +1,7 @@
+use futures::StreamExt;
 use std::matches;
+use std::time::Duration;
+use tokio::time::sleep;
 use traf_client::*;
 
 // Requires TRAF-CORE running on 0.0.0.0:4567
@@ -27,7 +30,7 @@ async fn test_basic_set_get_delete_flow() {
   assert!(get_again_result.is_err());
   assert!(matches!(
     get_again_result.err().unwrap(),
-    ClientError::Failure
+    ClientError::NotFound
   ));
 }
 
@@ -37,5 +40,981 @@ async fn test_missing_get_flow() {
   let get_result = client.get("nofoo").await;
 
   assert!(get_result.is_err());
-  assert!(matches!(get_result.err().unwrap(), ClientError::Failure));
+  assert!(matches!(get_result.err().unwrap(), ClientError::NotFound));
+}
+
+#[tokio::test]
+async fn test_counter_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("counter").await;
+
+  assert_eq!(1, client.incr("counter").await.unwrap());
+  assert_eq!(11, client.incr_by("counter", 10).await.unwrap());
+  assert_eq!(10, client.decr("counter").await.unwrap());
+
+  let value: Option<i64> = client.get("counter").await.unwrap().try_decode();
+  assert_eq!(Some(10i64), value);
+
+  client.set("not_counter", "abc").await.unwrap();
+  assert!(matches!(
+    client.incr("not_counter").await.err().unwrap(),
+    ClientError::WrongType
+  ));
+
+  client.set("counter", i64::MAX).await.unwrap();
+  assert!(matches!(
+    client.incr("counter").await,
+    Err(ClientError::Overflow)
+  ));
+  assert_eq!(
+    Some(i64::MAX),
+    client.get("counter").await.unwrap().try_decode()
+  );
+}
+
+#[tokio::test]
+async fn test_expiry_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+
+  client.set_ex("expiring", 1u8, 1).await.unwrap();
+  let ttl = client.ttl("expiring").await.unwrap();
+  assert!(ttl.is_some());
+  assert!(ttl.unwrap() <= Duration::from_secs(1));
+
+  client.set("persisted", 1u8).await.unwrap();
+  assert_eq!(None, client.ttl("persisted").await.unwrap());
+  client.expire("persisted", 1).await.unwrap();
+  client.persist("persisted").await.unwrap();
+  assert_eq!(None, client.ttl("persisted").await.unwrap());
+  assert!(matches!(
+    client.expire("persisted", u64::MAX).await,
+    Err(ClientError::Malformed)
+  ));
+  assert!(matches!(
+    client.set_ex("expiring:never", 1u8, u64::MAX).await,
+    Err(ClientError::Malformed)
+  ));
+
+  sleep(Duration::from_millis(1200)).await;
+
+  assert!(matches!(
+    client.get("expiring").await.err().unwrap(),
+    ClientError::NotFound
+  ));
+  assert!(client.get("persisted").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_compare_and_set_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.set("cas", 1u8).await.unwrap();
+
+  let (version, get) = client.get_versioned("cas").await.unwrap();
+  assert_eq!(Some(1u8), get.try_decode());
+
+  client.compare_and_set("cas", version, 2u8).await.unwrap();
+
+  let (new_version, get) = client.get_versioned("cas").await.unwrap();
+  assert!(new_version > version);
+  assert_eq!(Some(2u8), get.try_decode());
+
+  assert!(matches!(
+    client
+      .compare_and_set("cas", version, 3u8)
+      .await
+      .err()
+      .unwrap(),
+    ClientError::VersionMismatch
+  ));
+}
+
+#[tokio::test]
+async fn test_conditional_set_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("conditional").await;
+
+  assert!(matches!(
+    client
+      .set_if_present("conditional", 1u8)
+      .await
+      .err()
+      .unwrap(),
+    ClientError::NotFound
+  ));
+
+  client.set_if_absent("conditional", 1u8).await.unwrap();
+  assert!(matches!(
+    client
+      .set_if_absent("conditional", 2u8)
+      .await
+      .err()
+      .unwrap(),
+    ClientError::KeyExists
+  ));
+  assert_eq!(
+    Some(1u8),
+    client.get("conditional").await.unwrap().try_decode()
+  );
+
+  client.set_if_present("conditional", 3u8).await.unwrap();
+  let previous = client.get_set("conditional", 4u8).await.unwrap();
+  assert_eq!(Some(3u8), previous.unwrap().try_decode());
+  assert_eq!(
+    Some(4u8),
+    client.get("conditional").await.unwrap().try_decode()
+  );
+
+  client.delete("conditional").await.unwrap();
+  assert!(client.get_set("conditional", 5u8).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_snapshot() {
+  let mut analytics = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.set("snapshot:kept", 1u8).await.unwrap();
+  client.set("snapshot:deleted", 1u8).await.unwrap();
+  let _ = client.delete("snapshot:created").await;
+
+  let position = analytics.snapshot().await.unwrap();
+  client.set("snapshot:kept", 2u8).await.unwrap();
+  client.delete("snapshot:deleted").await.unwrap();
+  client.set("snapshot:created", 1u8).await.unwrap();
+
+  assert_eq!(
+    Some(1u8),
+    analytics.get("snapshot:kept").await.unwrap().try_decode()
+  );
+  assert!(analytics.get("snapshot:deleted").await.is_ok());
+  assert!(analytics.get("snapshot:created").await.is_err());
+  assert_eq!(
+    Some(2u8),
+    client.get("snapshot:kept").await.unwrap().try_decode()
+  );
+
+  // Other connections can read the pinned view too.
+  let (_, get) = client.get_at("snapshot:kept", position).await.unwrap();
+  assert_eq!(Some(1u8), get.try_decode());
+
+  analytics.release_snapshot().await.unwrap();
+  assert_eq!(
+    Some(2u8),
+    analytics.get("snapshot:kept").await.unwrap().try_decode()
+  );
+  assert!(matches!(
+    client
+      .get_at("snapshot:kept", position)
+      .await
+      .err()
+      .unwrap(),
+    ClientError::VersionUnavailable
+  ));
+}
+
+#[tokio::test]
+async fn test_snapshot_after_failed_write() {
+  let mut analytics = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("snapshot:late").await;
+
+  // Nothing is logged for it, so the next write gets the same version.
+  assert!(client.lpop("snapshot:missing").await.unwrap().is_none());
+  analytics.snapshot().await.unwrap();
+  client.set("snapshot:late", 1u8).await.unwrap();
+
+  assert!(analytics.get("snapshot:late").await.is_err());
+  analytics.release_snapshot().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_history() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.set("history", 1u8).await.unwrap();
+  client.incr("history:other").await.unwrap();
+  client.set("history", 2u8).await.unwrap();
+  client.delete("history").await.unwrap();
+  client.mset(&[("history", 3u8)]).await.unwrap();
+
+  let events = client.history("history", Some(4)).await.unwrap();
+  let values: Vec<Option<u8>> = events
+    .iter()
+    .map(|(_, value)| value.as_ref().and_then(|get| get.try_decode()))
+    .collect();
+  assert_eq!(vec![Some(3u8), None, Some(2u8), Some(1u8)], values);
+
+  let numbers: Vec<u64> = events.iter().map(|(number, _)| *number).collect();
+  assert!(numbers.windows(2).all(|pair| pair[0] > pair[1]));
+
+  assert_eq!(1, client.history("history", Some(1)).await.unwrap().len());
+  assert!(client
+    .history("history:never", None)
+    .await
+    .unwrap()
+    .is_empty());
+
+  // Flushes read as deletes, changes after them start from nothing.
+  let mut flushed = Client::connect("0.0.0.0:4567").await.unwrap();
+  flushed.namespace("history_flush").await.unwrap();
+  flushed.flush_namespace().await.unwrap();
+  flushed.set("key", 1u8).await.unwrap();
+  flushed.rpush("list", &[1u8]).await.unwrap();
+  flushed.flush_namespace().await.unwrap();
+  flushed.flush_namespace().await.unwrap();
+  flushed.rpush("list", &[2u8]).await.unwrap();
+  let values: Vec<Option<u8>> = flushed
+    .history("key", Some(2))
+    .await
+    .unwrap()
+    .iter()
+    .map(|(_, value)| value.as_ref().and_then(|get| get.try_decode()))
+    .collect();
+  assert_eq!(vec![None, Some(1u8)], values);
+  let lists: Vec<Option<Vec<u8>>> = flushed
+    .history("list", Some(3))
+    .await
+    .unwrap()
+    .iter()
+    .map(|(_, value)| value.as_ref().map(|get| get.as_bytes().to_vec()))
+    .collect();
+  assert_eq!(
+    vec![
+      Some(traf_lib::length_prefixed::encode(&[[2u8]])),
+      None,
+      Some(traf_lib::length_prefixed::encode(&[[1u8]])),
+    ],
+    lists
+  );
+
+  // Changes read as the value they left.
+  let _ = client.delete("history:log").await;
+  let _ = client.delete("history:list").await;
+  client.append("history:log", b"ab").await.unwrap();
+  client.append("history:log", b"cd").await.unwrap();
+  client.set_range("history:log", 0, b"A").await.unwrap();
+  let values: Vec<Vec<u8>> = client
+    .history("history:log", Some(3))
+    .await
+    .unwrap()
+    .iter()
+    .map(|(_, value)| value.as_ref().unwrap().as_bytes().to_vec())
+    .collect();
+  assert_eq!(
+    vec![b"Abcd".to_vec(), b"abcd".to_vec(), b"ab".to_vec()],
+    values
+  );
+
+  client.rpush("history:list", &[1u8]).await.unwrap();
+  client.rpush("history:list", &[2u8]).await.unwrap();
+  client.lpop("history:list").await.unwrap();
+  let lists: Vec<Vec<Vec<u8>>> = client
+    .history("history:list", Some(3))
+    .await
+    .unwrap()
+    .iter()
+    .map(|(_, value)| {
+      traf_lib::length_prefixed::decode(value.as_ref().unwrap().as_bytes()).unwrap()
+    })
+    .collect();
+  assert_eq!(
+    vec![vec![vec![2u8]], vec![vec![1u8], vec![2u8]], vec![vec![1u8]]],
+    lists
+  );
+}
+
+#[tokio::test]
+async fn test_rename_and_copy() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  for key in ["rename:src", "rename:dst", "rename:copy"] {
+    let _ = client.delete(key).await;
+  }
+
+  assert!(matches!(
+    client
+      .rename("rename:src", "rename:dst")
+      .await
+      .err()
+      .unwrap(),
+    ClientError::NotFound
+  ));
+
+  client.set("rename:src", 1u8).await.unwrap();
+  client.expire("rename:src", 100).await.unwrap();
+  client.rename("rename:src", "rename:dst").await.unwrap();
+  assert!(client.get("rename:src").await.is_err());
+  assert_eq!(
+    Some(1u8),
+    client.get("rename:dst").await.unwrap().try_decode()
+  );
+  assert!(client.ttl("rename:dst").await.unwrap().is_some());
+
+  client.copy("rename:dst", "rename:copy").await.unwrap();
+  assert!(matches!(
+    client
+      .copy("rename:dst", "rename:copy")
+      .await
+      .err()
+      .unwrap(),
+    ClientError::KeyExists
+  ));
+  client.set("rename:src", 2u8).await.unwrap();
+  assert!(matches!(
+    client
+      .rename_if_absent("rename:src", "rename:copy")
+      .await
+      .err()
+      .unwrap(),
+    ClientError::KeyExists
+  ));
+  client.rename("rename:src", "rename:copy").await.unwrap();
+  assert_eq!(
+    Some(2u8),
+    client.get("rename:copy").await.unwrap().try_decode()
+  );
+  assert_eq!(
+    Some(1u8),
+    client.get("rename:dst").await.unwrap().try_decode()
+  );
+
+  // The history of the destination carries the value it got.
+  let events = client.history("rename:copy", Some(2)).await.unwrap();
+  let values: Vec<Option<u8>> = events
+    .iter()
+    .map(|(_, value)| value.as_ref().and_then(|get| get.try_decode()))
+    .collect();
+  assert_eq!(vec![Some(2u8), Some(1u8)], values);
+}
+
+#[tokio::test]
+async fn test_multi_key_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client
+    .mset(&[("multi 1", 1u8), ("multi 2", 2u8)])
+    .await
+    .unwrap();
+
+  let values = client
+    .mget(&["multi 1", "multi 2", "multi 3"])
+    .await
+    .unwrap();
+  let decoded: Vec<Option<u8>> = values
+    .iter()
+    .map(|value| value.as_ref().and_then(|get| get.try_decode()))
+    .collect();
+  assert_eq!(vec![Some(1u8), Some(2u8), None], decoded);
+
+  client.mdel(&["multi 1", "multi 2"]).await.unwrap();
+  assert!(matches!(
+    client.mdel(&["multi 1", "multi 2"]).await.err().unwrap(),
+    ClientError::NotFound
+  ));
+}
+
+#[tokio::test]
+async fn test_scan_and_range_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client
+    .mset(&[
+      ("scan:c", 3u8),
+      ("scan:a", 1u8),
+      ("scan:b", 2u8),
+      ("scanx", 0u8),
+    ])
+    .await
+    .unwrap();
+
+  let (cursor, page) = client.scan_page("0", b"scan:", 2).await.unwrap();
+  let page_keys: Vec<Vec<u8>> = page.into_iter().map(|(key, _)| key).collect();
+  assert_eq!(vec![b"scan:a".to_vec(), b"scan:b".to_vec()], page_keys);
+
+  let (cursor, page) = client
+    .scan_page(&cursor.unwrap(), b"scan:", 2)
+    .await
+    .unwrap();
+  assert_eq!(None, cursor);
+  assert_eq!(1, page.len());
+
+  // Huge counts are capped by the server.
+  let (cursor, page) = client.scan_page("0", b"scan:", usize::MAX).await.unwrap();
+  assert_eq!(None, cursor);
+  assert_eq!(3, page.len());
+
+  let scanned: Vec<(Vec<u8>, Option<u8>)> = client
+    .scan(b"scan:")
+    .map(|entry| {
+      let (key, get) = entry.unwrap();
+      (key, get.try_decode())
+    })
+    .collect()
+    .await;
+  assert_eq!(
+    vec![
+      (b"scan:a".to_vec(), Some(1u8)),
+      (b"scan:b".to_vec(), Some(2u8)),
+      (b"scan:c".to_vec(), Some(3u8)),
+    ],
+    scanned
+  );
+
+  let range_keys: Vec<Vec<u8>> = client
+    .range("scan:b", "scanx")
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|(key, _)| key)
+    .collect();
+  assert_eq!(vec![b"scan:b".to_vec(), b"scan:c".to_vec()], range_keys);
+}
+
+#[tokio::test]
+async fn test_transaction() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client
+    .mset(&[("tx:from", 10i64), ("tx:to", 0i64)])
+    .await
+    .unwrap();
+
+  let results = client
+    .transaction()
+    .incr_by("tx:from", -3)
+    .incr_by("tx:to", 3)
+    .exec()
+    .await
+    .unwrap();
+  assert_eq!(2, results.len());
+  assert_eq!(
+    Some(7i64),
+    client.get("tx:from").await.unwrap().try_decode()
+  );
+  assert_eq!(Some(3i64), client.get("tx:to").await.unwrap().try_decode());
+
+  client.watch(&["tx:from"]).await.unwrap();
+
+  let mut other_client = Client::connect("0.0.0.0:4567").await.unwrap();
+  other_client.set("tx:from", 100i64).await.unwrap();
+
+  let aborted = client.transaction().set("tx:to", 42i64).exec().await;
+  assert!(matches!(aborted, Err(ClientError::VersionMismatch)));
+  assert_eq!(Some(3i64), client.get("tx:to").await.unwrap().try_decode());
+
+  // Watches are cleared by EXEC.
+  assert!(client.transaction().delete("tx:to").exec().await.is_ok());
+}
+
+#[tokio::test]
+async fn test_transaction_flush_namespace() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.namespace("txns").await.unwrap();
+  client.set("txns:before", 1i64).await.unwrap();
+
+  let results = client
+    .transaction()
+    .set("txns:flushed", 2i64)
+    .flush_namespace()
+    .set("txns:after", 3i64)
+    .exec()
+    .await
+    .unwrap();
+  assert_eq!(3, results.len());
+  assert!(client.get("txns:before").await.is_err());
+  assert!(client.get("txns:flushed").await.is_err());
+  assert_eq!(
+    Some(3i64),
+    client.get("txns:after").await.unwrap().try_decode()
+  );
+
+  // The server is still alive to take the next change.
+  client.set("txns:before", 4i64).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_pipeline() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("pipeline:counter").await;
+  client.set("pipeline:untouched", 1i64).await.unwrap();
+
+  // Each read sees the writes before it. Reads of other keys are answered without waiting.
+  let mut pipeline = client.pipeline().get("pipeline:untouched");
+  for i in 0..70i64 {
+    pipeline = pipeline
+      .set(format!("pipeline:{}", i), i)
+      .incr_by("pipeline:counter", 1);
+  }
+  let results = pipeline
+    .get("pipeline:untouched")
+    .get("pipeline:69")
+    .exec()
+    .await
+    .unwrap();
+  assert_eq!(143, results.len());
+  assert_eq!(Some(&ResponseFrame::Success), results.get(1));
+  let values: Vec<Option<i64>> = [0, 141, 142]
+    .iter()
+    .map(|&i| match &results[i] {
+      ResponseFrame::VersionedValue(_, bytes) | ResponseFrame::Value(bytes) => {
+        bincode::deserialize(bytes).ok()
+      }
+      _ => None,
+    })
+    .collect();
+  assert_eq!(vec![Some(1), Some(1), Some(69)], values);
+
+  assert_eq!(
+    Some(70i64),
+    client.get("pipeline:counter").await.unwrap().try_decode()
+  );
+  assert_eq!(
+    Some(69i64),
+    client.get("pipeline:69").await.unwrap().try_decode()
+  );
+}
+
+#[tokio::test]
+async fn test_shared_connection() {
+  let client = Client::connect("0.0.0.0:4567").await.unwrap();
+
+  let mut join_handles = vec![];
+  for task in 0..8i64 {
+    let mut client = client.clone();
+    join_handles.push(tokio::spawn(async move {
+      for i in 0..10i64 {
+        let key = format!("shared:{}:{}", task, i);
+        client.set(&key, task * 100 + i).await.unwrap();
+        assert_eq!(
+          Some(task * 100 + i),
+          client.get(&key).await.unwrap().try_decode()
+        );
+      }
+    }));
+  }
+  for join_handle in join_handles {
+    join_handle.await.unwrap();
+  }
+
+  // Transactions of clones do not mix.
+  let mut other = client.clone();
+  let mut client = client;
+  let (left, right) = tokio::join!(
+    client
+      .transaction()
+      .set("shared:tx:left", 1i64)
+      .set("shared:tx:left", 2i64)
+      .exec(),
+    other.transaction().set("shared:tx:right", 3i64).exec()
+  );
+  assert_eq!(2, left.unwrap().len());
+  assert_eq!(1, right.unwrap().len());
+}
+
+#[tokio::test]
+async fn test_namespaces() {
+  let mut team_a = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut team_b = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut default = Client::connect("0.0.0.0:4567").await.unwrap();
+
+  team_a
+    .namespace("team_a")
+    .await
+    .unwrap()
+    .set("ns:key", 1u8)
+    .await
+    .unwrap();
+  team_b
+    .namespace("team_b")
+    .await
+    .unwrap()
+    .set("ns:key", 2u8)
+    .await
+    .unwrap();
+
+  assert_eq!(Some(1u8), team_a.get("ns:key").await.unwrap().try_decode());
+  assert_eq!(Some(2u8), team_b.get("ns:key").await.unwrap().try_decode());
+  assert!(matches!(
+    default.get("ns:key").await,
+    Err(ClientError::NotFound)
+  ));
+
+  team_a.flush_namespace().await.unwrap();
+  assert!(matches!(
+    team_a.get("ns:key").await,
+    Err(ClientError::NotFound)
+  ));
+  assert_eq!(Some(2u8), team_b.get("ns:key").await.unwrap().try_decode());
+
+  assert!(team_a.namespace("not valid").await.is_err());
+}
+
+#[tokio::test]
+async fn test_move() {
+  let mut team_a = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut team_b = Client::connect("0.0.0.0:4567").await.unwrap();
+  team_a.namespace("move_a").await.unwrap();
+  team_b.namespace("move_b").await.unwrap();
+  let _ = team_a.delete("move:key").await;
+  let _ = team_b.delete("move:key").await;
+
+  team_a.set("move:key", 1u8).await.unwrap();
+  team_a.expire("move:key", 100).await.unwrap();
+  team_a.move_key("move:key", "move_b").await.unwrap();
+  assert!(team_a.get("move:key").await.is_err());
+  assert_eq!(
+    Some(1u8),
+    team_b.get("move:key").await.unwrap().try_decode()
+  );
+  assert!(team_b.ttl("move:key").await.unwrap().is_some());
+
+  assert!(matches!(
+    team_a.move_key("move:key", "move_b").await,
+    Err(ClientError::NotFound)
+  ));
+  team_a.set("move:key", 2u8).await.unwrap();
+  assert!(matches!(
+    team_a.move_key("move:key", "move_b").await,
+    Err(ClientError::KeyExists)
+  ));
+  assert!(matches!(
+    team_a.move_key("move:key", "not valid").await,
+    Err(ClientError::Malformed)
+  ));
+
+  // The history of the key in the target namespace carries the value it got.
+  let values: Vec<Option<u8>> = team_b
+    .history("move:key", Some(1))
+    .await
+    .unwrap()
+    .iter()
+    .map(|(_, value)| value.as_ref().and_then(|get| get.try_decode()))
+    .collect();
+  assert_eq!(vec![Some(1u8)], values);
+}
+
+#[tokio::test]
+async fn test_keys_and_dbsize() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.namespace("keys_test").await.unwrap();
+  client.flush_namespace().await.unwrap();
+  assert_eq!(0, client.dbsize().await.unwrap());
+
+  client
+    .mset(&[("user:1", 1u8), ("user:2", 2u8), ("order:1", 3u8)])
+    .await
+    .unwrap();
+  assert_eq!(3, client.dbsize().await.unwrap());
+
+  assert_eq!(
+    (vec![b"user:1".to_vec(), b"user:2".to_vec()], false),
+    client.keys("user:*", None).await.unwrap()
+  );
+  assert_eq!(
+    (vec![b"order:1".to_vec(), b"user:1".to_vec()], false),
+    client.keys("*:1", None).await.unwrap()
+  );
+  let (keys, truncated) = client.keys("*", Some(1)).await.unwrap();
+  assert_eq!(1, keys.len());
+  assert!(truncated);
+  let (keys, _) = client.keys("user:*", Some(usize::MAX)).await.unwrap();
+  assert_eq!(2, keys.len());
+}
+
+async fn next_key_change(
+  changes: &mut (impl futures::Stream<Item = Result<Change, ClientError>> + Unpin),
+) -> (Vec<u8>, Option<Get>) {
+  match changes.next().await.unwrap().unwrap() {
+    Change::Key(key, value) => (key, value),
+    Change::Flush => panic!("unexpected flush"),
+  }
+}
+
+#[tokio::test]
+async fn test_subscribe_flush() {
+  let mut subscriber = Client::connect("0.0.0.0:4567").await.unwrap();
+  subscriber.namespace("subflush").await.unwrap();
+  let mut changes = Box::pin(subscriber.subscribe("*").await.unwrap());
+
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.namespace("subflush").await.unwrap();
+  client.set("key", 1u8).await.unwrap();
+  client.flush_namespace().await.unwrap();
+
+  let (key, _) = next_key_change(&mut changes).await;
+  assert_eq!(b"key".to_vec(), key);
+  assert!(matches!(changes.next().await.unwrap(), Ok(Change::Flush)));
+}
+
+#[tokio::test]
+async fn test_subscribe_renames() {
+  let subscriber = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut changes = Box::pin(subscriber.subscribe("subren:*").await.unwrap());
+
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.set("subren:a", 1u8).await.unwrap();
+  client.rename("subren:a", "subren:b").await.unwrap();
+  client.copy("subren:b", "subren:c").await.unwrap();
+
+  let mut received: Vec<(Vec<u8>, Option<u8>)> = vec![];
+  for _ in 0..4 {
+    let (key, value) = next_key_change(&mut changes).await;
+    received.push((key, value.and_then(|get| get.try_decode())));
+  }
+  assert_eq!(
+    vec![
+      (b"subren:a".to_vec(), Some(1u8)),
+      (b"subren:b".to_vec(), Some(1u8)),
+      (b"subren:a".to_vec(), None),
+      (b"subren:c".to_vec(), Some(1u8)),
+    ],
+    received
+  );
+}
+
+#[tokio::test]
+async fn test_subscribe_changes() {
+  let subscriber = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut changes = Box::pin(subscriber.subscribe("subchg:*").await.unwrap());
+
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.append("subchg:log", b"ab").await.unwrap();
+  client.rpush("subchg:list", &[1u8]).await.unwrap();
+  client.lpop("subchg:list").await.unwrap();
+
+  let mut received: Vec<(Vec<u8>, Option<Vec<u8>>)> = vec![];
+  for _ in 0..3 {
+    let (key, value) = next_key_change(&mut changes).await;
+    received.push((key, value.map(|get| get.as_bytes().to_vec())));
+  }
+  assert_eq!(
+    vec![
+      (b"subchg:log".to_vec(), Some(b"ab".to_vec())),
+      (
+        b"subchg:list".to_vec(),
+        Some(traf_lib::length_prefixed::encode(&[[1u8]]))
+      ),
+      (b"subchg:list".to_vec(), None),
+    ],
+    received
+  );
+}
+
+#[tokio::test]
+async fn test_subscribe() {
+  let subscriber = Client::connect("0.0.0.0:4567").await.unwrap();
+  let mut changes = Box::pin(subscriber.subscribe("sub:*").await.unwrap());
+
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  client.set("other", 0u8).await.unwrap();
+  client.set("sub:a", 1u8).await.unwrap();
+  client.delete("sub:a").await.unwrap();
+
+  let (key, value) = next_key_change(&mut changes).await;
+  assert_eq!(b"sub:a".to_vec(), key);
+  assert_eq!(Some(1u8), value.unwrap().try_decode());
+
+  let (key, value) = next_key_change(&mut changes).await;
+  assert_eq!(b"sub:a".to_vec(), key);
+  assert!(value.is_none());
+}
+
+#[tokio::test]
+async fn test_byte_range_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("range:log").await;
+
+  assert_eq!(5, client.append("range:log", b"hello").await.unwrap());
+  assert_eq!(11, client.append("range:log", b" world").await.unwrap());
+  assert_eq!(11, client.strlen("range:log").await.unwrap());
+
+  assert_eq!(
+    b"world".to_vec(),
+    client.get_range("range:log", -5, -1).await.unwrap()
+  );
+  assert_eq!(
+    b"hello".to_vec(),
+    client.get_range("range:log", 0, 4).await.unwrap()
+  );
+  assert!(client
+    .get_range("range:log", 5, 2)
+    .await
+    .unwrap()
+    .is_empty());
+
+  assert_eq!(
+    11,
+    client.set_range("range:log", 6, b"WORLD").await.unwrap()
+  );
+  assert_eq!(14, client.set_range("range:log", 13, b"!").await.unwrap());
+  assert_eq!(
+    b"hello WORLD\0\0!".to_vec(),
+    client.get_range("range:log", 0, -1).await.unwrap()
+  );
+
+  assert!(matches!(
+    client.strlen("range:missing").await,
+    Err(ClientError::NotFound)
+  ));
+  assert!(matches!(
+    client.set_range("range:log", 1 << 30, b"!").await,
+    Err(ClientError::TooLarge)
+  ));
+  assert!(matches!(
+    client.set_range("range:log", u64::MAX, b"!").await,
+    Err(ClientError::TooLarge)
+  ));
+}
+
+#[tokio::test]
+async fn test_collections_flow() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("coll:list").await;
+  let _ = client.delete("coll:hash").await;
+  let _ = client.delete("coll:set").await;
+
+  assert_eq!(2, client.rpush("coll:list", &[2u32, 3u32]).await.unwrap());
+  assert_eq!(4, client.lpush("coll:list", &[1u32, 0u32]).await.unwrap());
+  let list: Vec<u32> = client
+    .lrange("coll:list", 0, -1)
+    .await
+    .unwrap()
+    .iter()
+    .map(|get| get.try_decode().unwrap())
+    .collect();
+  assert_eq!(vec![0, 1, 2, 3], list);
+  assert_eq!(
+    Some(0u32),
+    client
+      .lpop("coll:list")
+      .await
+      .unwrap()
+      .unwrap()
+      .try_decode()
+  );
+  assert_eq!(
+    Some(3u32),
+    client
+      .rpop("coll:list")
+      .await
+      .unwrap()
+      .unwrap()
+      .try_decode()
+  );
+  assert_eq!(1, client.lrange("coll:list", -1, -1).await.unwrap().len());
+
+  assert_eq!(
+    2,
+    client
+      .hset("coll:hash", &[("a", 1u8), ("b", 2u8)])
+      .await
+      .unwrap()
+  );
+  assert_eq!(0, client.hset("coll:hash", &[("a", 10u8)]).await.unwrap());
+  assert_eq!(
+    Some(10u8),
+    client
+      .hget("coll:hash", "a")
+      .await
+      .unwrap()
+      .unwrap()
+      .try_decode()
+  );
+  assert!(client.hget("coll:hash", "c").await.unwrap().is_none());
+  assert_eq!(1, client.hdel("coll:hash", &["b", "c"]).await.unwrap());
+  // Removing nothing is not a write.
+  let event_count = client.history("coll:hash", None).await.unwrap().len();
+  assert_eq!(0, client.hdel("coll:hash", &["c"]).await.unwrap());
+  assert_eq!(0, client.hdel("coll:missing", &["c"]).await.unwrap());
+  assert_eq!(0, client.srem("coll:missing", &["x"]).await.unwrap());
+  assert_eq!(
+    event_count,
+    client.history("coll:hash", None).await.unwrap().len()
+  );
+  assert!(client
+    .history("coll:missing", None)
+    .await
+    .unwrap()
+    .is_empty());
+  let hash = client.hgetall("coll:hash").await.unwrap();
+  assert_eq!(1, hash.len());
+  assert_eq!(b"a".to_vec(), hash[0].0);
+
+  assert_eq!(2, client.sadd("coll:set", &["x", "y", "x"]).await.unwrap());
+  assert!(client.sismember("coll:set", "x").await.unwrap());
+  assert_eq!(1, client.srem("coll:set", &["x"]).await.unwrap());
+  assert!(!client.sismember("coll:set", "x").await.unwrap());
+  assert_eq!(1, client.smembers("coll:set").await.unwrap().len());
+
+  assert!(matches!(
+    client.get("coll:set").await,
+    Err(ClientError::WrongType)
+  ));
+  assert!(matches!(
+    client.lpush("coll:hash", &[1u8]).await,
+    Err(ClientError::WrongType)
+  ));
+}
+
+#[tokio::test]
+async fn test_binary_keys() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let key: &[u8] = b"bin: key \xff\x00 with spaces";
+
+  client.set(key, 7u8).await.unwrap();
+  assert_eq!(Some(7u8), client.get(key).await.unwrap().try_decode());
+
+  let values = client.mget(&[key, b"bin:missing"]).await.unwrap();
+  assert_eq!(Some(7u8), values[0].as_ref().unwrap().try_decode());
+  assert!(values[1].is_none());
+
+  let scanned: Vec<Vec<u8>> = client
+    .scan(b"bin: ")
+    .map(|entry| entry.unwrap().0)
+    .collect()
+    .await;
+  assert_eq!(vec![key.to_vec()], scanned);
+
+  assert!(client.delete(key).await.is_ok());
+  assert!(matches!(
+    client.get(key).await.err().unwrap(),
+    ClientError::NotFound
+  ));
+}
+
+#[tokio::test]
+async fn test_stat() {
+  let mut client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let _ = client.delete("stat:key").await;
+  assert!(matches!(
+    client.stat("stat:key").await.err().unwrap(),
+    ClientError::NotFound
+  ));
+
+  client.set("stat:key", 1u64).await.unwrap();
+  let (version, _) = client.get_versioned("stat:key").await.unwrap();
+  let created = client.stat("stat:key").await.unwrap();
+  assert_eq!(version, created.version);
+  assert_eq!(8, created.size);
+  assert_eq!(created.created_at, created.modified_at);
+
+  // Reads move the access time, stats do not.
+  sleep(Duration::from_millis(10)).await;
+  client.get("stat:key").await.unwrap();
+  let read = client.stat("stat:key").await.unwrap();
+  assert!(read.accessed_at > created.accessed_at);
+  assert_eq!(read, client.stat("stat:key").await.unwrap());
+
+  sleep(Duration::from_millis(10)).await;
+  client.set("stat:key", 2u64).await.unwrap();
+  let modified = client.stat("stat:key").await.unwrap();
+  assert_eq!(created.created_at, modified.created_at);
+  assert!(modified.modified_at > created.modified_at);
+  assert!(modified.version > created.version);
+}
+
+#[tokio::test]
+async fn test_hello() {
+  let client = Client::connect("0.0.0.0:4567").await.unwrap();
+  let server_info = client.server_info();
+  assert_eq!(PROTOCOL_VERSION, server_info.protocol_version);
+  assert_eq!("writer", server_info.role);
+  assert!(server_info
+    .capabilities
+    .iter()
+    .any(|capability| capability == "history"));
 }
@@ -1,11 +1,27 @@
+use crate::eviction::MemoryLimit;
 use crate::file_backup::FileBackup;
+use crate::pubsub::Publisher;
 use crate::replicator::{ReaderList, Replicator};
 use crate::storage::*;
-use crate::FrameAndChannel;
+use crate::transaction::is_watch_intact;
+use crate::CommandAndChannel;
 use crate::{command::*, Executor};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
-use traf_lib::response_frame::ResponseFrame;
+use tokio::time::interval;
+use traf_lib::response_frame::{ResponseFrame, ServerInfo, PROTOCOL_VERSION};
+
+const EXPIRY_SWEEP_INTERVAL_MS: u64 = 100;
+// Optional features announced to clients in HELLO. Only writers keep the event log for HISTORY.
+const CAPABILITIES: [&str; 6] = [
+  "namespaces",
+  "transactions",
+  "subscribe",
+  "snapshots",
+  "collections",
+  "history",
+];
 
 #[derive(PartialEq)]
 pub enum InstanceType {
@@ -13,12 +29,13 @@ pub enum InstanceType {
   Writer,
 }
 
-pub struct App {
-  storage: Arc<Mutex<Storage>>,
-  rx: Receiver<FrameAndChannel>,
+pub struct App<E: KeyspaceEngine> {
+  storage: Arc<RwLock<Storage<E>>>,
+  rx: Receiver<CommandAndChannel>,
   backup: FileBackup,
   instance_type: InstanceType,
   replicator: Replicator,
+  publisher: Publisher,
   last_replica_id: Option<u64>,
   replica_sync_mutex: Mutex<()>,
 }
@@ -26,68 +43,166 @@ pub struct App {
 // IDEA: Something smells with the App being either writer or reader and some behaviour divides on this.
 //        Almost like it should be 2 types. Somehow this should be way safer.
 
-impl App {
+impl<E: KeyspaceEngine> App<E> {
   pub fn new(
     instance_type: InstanceType,
     last_replica_id: Option<u64>,
     readers: ReaderList,
-    rx: Receiver<FrameAndChannel>,
+    memory_limit: Option<MemoryLimit>,
+    publisher: Publisher,
+    rx: Receiver<CommandAndChannel>,
   ) -> Self {
-    let storage = Arc::new(Mutex::new(Storage::new()));
+    let storage = Arc::new(RwLock::new(Storage::new(memory_limit)));
     let backup = FileBackup::new("/tmp".into());
 
     backup.restore(storage.clone());
 
-    App {
+    let app = App {
       storage: storage.clone(),
       rx,
       backup,
       instance_type,
       replicator: Replicator::new("/tmp".into(), readers),
+      publisher,
       last_replica_id,
       replica_sync_mutex: Mutex::new(()),
-    }
+    };
+
+    // Snapshots taken before the first write see all the restored events.
+    storage
+      .read()
+      .unwrap()
+      .snapshots()
+      .advance_to(app.next_version());
+
+    app
+  }
+
+  // The store shared with the connections, which run reads on it directly.
+  pub fn storage(&self) -> Arc<RwLock<Storage<E>>> {
+    self.storage.clone()
   }
 
   pub async fn listen(&mut self) {
     info!("app start listening");
-    while let Some(frame_and_channel) = self.rx.recv().await {
-      info!("app channel got message");
-      let res = self.execute(frame_and_channel.frame.bytes).await;
+    let mut expiry_sweep_interval = interval(Duration::from_millis(EXPIRY_SWEEP_INTERVAL_MS));
+
+    loop {
+      tokio::select! {
+        command_and_channel = self.rx.recv() => {
+          let command_and_channel = match command_and_channel {
+            Some(command_and_channel) => command_and_channel,
+            None => break,
+          };
+
+          info!("app channel got message");
+          let res = self.execute(command_and_channel.command).await;
+
+          command_and_channel
+            .channel
+            .send(res)
+            .expect("Failed sending response");
+        }
+        // Only the writer expires keys. Readers receive the expirations as deletes via sync, so
+        // they converge on the writer's clock rather than their own.
+        _ = expiry_sweep_interval.tick(), if !self.is_read_only() => {
+          self.sweep_expired_keys().await;
+        }
+      }
+    }
+  }
+
+  async fn sweep_expired_keys(&mut self) {
+    let expired_keys = self.storage.read().unwrap().expired_keys(now_ms());
+
+    for (namespace, key) in expired_keys {
+      info!("Key expired: {:?} in {:?}", key, namespace);
+      self.delete_key(namespace, key).await;
+    }
+  }
+
+  // Evicts keys until the storage is within its memory limit. Returns false if it's still over the
+  // limit as the policy allows no (more) evictions.
+  async fn evict_to_memory_limit(&mut self) -> bool {
+    loop {
+      let candidate = {
+        let storage = self.storage.read().unwrap();
+        if !storage.is_over_memory_limit() {
+          return true;
+        }
+        storage.eviction_candidate()
+      };
 
-      frame_and_channel
-        .channel
-        .send(res.into())
-        .expect("Failed sending response");
+      match candidate {
+        Some((namespace, key)) => {
+          info!("Key evicted: {:?} in {:?}", key, namespace);
+          self.delete_key(namespace, key).await;
+        }
+        None => return false,
+      }
+    }
+  }
+
+  // Deletes a key on behalf of the writer itself (expiry, eviction). Readers get it as a plain delete.
+  async fn delete_key(&mut self, namespace: NamespaceT, key: KeyT) {
+    let cmd = Command::Delete { key }.in_namespace(&namespace);
+    let version = self.next_version();
+    let result = self.storage.write().unwrap().execute(cmd.clone(), version);
+
+    if let ResponseFrame::Success = result {
+      self.commit(&cmd, version).await;
     }
   }
 
   // IDEA: More commands:
-  // - inc int / dec int
   // - key defined?
 
-  async fn execute(&mut self, input: Vec<u8>) -> ResponseFrame {
-    let cmd = Command::from(input);
+  // Reads usually run on the connections' tasks, they only get here when they have to wait for
+  // expired keys to be swept.
+  async fn execute(&mut self, command: Command) -> ResponseFrame {
+    let (namespace, cmd) = command.into_namespaced();
+
+    // Do not let commands observe keys that expired since the last sweep.
+    if !self.is_read_only() {
+      self.sweep_expired_keys().await;
+    }
 
     // FIXME: cloning a SET command with value can be expensive. Try to avoid it.
 
     // IDEA: The Executor trait (used by Storage) doesn't seem too strong as not all commands
     //        are owned by a single struct (like the way Storage does). Can we do better?
 
-    let result = match cmd {
-      Command::Set { .. } | Command::Delete { .. } => match self.instance_type {
-        InstanceType::Reader => ResponseFrame::ErrorInvalidCommand,
+    match cmd {
+      cmd if cmd.is_read() => self.storage.read().unwrap().read(&namespace, cmd),
+      cmd if cmd.is_mutation() => match self.instance_type {
+        InstanceType::Reader => ResponseFrame::ErrorReadOnly,
         InstanceType::Writer => {
-          let result = self.storage.lock().unwrap().execute(cmd.clone());
-          match &result {
-            ResponseFrame::Success => self.backup.log(&cmd),
-            _ => (),
+          if cmd.may_grow() && !self.evict_to_memory_limit().await {
+            return ResponseFrame::ErrorOutOfMemory;
+          }
+
+          let version = self.next_version();
+          let (result, effective_cmd) = {
+            let mut storage = self.storage.write().unwrap();
+            let (result, effective_cmd) = apply(storage.keyspace_mut(&namespace), cmd, version);
+            // Moved under the same lock, so no snapshot sees the write without its version.
+            if effective_cmd.is_some() {
+              storage.snapshots().advance_to(version + 1);
+            }
+            (result, effective_cmd)
           };
 
+          if let Some(effective_cmd) = effective_cmd {
+            let effective_cmd = effective_cmd.in_namespace(&namespace);
+            self.commit(&effective_cmd, version).await;
+          }
+
           result
         }
       },
-      Command::Get { .. } => self.storage.lock().unwrap().execute(cmd.clone()),
+      Command::Exec { watches, commands } => {
+        self.execute_transaction(namespace, watches, commands).await
+      }
       Command::GetLastReplicationId => match self.instance_type {
         // IDEA: For a reader not having a last replication id is valid - it might be the beginning.
         //        Though it's also a weakness as we cannot really tell if that's legitimate or not.
@@ -97,6 +212,49 @@ impl App {
         },
         InstanceType::Writer => ResponseFrame::ErrorInvalidCommand,
       },
+      Command::Hello => ResponseFrame::Hello(self.server_info()),
+      Command::History { key, limit } => match self.instance_type {
+        // Only the writer keeps an event log.
+        InstanceType::Reader => ResponseFrame::ErrorReadOnly,
+        InstanceType::Writer => match self.replicator.history(&namespace, &key, limit) {
+          Ok(events) => ResponseFrame::History(events),
+          Err(message) => ResponseFrame::ErrorInternal(message),
+        },
+      },
+      // Not a mutation of a single namespace, the storage moves the key between its keyspaces.
+      Command::Move {
+        key,
+        namespace: dst_namespace,
+      } => match self.instance_type {
+        InstanceType::Reader => ResponseFrame::ErrorReadOnly,
+        InstanceType::Writer => {
+          let cmd = Command::Move {
+            key,
+            namespace: dst_namespace,
+          }
+          .in_namespace(&namespace);
+          let version = self.next_version();
+          let result = self.storage.write().unwrap().execute(cmd.clone(), version);
+          if let ResponseFrame::Success = result {
+            self.commit(&cmd, version).await;
+          }
+          result
+        }
+      },
+      // Not a mutation of the namespace, it clears the whole storage in a single event.
+      Command::FlushAll => match self.instance_type {
+        InstanceType::Reader => ResponseFrame::ErrorReadOnly,
+        InstanceType::Writer => {
+          let version = self.next_version();
+          let result = self
+            .storage
+            .write()
+            .unwrap()
+            .execute(Command::FlushAll, version);
+          self.commit(&Command::FlushAll, version).await;
+          result
+        }
+      },
       Command::Sync { ref dump } => {
         let _replica_mutex = self
           .replica_sync_mutex
@@ -117,27 +275,117 @@ impl App {
           self.last_replica_id = restore_result.last_event_id;
         }
 
-        for applied_cmd in &restore_result.applied_commands {
-          self.backup.log(applied_cmd);
+        // Subscribers of readers get the changes as they arrive with the sync.
+        for (event_id, applied_cmd) in &restore_result.applied_commands {
+          self.backup.log(applied_cmd, *event_id);
+          self
+            .publisher
+            .publish(applied_cmd, &self.storage.read().unwrap());
         }
 
         restore_result.response
       }
-      Command::Invalid => ResponseFrame::ErrorInvalidCommand,
-    };
+      Command::Invalid => ResponseFrame::ErrorMalformed,
+      // Batches only arrive via sync. Namespaces were unwrapped above and do not nest.
+      _ => ResponseFrame::ErrorInvalidCommand,
+    }
+  }
 
-    match &result {
-      // Mutating operations have a result (for now) of ::Success - which is the only case
-      // when we need replica/backup tracking.
-      &ResponseFrame::Success => {
-        if !self.is_read_only() {
-          self.replicator.log(&cmd).await;
+  // Runs the queued commands of a MULTI/EXEC as one unit under a single storage lock. Aborts
+  // without running anything if a watched key changed since the WATCH. All mutations are logged
+  // as one batch event, so readers apply them all or nothing.
+  async fn execute_transaction(
+    &mut self,
+    namespace: NamespaceT,
+    watches: Vec<(KeyT, Option<VersionT>)>,
+    commands: Vec<Command>,
+  ) -> ResponseFrame {
+    if self.is_read_only() && commands.iter().any(Command::is_mutation) {
+      return ResponseFrame::ErrorReadOnly;
+    }
+    if commands.iter().any(Command::may_grow) && !self.evict_to_memory_limit().await {
+      return ResponseFrame::ErrorOutOfMemory;
+    }
+
+    let version = self.next_version();
+    let mut results: Vec<ResponseFrame> = vec![];
+    let mut effective_cmds: Vec<Command> = vec![];
+
+    {
+      let mut storage = self.storage.write().unwrap();
+      let keyspace = storage.keyspace_mut(&namespace);
+
+      if !is_watch_intact(&watches, keyspace) {
+        return ResponseFrame::ErrorVersionMismatch;
+      }
+
+      for cmd in commands {
+        if let Command::Invalid = cmd {
+          results.push(ResponseFrame::ErrorMalformed);
+          continue;
+        }
+        if !cmd.is_mutation() && !cmd.is_read() {
+          results.push(ResponseFrame::ErrorInvalidCommand);
+          continue;
         }
+
+        let (result, effective_cmd) = apply(keyspace, cmd, version);
+        results.push(result);
+        effective_cmds.extend(effective_cmd);
+      }
+
+      if !effective_cmds.is_empty() {
+        storage.snapshots().advance_to(version + 1);
       }
-      _ => (),
+    }
+
+    if !effective_cmds.is_empty() {
+      let batch = Command::Batch {
+        commands: effective_cmds,
+      }
+      .in_namespace(&namespace);
+      self.commit(&batch, version).await;
+    }
+
+    ResponseFrame::Results(results)
+  }
+
+  // Persists, replicates and publishes an applied mutation.
+  async fn commit(&mut self, cmd: &Command, version: VersionT) {
+    self.backup.log(cmd, version);
+    self.replicator.log(cmd).await;
+    self.publisher.publish(cmd, &self.storage.read().unwrap());
+  }
+
+  // The version the next mutation gets - the number of the replication event it will become.
+  fn next_version(&self) -> VersionT {
+    match self.instance_type {
+      InstanceType::Reader => self.last_replica_id.map(|id| id + 1).unwrap_or(0),
+      InstanceType::Writer => self.replicator.next_event_log_number(),
+    }
+  }
+
+  fn server_info(&self) -> ServerInfo {
+    let (role, last_replication_id) = match self.instance_type {
+      InstanceType::Reader => ("reader", self.last_replica_id),
+      InstanceType::Writer => (
+        "writer",
+        self.replicator.next_event_log_number().checked_sub(1),
+      ),
     };
+    let capabilities = CAPABILITIES
+      .iter()
+      .filter(|capability| **capability != "history" || !self.is_read_only())
+      .map(|capability| capability.to_string())
+      .collect();
 
-    result
+    ServerInfo {
+      protocol_version: PROTOCOL_VERSION,
+      version: env!("CARGO_PKG_VERSION").into(),
+      role: role.into(),
+      last_replication_id,
+      capabilities,
+    }
   }
 
   fn is_read_only(&self) -> bool {
@@ -147,3 +395,56 @@ impl App {
     }
   }
 }
+
+// Executes a command and returns the command to log for it (None if nothing changed).
+// Counters, swaps and conditional sets are logged as the resulting plain set, so replaying the logs stays idempotent.
+// Byte-range writes are logged as they are to keep events of large values small - they rely on
+// events being applied exactly once (which event numbers already guarantee). So are renames and
+// copies, readers move the value within the single event.
+fn apply<E: KeyspaceEngine>(
+  storage: &mut Keyspace<E>,
+  cmd: Command,
+  version: VersionT,
+) -> (ResponseFrame, Option<Command>) {
+  match cmd {
+    Command::Incr { ref key } | Command::Decr { ref key } | Command::IncrBy { ref key, .. } => {
+      let key = key.clone();
+      match storage.execute(cmd, version) {
+        ResponseFrame::Value(value) => {
+          let effective_cmd = Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+            expire_at: storage.expire_at(&key),
+          };
+          (ResponseFrame::Value(value), Some(effective_cmd))
+        }
+        result => (result, None),
+      }
+    }
+    Command::Cas {
+      ref key, ref value, ..
+    }
+    | Command::SetNx { ref key, ref value }
+    | Command::SetXx { ref key, ref value }
+    | Command::GetSet { ref key, ref value } => {
+      let effective_cmd = Command::Set {
+        key: key.clone(),
+        value: value.clone(),
+        expire_at: None,
+      };
+      // GETSET answers with the replaced value.
+      match storage.execute(cmd, version) {
+        result @ ResponseFrame::Success | result @ ResponseFrame::Value(_) => {
+          (result, Some(effective_cmd))
+        }
+        result => (result, None),
+      }
+    }
+    // Byte-range writes and collection operations answer with a value (eg. a length) on success.
+    cmd if cmd.is_mutation() => match storage.execute(cmd.clone(), version) {
+      result @ ResponseFrame::Success | result @ ResponseFrame::Value(_) => (result, Some(cmd)),
+      result => (result, None),
+    },
+    cmd => (storage.execute(cmd, version), None),
+  }
+}
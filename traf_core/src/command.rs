@@ -1,57 +1,789 @@
-use std::convert::TryInto;
+use crate::storage::{now_ms, KeyT, NamespaceT, DEFAULT_NAMESPACE};
+use std::convert::{TryFrom, TryInto};
+use traf_lib::request::{self, Opcode, Request};
 
+// Commands travel (and are logged) as binary requests, see `traf_lib::request`. Keys are binary (eg.
+// hashes or composite ids) and can hold spaces, so each key is a field of its own.
+// Expiry deadlines are absolute unix timestamps in milliseconds. Relative forms (EX, EXPIRE) are
+// converted on parse so the event log and the backup only ever carry the writer's deadline.
 #[derive(Clone)]
 pub enum Command {
-  Set { key: String, value: Vec<u8> },
-  Get { key: String },
-  Delete { key: String },
+  Set {
+    key: KeyT,
+    value: Vec<u8>,
+    expire_at: Option<u64>,
+  },
+  Get {
+    key: KeyT,
+  },
+  // The key as of a point of the event log (`GET <key> AT <position>`): as it was before the event
+  // `position` applied. Only positions pinned by a SNAPSHOT can be read.
+  GetAt {
+    key: KeyT,
+    position: u64,
+  },
+  // Compare-and-swap: only sets the value if the key is still at the expected version.
+  Cas {
+    key: KeyT,
+    expected_version: u64,
+    value: Vec<u8>,
+  },
+  // Conditional sets: only if the key is missing (SETNX) / present (SETXX), or always, answering
+  // with the value it replaced (GETSET).
+  SetNx {
+    key: KeyT,
+    value: Vec<u8>,
+  },
+  SetXx {
+    key: KeyT,
+    value: Vec<u8>,
+  },
+  GetSet {
+    key: KeyT,
+    value: Vec<u8>,
+  },
+  Delete {
+    key: KeyT,
+  },
+  // Moves the value of `src` (with its expiry) to `dst`, replacing it unless `nx` is set.
+  Rename {
+    src: KeyT,
+    dst: KeyT,
+    nx: bool,
+  },
+  // Copies the value of `src` (with its expiry) to `dst`, only if `dst` is missing.
+  Copy {
+    src: KeyT,
+    dst: KeyT,
+  },
+  // Moves the key (with its expiry) to another namespace, only if it's missing there. Not a
+  // mutation of a single namespace, the storage runs it.
+  Move {
+    key: KeyT,
+    namespace: NamespaceT,
+  },
+  Incr {
+    key: KeyT,
+  },
+  Decr {
+    key: KeyT,
+  },
+  IncrBy {
+    key: KeyT,
+    delta: i64,
+  },
+  MGet {
+    keys: Vec<KeyT>,
+  },
+  MSet {
+    pairs: Vec<(KeyT, Vec<u8>)>,
+  },
+  MDel {
+    keys: Vec<KeyT>,
+  },
+  // Cursor is None for a new scan, otherwise the last key returned by the previous page.
+  Scan {
+    cursor: Option<KeyT>,
+    prefix: KeyT,
+    count: usize,
+  },
+  // Keys from start (inclusive) to end (exclusive).
+  Range {
+    start: KeyT,
+    end: KeyT,
+  },
+  // Byte-range operations. They are logged as they are (as deltas), not as the resulting value.
+  Append {
+    key: KeyT,
+    value: Vec<u8>,
+  },
+  // Bytes from start to end (both inclusive), negative positions count from the end of the value.
+  GetRange {
+    key: KeyT,
+    start: i64,
+    end: i64,
+  },
+  // Overwrites the value from offset, padding it with zero bytes if it's shorter than offset.
+  SetRange {
+    key: KeyT,
+    offset: usize,
+    value: Vec<u8>,
+  },
+  StrLen {
+    key: KeyT,
+  },
+  // Collection types.
+  LPush {
+    key: KeyT,
+    elements: Vec<Vec<u8>>,
+  },
+  RPush {
+    key: KeyT,
+    elements: Vec<Vec<u8>>,
+  },
+  LPop {
+    key: KeyT,
+  },
+  RPop {
+    key: KeyT,
+  },
+  // Elements from start to stop (both inclusive), negative positions count from the end.
+  LRange {
+    key: KeyT,
+    start: i64,
+    stop: i64,
+  },
+  HSet {
+    key: KeyT,
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+  },
+  HGet {
+    key: KeyT,
+    field: Vec<u8>,
+  },
+  HDel {
+    key: KeyT,
+    fields: Vec<Vec<u8>>,
+  },
+  HGetAll {
+    key: KeyT,
+  },
+  SAdd {
+    key: KeyT,
+    members: Vec<Vec<u8>>,
+  },
+  SRem {
+    key: KeyT,
+    members: Vec<Vec<u8>>,
+  },
+  SMembers {
+    key: KeyT,
+  },
+  SIsMember {
+    key: KeyT,
+    member: Vec<u8>,
+  },
+  Expire {
+    key: KeyT,
+    expire_at: u64,
+  },
+  Ttl {
+    key: KeyT,
+  },
+  Persist {
+    key: KeyT,
+  },
+  // Metadata of the key (timestamps, version, size). Does not count as an access of the key.
+  Stat {
+    key: KeyT,
+  },
+  // The past writes of the key from the event log, newest first, at most `limit` of them.
+  History {
+    key: KeyT,
+    limit: Option<usize>,
+  },
+  // The queued commands of a MULTI/EXEC, with the watched keys and their versions at WATCH time.
+  Exec {
+    watches: Vec<(KeyT, Option<u64>)>,
+    commands: Vec<Command>,
+  },
+  // The mutations of a transaction as a single replication event.
+  Batch {
+    commands: Vec<Command>,
+  },
+  // Removes all keys of the namespace.
+  FlushNs,
+  // Removes all keys of all namespaces, whichever namespace it is sent on.
+  FlushAll,
+  // The number of keys in the namespace.
+  DbSize,
+  // The keys of the namespace matching the glob `pattern`, in key order. It stops looking at
+  // `limit` matches or after a fixed number of keys visited, so it cannot hold the storage lock for
+  // long, and tells if it stopped with keys left - SCAN pages through all keys.
+  Keys {
+    pattern: Vec<u8>,
+    limit: usize,
+  },
+  // A command on a namespace other than the default one.
+  Namespaced {
+    namespace: NamespaceT,
+    command: Box<Command>,
+  },
   GetLastReplicationId,
+  // The server's answer to a client's HELLO, which the connection handles.
+  Hello,
   Invalid,
-  Sync { dump: Vec<u8> },
+  Sync {
+    dump: Vec<u8>,
+  },
 }
 
-impl From<Vec<u8>> for Command {
-  fn from(input: Vec<u8>) -> Command {
-    let after_cmd_space_pos = input
+impl Command {
+  pub fn is_mutation(&self) -> bool {
+    matches!(
+      self,
+      Command::Set { .. }
+        | Command::Cas { .. }
+        | Command::SetNx { .. }
+        | Command::SetXx { .. }
+        | Command::GetSet { .. }
+        | Command::Delete { .. }
+        | Command::Rename { .. }
+        | Command::Copy { .. }
+        | Command::Incr { .. }
+        | Command::Decr { .. }
+        | Command::IncrBy { .. }
+        | Command::MSet { .. }
+        | Command::MDel { .. }
+        | Command::Expire { .. }
+        | Command::Persist { .. }
+        | Command::Append { .. }
+        | Command::SetRange { .. }
+        | Command::LPush { .. }
+        | Command::RPush { .. }
+        | Command::LPop { .. }
+        | Command::RPop { .. }
+        | Command::HSet { .. }
+        | Command::HDel { .. }
+        | Command::SAdd { .. }
+        | Command::SRem { .. }
+        | Command::FlushNs
+    )
+  }
+
+  pub fn is_read(&self) -> bool {
+    matches!(
+      self,
+      Command::Get { .. }
+        | Command::GetAt { .. }
+        | Command::MGet { .. }
+        | Command::Scan { .. }
+        | Command::Range { .. }
+        | Command::Ttl { .. }
+        | Command::GetRange { .. }
+        | Command::StrLen { .. }
+        | Command::LRange { .. }
+        | Command::HGet { .. }
+        | Command::HGetAll { .. }
+        | Command::SMembers { .. }
+        | Command::SIsMember { .. }
+        | Command::Stat { .. }
+        | Command::DbSize
+        | Command::Keys { .. }
+    )
+  }
+
+  // Mutations that can grow the storage, so they are refused when it's over its memory limit.
+  // Removals still go through, they are the way out of it.
+  pub fn may_grow(&self) -> bool {
+    matches!(
+      self,
+      Command::Set { .. }
+        | Command::Cas { .. }
+        | Command::SetNx { .. }
+        | Command::SetXx { .. }
+        | Command::GetSet { .. }
+        | Command::Copy { .. }
+        | Command::Incr { .. }
+        | Command::Decr { .. }
+        | Command::IncrBy { .. }
+        | Command::MSet { .. }
+        | Command::Append { .. }
+        | Command::SetRange { .. }
+        | Command::LPush { .. }
+        | Command::RPush { .. }
+        | Command::HSet { .. }
+        | Command::SAdd { .. }
+    )
+  }
+
+  // The keys the command reads or writes, empty for commands not bound to given keys (eg. SCAN).
+  pub fn keys(&self) -> Vec<&KeyT> {
+    match self {
+      Command::Set { key, .. }
+      | Command::Get { key }
+      | Command::GetAt { key, .. }
+      | Command::Cas { key, .. }
+      | Command::SetNx { key, .. }
+      | Command::SetXx { key, .. }
+      | Command::GetSet { key, .. }
+      | Command::Delete { key }
+      | Command::Incr { key }
+      | Command::Decr { key }
+      | Command::IncrBy { key, .. }
+      | Command::Append { key, .. }
+      | Command::GetRange { key, .. }
+      | Command::SetRange { key, .. }
+      | Command::StrLen { key }
+      | Command::LPush { key, .. }
+      | Command::RPush { key, .. }
+      | Command::LPop { key }
+      | Command::RPop { key }
+      | Command::LRange { key, .. }
+      | Command::HSet { key, .. }
+      | Command::HGet { key, .. }
+      | Command::HDel { key, .. }
+      | Command::HGetAll { key }
+      | Command::SAdd { key, .. }
+      | Command::SRem { key, .. }
+      | Command::SMembers { key }
+      | Command::SIsMember { key, .. }
+      | Command::Expire { key, .. }
+      | Command::Ttl { key }
+      | Command::Persist { key }
+      | Command::Stat { key }
+      | Command::Move { key, .. } => vec![key],
+      Command::MGet { keys } | Command::MDel { keys } => keys.iter().collect(),
+      Command::MSet { pairs } => pairs.iter().map(|(key, _)| key).collect(),
+      Command::Rename { src, dst, .. } | Command::Copy { src, dst } => vec![src, dst],
+      _ => vec![],
+    }
+  }
+
+  // Wraps the command so it runs on the given namespace.
+  pub fn in_namespace(self, namespace: &str) -> Command {
+    if namespace == DEFAULT_NAMESPACE {
+      self
+    } else {
+      Command::Namespaced {
+        namespace: namespace.into(),
+        command: Box::new(self),
+      }
+    }
+  }
+
+  // Splits off the namespace the command runs on.
+  pub fn into_namespaced(self) -> (NamespaceT, Command) {
+    match self {
+      Command::Namespaced { namespace, command } => (namespace, *command),
+      command => (DEFAULT_NAMESPACE.into(), command),
+    }
+  }
+}
+
+// Namespaces end up in backup file names, so only a safe subset of characters is allowed.
+pub fn is_valid_namespace(namespace: &[u8]) -> bool {
+  !namespace.is_empty()
+    && namespace
       .iter()
-      .position(|ch| ch == &b' ')
-      .unwrap_or(input.len());
+      .all(|ch| ch.is_ascii_alphanumeric() || ch == &b'_' || ch == &b'-')
+}
 
-    let (cmd, suffix_padded) = input.split_at(after_cmd_space_pos);
+impl From<Vec<u8>> for Command {
+  fn from(bytes: Vec<u8>) -> Command {
+    match Request::try_from(bytes) {
+      Ok(request) => Command::from(request),
+      Err(_) => Command::Invalid,
+    }
+  }
+}
 
-    if cmd == b"SET" {
-      let suffix = &suffix_padded[1..];
-      let after_key_space_pos = suffix.iter().position(|ch| ch == &b' ');
-      if after_key_space_pos.is_none() {
-        return Command::Invalid;
+// Requests with missing or extra fields, or numbers of the wrong size, are invalid. Connection level
+// requests (eg. MULTI) are not commands either, the connection handles them.
+impl From<Request> for Command {
+  fn from(request: Request) -> Command {
+    let mut fields = Fields(request.fields.into_iter());
+    match decode_request(request.opcode, &mut fields) {
+      Some(command) if fields.is_empty() => command,
+      _ => Command::Invalid,
+    }
+  }
+}
+
+fn decode_request(opcode: Opcode, fields: &mut Fields) -> Option<Command> {
+  let command = match opcode {
+    Opcode::Set => Command::Set {
+      key: fields.field()?,
+      value: fields.field()?,
+      expire_at: fields.optional_number()?,
+    },
+    Opcode::SetEx => Command::Set {
+      key: fields.field()?,
+      value: fields.field()?,
+      expire_at: Some(expire_in(fields.number()?)?),
+    },
+    Opcode::Get => {
+      let key = fields.field()?;
+      match fields.optional_number()? {
+        Some(position) => Command::GetAt { key, position },
+        None => Command::Get { key },
+      }
+    }
+    Opcode::Cas => Command::Cas {
+      key: fields.field()?,
+      expected_version: fields.number()?,
+      value: fields.field()?,
+    },
+    Opcode::SetNx => Command::SetNx {
+      key: fields.field()?,
+      value: fields.field()?,
+    },
+    Opcode::SetXx => Command::SetXx {
+      key: fields.field()?,
+      value: fields.field()?,
+    },
+    Opcode::GetSet => Command::GetSet {
+      key: fields.field()?,
+      value: fields.field()?,
+    },
+    Opcode::Delete => Command::Delete {
+      key: fields.field()?,
+    },
+    Opcode::Rename | Opcode::RenameNx => Command::Rename {
+      src: fields.field()?,
+      dst: fields.field()?,
+      nx: opcode == Opcode::RenameNx,
+    },
+    Opcode::Copy => Command::Copy {
+      src: fields.field()?,
+      dst: fields.field()?,
+    },
+    Opcode::Move => {
+      let key = fields.field()?;
+      let namespace = fields.field()?;
+      if !is_valid_namespace(&namespace) {
+        return None;
+      }
+      Command::Move {
+        key,
+        namespace: String::from_utf8(namespace).ok()?,
       }
+    }
+    Opcode::Incr => Command::Incr {
+      key: fields.field()?,
+    },
+    Opcode::Decr => Command::Decr {
+      key: fields.field()?,
+    },
+    Opcode::IncrBy => Command::IncrBy {
+      key: fields.field()?,
+      delta: fields.signed_number()?,
+    },
+    Opcode::MGet => Command::MGet {
+      keys: fields.rest(),
+    },
+    Opcode::MSet => Command::MSet {
+      pairs: fields.rest_pairs()?,
+    },
+    Opcode::MDel => Command::MDel {
+      keys: fields.rest(),
+    },
+    Opcode::Scan => Command::Scan {
+      cursor: decode_scan_cursor(&fields.field()?)?,
+      prefix: fields.field().unwrap_or_default(),
+      count: fields
+        .optional_number()?
+        .map_or(SCAN_DEFAULT_COUNT, |count| {
+          count.min(SCAN_MAX_COUNT as u64) as usize
+        }),
+    },
+    Opcode::Range => Command::Range {
+      start: fields.field()?,
+      end: fields.field()?,
+    },
+    Opcode::Append => Command::Append {
+      key: fields.field()?,
+      value: fields.field()?,
+    },
+    Opcode::GetRange => Command::GetRange {
+      key: fields.field()?,
+      start: fields.signed_number()?,
+      end: fields.signed_number()?,
+    },
+    Opcode::SetRange => Command::SetRange {
+      key: fields.field()?,
+      offset: fields.number()? as usize,
+      value: fields.field()?,
+    },
+    Opcode::StrLen => Command::StrLen {
+      key: fields.field()?,
+    },
+    Opcode::LPush => Command::LPush {
+      key: fields.field()?,
+      elements: fields.rest_non_empty()?,
+    },
+    Opcode::RPush => Command::RPush {
+      key: fields.field()?,
+      elements: fields.rest_non_empty()?,
+    },
+    Opcode::LPop => Command::LPop {
+      key: fields.field()?,
+    },
+    Opcode::RPop => Command::RPop {
+      key: fields.field()?,
+    },
+    Opcode::LRange => Command::LRange {
+      key: fields.field()?,
+      start: fields.signed_number()?,
+      stop: fields.signed_number()?,
+    },
+    Opcode::HSet => Command::HSet {
+      key: fields.field()?,
+      pairs: fields.rest_pairs().filter(|pairs| !pairs.is_empty())?,
+    },
+    Opcode::HGet => Command::HGet {
+      key: fields.field()?,
+      field: fields.field()?,
+    },
+    Opcode::HDel => Command::HDel {
+      key: fields.field()?,
+      fields: fields.rest_non_empty()?,
+    },
+    Opcode::HGetAll => Command::HGetAll {
+      key: fields.field()?,
+    },
+    Opcode::SAdd => Command::SAdd {
+      key: fields.field()?,
+      members: fields.rest_non_empty()?,
+    },
+    Opcode::SRem => Command::SRem {
+      key: fields.field()?,
+      members: fields.rest_non_empty()?,
+    },
+    Opcode::SMembers => Command::SMembers {
+      key: fields.field()?,
+    },
+    Opcode::SIsMember => Command::SIsMember {
+      key: fields.field()?,
+      member: fields.field()?,
+    },
+    Opcode::Expire => Command::Expire {
+      key: fields.field()?,
+      expire_at: expire_in(fields.number()?)?,
+    },
+    Opcode::PExpireAt => Command::Expire {
+      key: fields.field()?,
+      expire_at: fields.number()?,
+    },
+    Opcode::Ttl => Command::Ttl {
+      key: fields.field()?,
+    },
+    Opcode::Persist => Command::Persist {
+      key: fields.field()?,
+    },
+    Opcode::Stat => Command::Stat {
+      key: fields.field()?,
+    },
+    Opcode::History => Command::History {
+      key: fields.field()?,
+      limit: fields.optional_number()?.map(|limit| limit as usize),
+    },
+    Opcode::FlushNs => Command::FlushNs,
+    Opcode::FlushAll => Command::FlushAll,
+    Opcode::DbSize => Command::DbSize,
+    Opcode::Keys => Command::Keys {
+      pattern: fields.field()?,
+      limit: fields
+        .optional_number()?
+        .map_or(KEYS_DEFAULT_LIMIT, |limit| {
+          limit.min(KEYS_MAX_LIMIT as u64) as usize
+        }),
+    },
+    Opcode::Namespaced => {
+      let namespace = fields.field()?;
+      match Command::from(fields.field()?) {
+        // Namespaces do not nest.
+        Command::Namespaced { .. } | Command::Invalid => return None,
+        _ if !is_valid_namespace(&namespace) => return None,
+        command => command.in_namespace(std::str::from_utf8(&namespace).ok()?),
+      }
+    }
+    Opcode::LastReplicationId => Command::GetLastReplicationId,
+    Opcode::Sync => Command::Sync {
+      dump: fields.field()?,
+    },
+    // A batch with any undecodable command is rejected as a whole.
+    Opcode::Batch => Command::Batch {
+      commands: fields
+        .rest()
+        .into_iter()
+        .map(|bytes| Some(Command::from(bytes)).filter(Command::is_mutation))
+        .collect::<Option<Vec<_>>>()?,
+    },
+    Opcode::Hello
+    | Opcode::Multi
+    | Opcode::Exec
+    | Opcode::Discard
+    | Opcode::Watch
+    | Opcode::Select
+    | Opcode::Subscribe
+    | Opcode::Snapshot
+    | Opcode::SnapshotRelease => return None,
+  };
+  Some(command)
+}
+
+// The fields of a request, taken in order.
+struct Fields(std::vec::IntoIter<Vec<u8>>);
+
+impl Fields {
+  fn field(&mut self) -> Option<Vec<u8>> {
+    self.0.next()
+  }
+
+  fn number(&mut self) -> Option<u64> {
+    request::decode_number(&self.0.next()?)
+  }
+
+  fn signed_number(&mut self) -> Option<i64> {
+    request::decode_signed_number(&self.0.next()?)
+  }
+
+  // A trailing number that may be left off: None if it's malformed, Some(None) if it's missing.
+  fn optional_number(&mut self) -> Option<Option<u64>> {
+    match self.0.next() {
+      Some(field) => request::decode_number(&field).map(Some),
+      None => Some(None),
+    }
+  }
+
+  fn rest(&mut self) -> Vec<Vec<u8>> {
+    self.0.by_ref().collect()
+  }
+
+  fn rest_non_empty(&mut self) -> Option<Vec<Vec<u8>>> {
+    Some(self.rest()).filter(|fields| !fields.is_empty())
+  }
 
-      let (key, value_padded) = suffix.split_at(after_key_space_pos.unwrap());
-      let value = &value_padded[1..];
+  fn rest_pairs(&mut self) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let fields = self.rest();
+    if fields.len() % 2 == 1 {
+      return None;
+    }
+
+    let mut fields = fields.into_iter();
+    let mut pairs = vec![];
+    while let (Some(first), Some(second)) = (fields.next(), fields.next()) {
+      pairs.push((first, second));
+    }
+    Some(pairs)
+  }
+
+  fn is_empty(&self) -> bool {
+    self.0.len() == 0
+  }
+}
+
+impl TryFrom<Command> for Request {
+  type Error = ();
+
+  fn try_from(command: Command) -> Result<Request, Self::Error> {
+    let request = match command {
       Command::Set {
-        key: String::from_utf8(key.into()).unwrap(),
-        value: value.into(),
+        key,
+        value,
+        expire_at,
+      } => {
+        let request = Request::new(Opcode::Set).field(key).field(value);
+        match expire_at {
+          Some(expire_at) => request.number(expire_at),
+          None => request,
+        }
       }
-    } else if cmd == b"GET" {
-      let suffix = &suffix_padded[1..];
-      Command::Get {
-        key: String::from_utf8(suffix.into()).unwrap(),
+      Command::Get { key } => Request::new(Opcode::Get).field(key),
+      Command::GetAt { key, position } => Request::new(Opcode::Get).field(key).number(position),
+      Command::Cas {
+        key,
+        expected_version,
+        value,
+      } => Request::new(Opcode::Cas)
+        .field(key)
+        .number(expected_version)
+        .field(value),
+      Command::SetNx { key, value } => Request::new(Opcode::SetNx).field(key).field(value),
+      Command::SetXx { key, value } => Request::new(Opcode::SetXx).field(key).field(value),
+      Command::GetSet { key, value } => Request::new(Opcode::GetSet).field(key).field(value),
+      Command::Delete { key } => Request::new(Opcode::Delete).field(key),
+      Command::Rename { src, dst, nx } => {
+        let opcode = if nx { Opcode::RenameNx } else { Opcode::Rename };
+        Request::new(opcode).field(src).field(dst)
       }
-    } else if cmd == b"DELETE" {
-      let suffix = &suffix_padded[1..];
-      Command::Delete {
-        key: String::from_utf8(suffix.into()).unwrap(),
+      Command::Copy { src, dst } => Request::new(Opcode::Copy).field(src).field(dst),
+      Command::Move { key, namespace } => Request::new(Opcode::Move).field(key).field(namespace),
+      Command::Incr { key } => Request::new(Opcode::Incr).field(key),
+      Command::Decr { key } => Request::new(Opcode::Decr).field(key),
+      Command::IncrBy { key, delta } => {
+        Request::new(Opcode::IncrBy).field(key).signed_number(delta)
       }
-    } else if cmd == b"LAST_REPLICATION_ID" {
-      Command::GetLastReplicationId
-    } else if cmd == b"SYNC" {
-      let suffix = &suffix_padded[1..];
-      Command::Sync {
-        dump: suffix.into(),
+      Command::MGet { keys } => Request::new(Opcode::MGet).fields(&keys),
+      Command::MSet { pairs } => Request::new(Opcode::MSet).fields(&flatten_pairs(pairs)),
+      Command::MDel { keys } => Request::new(Opcode::MDel).fields(&keys),
+      Command::Scan {
+        cursor,
+        prefix,
+        count,
+      } => Request::new(Opcode::Scan)
+        .field(encode_scan_cursor(cursor))
+        .field(prefix)
+        .number(count as u64),
+      Command::Range { start, end } => Request::new(Opcode::Range).field(start).field(end),
+      Command::Append { key, value } => Request::new(Opcode::Append).field(key).field(value),
+      Command::GetRange { key, start, end } => Request::new(Opcode::GetRange)
+        .field(key)
+        .signed_number(start)
+        .signed_number(end),
+      Command::SetRange { key, offset, value } => Request::new(Opcode::SetRange)
+        .field(key)
+        .number(offset as u64)
+        .field(value),
+      Command::StrLen { key } => Request::new(Opcode::StrLen).field(key),
+      Command::LPush { key, elements } => Request::new(Opcode::LPush).field(key).fields(&elements),
+      Command::RPush { key, elements } => Request::new(Opcode::RPush).field(key).fields(&elements),
+      Command::LPop { key } => Request::new(Opcode::LPop).field(key),
+      Command::RPop { key } => Request::new(Opcode::RPop).field(key),
+      Command::LRange { key, start, stop } => Request::new(Opcode::LRange)
+        .field(key)
+        .signed_number(start)
+        .signed_number(stop),
+      Command::HSet { key, pairs } => Request::new(Opcode::HSet)
+        .field(key)
+        .fields(&flatten_pairs(pairs)),
+      Command::HGet { key, field } => Request::new(Opcode::HGet).field(key).field(field),
+      Command::HDel { key, fields } => Request::new(Opcode::HDel).field(key).fields(&fields),
+      Command::HGetAll { key } => Request::new(Opcode::HGetAll).field(key),
+      Command::SAdd { key, members } => Request::new(Opcode::SAdd).field(key).fields(&members),
+      Command::SRem { key, members } => Request::new(Opcode::SRem).field(key).fields(&members),
+      Command::SMembers { key } => Request::new(Opcode::SMembers).field(key),
+      Command::SIsMember { key, member } => {
+        Request::new(Opcode::SIsMember).field(key).field(member)
       }
-    } else {
-      Command::Invalid
-    }
+      Command::Expire { key, expire_at } => {
+        Request::new(Opcode::PExpireAt).field(key).number(expire_at)
+      }
+      Command::Ttl { key } => Request::new(Opcode::Ttl).field(key),
+      Command::Persist { key } => Request::new(Opcode::Persist).field(key),
+      Command::Stat { key } => Request::new(Opcode::Stat).field(key),
+      Command::History { key, limit } => {
+        let request = Request::new(Opcode::History).field(key);
+        match limit {
+          Some(limit) => request.number(limit as u64),
+          None => request,
+        }
+      }
+      Command::Batch { commands } => Request::new(Opcode::Batch).fields(
+        &commands
+          .into_iter()
+          .map(|cmd| cmd.try_into())
+          .collect::<Result<Vec<Vec<u8>>, ()>>()?,
+      ),
+      Command::FlushNs => Request::new(Opcode::FlushNs),
+      Command::FlushAll => Request::new(Opcode::FlushAll),
+      Command::DbSize => Request::new(Opcode::DbSize),
+      Command::Keys { pattern, limit } => Request::new(Opcode::Keys)
+        .field(pattern)
+        .number(limit as u64),
+      Command::Namespaced { namespace, command } => {
+        let command: Vec<u8> = (*command).try_into()?;
+        Request::new(Opcode::Namespaced)
+          .field(namespace)
+          .field(command)
+      }
+      Command::GetLastReplicationId => Request::new(Opcode::LastReplicationId),
+      Command::Sync { dump } => Request::new(Opcode::Sync).field(dump),
+      // The connection builds these, they are never sent.
+      Command::Exec { .. } | Command::Hello | Command::Invalid => return Err(()),
+    };
+    Ok(request)
   }
 }
 
@@ -59,26 +791,308 @@ impl TryInto<Vec<u8>> for Command {
   type Error = ();
 
   fn try_into(self) -> Result<Vec<u8>, Self::Error> {
-    let mut bytes: Vec<u8> = vec![];
+    Request::try_from(self).map(Vec::from)
+  }
+}
 
-    match self {
-      Command::Set { key, mut value } => {
-        bytes.append(&mut Vec::from(&b"SET "[..]));
-        bytes.append(&mut Vec::from(&key[..]));
-        bytes.push(b' ');
-        bytes.append(&mut value);
-      }
-      Command::Delete { key } => {
-        bytes.append(&mut Vec::from(&b"DELETE "[..]));
-        bytes.append(&mut Vec::from(&key[..]));
-      }
-      Command::Get { key } => {
-        bytes.append(&mut Vec::from(&b"GET "[..]));
-        bytes.append(&mut Vec::from(&key[..]));
-      }
-      Command::Invalid | Command::GetLastReplicationId | Command::Sync { .. } => return Err(()),
+fn flatten_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<Vec<u8>> {
+  pairs
+    .into_iter()
+    .flat_map(|(first, second)| vec![first, second])
+    .collect()
+}
+
+// The unix ms deadline `seconds` from now, None if it's beyond what the clock can hold.
+pub fn expire_in(seconds: u64) -> Option<u64> {
+  seconds.checked_mul(1000)?.checked_add(now_ms())
+}
+
+pub const SCAN_DEFAULT_COUNT: usize = 10;
+// Larger counts are lowered to it, a page is built under the storage lock.
+pub const SCAN_MAX_COUNT: usize = 1000;
+pub const KEYS_DEFAULT_LIMIT: usize = 1000;
+// Larger limits are lowered to it, the matches are collected under the storage lock.
+pub const KEYS_MAX_LIMIT: usize = 10_000;
+
+// Scan cursors are the hex encoded last key of the previous page, so they are safe to send as text
+// whatever bytes the key has. "0" starts a new scan and marks the end of one (hex is even length).
+pub fn encode_scan_cursor(key: Option<KeyT>) -> String {
+  match key {
+    Some(key) => key.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    None => "0".into(),
+  }
+}
+
+pub fn decode_scan_cursor(cursor: &[u8]) -> Option<Option<KeyT>> {
+  if cursor == b"0" {
+    return Some(None);
+  }
+  if cursor.len() % 2 == 1 {
+    return None;
+  }
+
+  cursor
+    .chunks(2)
+    .map(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+    .collect::<Option<KeyT>>()
+    .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(command: Command) -> Vec<u8> {
+    command.try_into().unwrap()
+  }
+
+  // One command for each opcode a command is sent with.
+  fn commands() -> Vec<Command> {
+    let set = || Command::Set {
+      key: "k".into(),
+      value: vec![1],
+      expire_at: None,
+    };
+    vec![
+      set(),
+      Command::Set {
+        key: "k k".into(),
+        value: vec![],
+        expire_at: Some(42),
+      },
+      Command::Get { key: vec![0, 255] },
+      Command::GetAt {
+        key: "k".into(),
+        position: 3,
+      },
+      Command::Cas {
+        key: "k".into(),
+        expected_version: 2,
+        value: vec![1],
+      },
+      Command::SetNx {
+        key: "k".into(),
+        value: vec![1],
+      },
+      Command::SetXx {
+        key: "k".into(),
+        value: vec![1],
+      },
+      Command::GetSet {
+        key: "k".into(),
+        value: vec![1],
+      },
+      Command::Delete { key: "k".into() },
+      Command::Rename {
+        src: "a".into(),
+        dst: "b".into(),
+        nx: false,
+      },
+      Command::Rename {
+        src: "a".into(),
+        dst: "b".into(),
+        nx: true,
+      },
+      Command::Copy {
+        src: "a".into(),
+        dst: "b".into(),
+      },
+      Command::Move {
+        key: "k".into(),
+        namespace: "other".into(),
+      },
+      Command::Incr { key: "k".into() },
+      Command::Decr { key: "k".into() },
+      Command::IncrBy {
+        key: "k".into(),
+        delta: -7,
+      },
+      Command::MGet {
+        keys: vec!["a".into(), "b".into()],
+      },
+      Command::MSet {
+        pairs: vec![("a".into(), vec![1]), ("b".into(), vec![])],
+      },
+      Command::MDel {
+        keys: vec!["a".into()],
+      },
+      Command::Scan {
+        cursor: Some("k".into()),
+        prefix: "p".into(),
+        count: 5,
+      },
+      Command::Range {
+        start: "a".into(),
+        end: "z".into(),
+      },
+      Command::Append {
+        key: "k".into(),
+        value: vec![1],
+      },
+      Command::GetRange {
+        key: "k".into(),
+        start: 1,
+        end: -1,
+      },
+      Command::SetRange {
+        key: "k".into(),
+        offset: 3,
+        value: vec![1],
+      },
+      Command::StrLen { key: "k".into() },
+      Command::LPush {
+        key: "k".into(),
+        elements: vec![vec![1], vec![2]],
+      },
+      Command::RPush {
+        key: "k".into(),
+        elements: vec![vec![1]],
+      },
+      Command::LPop { key: "k".into() },
+      Command::RPop { key: "k".into() },
+      Command::LRange {
+        key: "k".into(),
+        start: 0,
+        stop: -1,
+      },
+      Command::HSet {
+        key: "k".into(),
+        pairs: vec![(vec![1], vec![2])],
+      },
+      Command::HGet {
+        key: "k".into(),
+        field: vec![1],
+      },
+      Command::HDel {
+        key: "k".into(),
+        fields: vec![vec![1]],
+      },
+      Command::HGetAll { key: "k".into() },
+      Command::SAdd {
+        key: "k".into(),
+        members: vec![vec![1]],
+      },
+      Command::SRem {
+        key: "k".into(),
+        members: vec![vec![1]],
+      },
+      Command::SMembers { key: "k".into() },
+      Command::SIsMember {
+        key: "k".into(),
+        member: vec![1],
+      },
+      Command::Expire {
+        key: "k".into(),
+        expire_at: 42,
+      },
+      Command::Ttl { key: "k".into() },
+      Command::Persist { key: "k".into() },
+      Command::Stat { key: "k".into() },
+      Command::History {
+        key: "k".into(),
+        limit: Some(3),
+      },
+      Command::History {
+        key: "k".into(),
+        limit: None,
+      },
+      Command::Batch {
+        commands: vec![set(), Command::Delete { key: "k".into() }],
+      },
+      Command::FlushNs,
+      Command::FlushAll,
+      Command::DbSize,
+      Command::Keys {
+        pattern: "k*".into(),
+        limit: 5,
+      },
+      set().in_namespace("other"),
+      Command::GetLastReplicationId,
+      Command::Sync { dump: vec![1, 2] },
+    ]
+  }
+
+  #[test]
+  fn commands_round_trip() {
+    let mut opcodes = vec![];
+    for command in commands() {
+      let bytes = encode(command.clone());
+      opcodes.push(Request::try_from(bytes.clone()).unwrap().opcode);
+
+      let decoded = Command::from(bytes.clone());
+      assert!(!matches!(decoded, Command::Invalid), "{:?}", bytes);
+      assert_eq!(bytes, encode(decoded));
+    }
+
+    // The rest are relative expiries (decoded to absolute deadlines) or connection level requests.
+    let not_sent = [
+      Opcode::SetEx,
+      Opcode::Expire,
+      Opcode::Hello,
+      Opcode::Multi,
+      Opcode::Exec,
+      Opcode::Discard,
+      Opcode::Watch,
+      Opcode::Select,
+      Opcode::Subscribe,
+      Opcode::Snapshot,
+      Opcode::SnapshotRelease,
+    ];
+    for opcode in (0..=u8::MAX).filter_map(|byte| Opcode::try_from(byte).ok()) {
+      assert!(
+        opcodes.contains(&opcode) || not_sent.contains(&opcode),
+        "{:?}",
+        opcode
+      );
+    }
+  }
+
+  #[test]
+  fn relative_expiries_decode_to_deadlines() {
+    let request = Request::new(Opcode::SetEx).field("k").field([1]).number(10);
+    assert!(matches!(
+      Command::from(request),
+      Command::Set { expire_at: Some(expire_at), .. } if expire_at > now_ms()
+    ));
+
+    let request = Request::new(Opcode::Expire).field("k").number(10);
+    assert!(matches!(
+      Command::from(request),
+      Command::Expire { expire_at, .. } if expire_at > now_ms()
+    ));
+  }
+
+  #[test]
+  fn connection_requests_are_not_commands() {
+    for opcode in [Opcode::Hello, Opcode::Multi, Opcode::Watch, Opcode::Select] {
+      assert!(matches!(
+        Command::from(Request::new(opcode)),
+        Command::Invalid
+      ));
     }
+  }
 
-    Ok(bytes)
+  #[test]
+  fn malformed_requests_are_invalid() {
+    let requests = vec![
+      // Missing field.
+      Request::new(Opcode::Set).field("k"),
+      // Extra field.
+      Request::new(Opcode::Delete).field("k").field("k"),
+      // Number of the wrong size.
+      Request::new(Opcode::Incr).field("k").field([1]),
+      Request::new(Opcode::IncrBy).field("k").field([1]),
+      // Odd pairs.
+      Request::new(Opcode::MSet).fields(&["k"]),
+      Request::new(Opcode::LPush).field("k"),
+      Request::new(Opcode::Move).field("k").field("no space"),
+      Request::new(Opcode::Batch).field(encode(Command::Get { key: "k".into() })),
+      Request::new(Opcode::Namespaced)
+        .field("other")
+        .field(encode(Command::FlushNs.in_namespace("nested"))),
+    ];
+    for request in requests {
+      assert!(matches!(Command::from(request), Command::Invalid));
+    }
   }
 }
@@ -0,0 +1,337 @@
+use crate::storage::KeyT;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::hash::Hasher;
+use std::iter::Peekable;
+use std::ops::Bound;
+
+const SHARD_COUNT: usize = 16;
+
+pub type KeysIter<'a> = Box<dyn Iterator<Item = &'a KeyT> + 'a>;
+
+// Where a keyspace keeps its entries. Engines only store the entries and keep their keys in order
+// for scans - value types, expiries and memory accounting are up to the keyspace on top.
+pub trait StorageEngine<V>: Send + Sync {
+  fn get(&self, key: &KeyT) -> Option<&V>;
+  fn get_mut(&mut self, key: &KeyT) -> Option<&mut V>;
+  // Returns the replaced value, if any.
+  fn set(&mut self, key: KeyT, value: V) -> Option<V>;
+  fn delete(&mut self, key: &KeyT) -> Option<V>;
+  // Keys between the bounds, in order.
+  fn scan<'a>(&'a self, start: Bound<&KeyT>, end: Bound<&KeyT>) -> KeysIter<'a>;
+  fn len(&self) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+  // Hash map lookups, plus an ordered index of the keys for scans.
+  HashMap,
+  // An ordered map. Lookups are slower, but there is no separate index to keep.
+  BTreeMap,
+  // Keys spread over hash map shards by hash, so each shard stays small and grows (rehashes) on
+  // its own.
+  Sharded,
+}
+
+impl TryFrom<&str> for EngineKind {
+  type Error = ();
+
+  fn try_from(s: &str) -> Result<Self, Self::Error> {
+    match s {
+      "hashmap" => Ok(EngineKind::HashMap),
+      "btreemap" => Ok(EngineKind::BTreeMap),
+      "sharded" => Ok(EngineKind::Sharded),
+      _ => Err(()),
+    }
+  }
+}
+
+pub struct HashMapEngine<V> {
+  data: HashMap<KeyT, V>,
+  index: BTreeSet<KeyT>,
+}
+
+impl<V> Default for HashMapEngine<V> {
+  fn default() -> Self {
+    HashMapEngine {
+      data: Default::default(),
+      index: Default::default(),
+    }
+  }
+}
+
+impl<V: Send + Sync> StorageEngine<V> for HashMapEngine<V> {
+  fn get(&self, key: &KeyT) -> Option<&V> {
+    self.data.get(key)
+  }
+
+  fn get_mut(&mut self, key: &KeyT) -> Option<&mut V> {
+    self.data.get_mut(key)
+  }
+
+  fn set(&mut self, key: KeyT, value: V) -> Option<V> {
+    if !self.data.contains_key(&key) {
+      self.index.insert(key.clone());
+    }
+    self.data.insert(key, value)
+  }
+
+  fn delete(&mut self, key: &KeyT) -> Option<V> {
+    self.index.remove(key);
+    self.data.remove(key)
+  }
+
+  fn scan<'a>(&'a self, start: Bound<&KeyT>, end: Bound<&KeyT>) -> KeysIter<'a> {
+    Box::new(self.index.range::<KeyT, _>((start, end)))
+  }
+
+  fn len(&self) -> usize {
+    self.data.len()
+  }
+}
+
+pub struct BTreeMapEngine<V> {
+  data: BTreeMap<KeyT, V>,
+}
+
+impl<V> Default for BTreeMapEngine<V> {
+  fn default() -> Self {
+    BTreeMapEngine {
+      data: Default::default(),
+    }
+  }
+}
+
+impl<V: Send + Sync> StorageEngine<V> for BTreeMapEngine<V> {
+  fn get(&self, key: &KeyT) -> Option<&V> {
+    self.data.get(key)
+  }
+
+  fn get_mut(&mut self, key: &KeyT) -> Option<&mut V> {
+    self.data.get_mut(key)
+  }
+
+  fn set(&mut self, key: KeyT, value: V) -> Option<V> {
+    self.data.insert(key, value)
+  }
+
+  fn delete(&mut self, key: &KeyT) -> Option<V> {
+    self.data.remove(key)
+  }
+
+  fn scan<'a>(&'a self, start: Bound<&KeyT>, end: Bound<&KeyT>) -> KeysIter<'a> {
+    Box::new(self.data.range::<KeyT, _>((start, end)).map(|(key, _)| key))
+  }
+
+  fn len(&self) -> usize {
+    self.data.len()
+  }
+}
+
+// Writes are applied one by one by the app under the storage lock, so shards do not let writes run
+// in parallel. What they bound is the pause of a rehash: a shard grows on its own, so a write never
+// rehashes the whole keyspace while holding the lock that all reads wait for.
+// IDEA: Shards could each get their own lock, so reads only wait for writes to their shard. The
+//        keyspace state next to the engine (expiries, eviction ranks) would need its own locks too.
+pub struct ShardedEngine<V> {
+  shards: Vec<HashMapEngine<V>>,
+}
+
+impl<V> Default for ShardedEngine<V> {
+  fn default() -> Self {
+    ShardedEngine::new(SHARD_COUNT)
+  }
+}
+
+impl<V> ShardedEngine<V> {
+  pub fn new(shard_count: usize) -> Self {
+    ShardedEngine {
+      shards: (0..shard_count).map(|_| HashMapEngine::default()).collect(),
+    }
+  }
+
+  fn shard_index(&self, key: &KeyT) -> usize {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    (hasher.finish() % self.shards.len() as u64) as usize
+  }
+}
+
+impl<V: Send + Sync> StorageEngine<V> for ShardedEngine<V> {
+  fn get(&self, key: &KeyT) -> Option<&V> {
+    self.shards[self.shard_index(key)].get(key)
+  }
+
+  fn get_mut(&mut self, key: &KeyT) -> Option<&mut V> {
+    let shard_index = self.shard_index(key);
+    self.shards[shard_index].get_mut(key)
+  }
+
+  fn set(&mut self, key: KeyT, value: V) -> Option<V> {
+    let shard_index = self.shard_index(&key);
+    self.shards[shard_index].set(key, value)
+  }
+
+  fn delete(&mut self, key: &KeyT) -> Option<V> {
+    let shard_index = self.shard_index(key);
+    self.shards[shard_index].delete(key)
+  }
+
+  // Keys are ordered within the shards only, so the shards' keys are merged.
+  fn scan<'a>(&'a self, start: Bound<&KeyT>, end: Bound<&KeyT>) -> KeysIter<'a> {
+    Box::new(MergedKeys {
+      shards: self
+        .shards
+        .iter()
+        .map(|shard| shard.scan(start, end).peekable())
+        .collect(),
+    })
+  }
+
+  fn len(&self) -> usize {
+    self.shards.iter().map(|shard| shard.len()).sum()
+  }
+}
+
+// Merges ordered key iterators into one ordered iterator.
+struct MergedKeys<'a> {
+  shards: Vec<Peekable<KeysIter<'a>>>,
+}
+
+impl<'a> Iterator for MergedKeys<'a> {
+  type Item = &'a KeyT;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let mut next_shard: Option<(usize, &'a KeyT)> = None;
+    for (shard_index, keys) in self.shards.iter_mut().enumerate() {
+      if let Some(&key) = keys.peek() {
+        if next_shard.is_none_or(|(_, next_key)| key < next_key) {
+          next_shard = Some((shard_index, key));
+        }
+      }
+    }
+
+    let (shard_index, _) = next_shard?;
+    self.shards[shard_index].next()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key(key: &str) -> KeyT {
+    key.as_bytes().to_vec()
+  }
+
+  fn scanned<E: StorageEngine<u8>>(
+    engine: &E,
+    start: Bound<&KeyT>,
+    end: Bound<&KeyT>,
+  ) -> Vec<KeyT> {
+    engine.scan(start, end).cloned().collect()
+  }
+
+  fn check_engine<E: StorageEngine<u8>>(mut engine: E) {
+    assert_eq!(None, engine.set(key("b"), 2));
+    assert_eq!(None, engine.set(key("a"), 1));
+    assert_eq!(None, engine.set(key("c"), 3));
+    assert_eq!(Some(2), engine.set(key("b"), 20));
+    assert_eq!(3, engine.len());
+
+    assert_eq!(Some(&20), engine.get(&key("b")));
+    *engine.get_mut(&key("a")).unwrap() = 10;
+    assert_eq!(Some(&10), engine.get(&key("a")));
+    assert_eq!(None, engine.get(&key("d")));
+
+    assert_eq!(
+      vec![key("a"), key("b"), key("c")],
+      scanned(&engine, Bound::Unbounded, Bound::Unbounded)
+    );
+    assert_eq!(
+      vec![key("b")],
+      scanned(
+        &engine,
+        Bound::Excluded(&key("a")),
+        Bound::Excluded(&key("c"))
+      )
+    );
+
+    assert_eq!(Some(20), engine.delete(&key("b")));
+    assert_eq!(None, engine.delete(&key("b")));
+    assert_eq!(2, engine.len());
+    assert_eq!(
+      vec![key("a"), key("c")],
+      scanned(&engine, Bound::Unbounded, Bound::Unbounded)
+    );
+  }
+
+  #[test]
+  fn hash_map_engine_works() {
+    check_engine(HashMapEngine::default());
+  }
+
+  #[test]
+  fn btree_map_engine_works() {
+    check_engine(BTreeMapEngine::default());
+  }
+
+  #[test]
+  fn sharded_engine_works() {
+    check_engine(ShardedEngine::default());
+    check_engine(ShardedEngine::new(1));
+  }
+
+  #[test]
+  fn sharded_engine_scans_in_order() {
+    let mut engine = ShardedEngine::new(7);
+    let mut keys: Vec<KeyT> = (0..500u32)
+      .map(|i| format!("key:{}", i * 7919 % 500).into_bytes())
+      .collect();
+    for (i, key) in keys.iter().enumerate() {
+      engine.set(key.clone(), i as u8);
+    }
+    keys.sort();
+
+    assert_eq!(keys, scanned(&engine, Bound::Unbounded, Bound::Unbounded));
+    assert_eq!(
+      keys[100..=200].to_vec(),
+      scanned(
+        &engine,
+        Bound::Included(&keys[100]),
+        Bound::Included(&keys[200])
+      )
+    );
+    assert!(scanned(&engine, Bound::Excluded(&keys[499]), Bound::Unbounded).is_empty());
+  }
+
+  #[test]
+  fn merged_keys_merges_in_order() {
+    let shards: Vec<Vec<KeyT>> = vec![
+      vec![key("a"), key("d"), key("e")],
+      vec![],
+      vec![key("b"), key("c"), key("f")],
+      vec![key("0")],
+    ];
+    let merged = MergedKeys {
+      shards: shards
+        .iter()
+        .map(|keys| (Box::new(keys.iter()) as KeysIter).peekable())
+        .collect(),
+    };
+
+    assert_eq!(
+      vec![
+        key("0"),
+        key("a"),
+        key("b"),
+        key("c"),
+        key("d"),
+        key("e"),
+        key("f")
+      ],
+      merged.cloned().collect::<Vec<_>>()
+    );
+  }
+}
@@ -0,0 +1,104 @@
+use std::convert::TryFrom;
+
+// How keys are chosen for eviction once the storage grows over its memory limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+  // Writes that could grow the storage fail instead.
+  NoEviction,
+  // The least recently used key of any namespace.
+  AllKeysLru,
+  // The least frequently used key of any namespace, the least recently used one on a tie.
+  AllKeysLfu,
+  // The key with the nearest expiry. Keys without an expiry are never evicted.
+  VolatileTtl,
+}
+
+// Order of eviction - keys with the smallest rank go first.
+pub type EvictionRankT = (u64, u64);
+
+impl EvictionPolicy {
+  // The rank of a key with the given access stats, None if the policy does not evict by access
+  // (so there is no need to track it).
+  pub fn rank(&self, last_access: u64, hits: u64) -> Option<EvictionRankT> {
+    match self {
+      EvictionPolicy::AllKeysLru => Some((last_access, 0)),
+      // IDEA: Hits never decay, so keys popular long ago are kept over recently popular ones.
+      EvictionPolicy::AllKeysLfu => Some((hits, last_access)),
+      EvictionPolicy::NoEviction | EvictionPolicy::VolatileTtl => None,
+    }
+  }
+
+  pub fn evicts_by_access(&self) -> bool {
+    matches!(
+      self,
+      EvictionPolicy::AllKeysLru | EvictionPolicy::AllKeysLfu
+    )
+  }
+}
+
+impl TryFrom<&str> for EvictionPolicy {
+  type Error = ();
+
+  fn try_from(s: &str) -> Result<Self, Self::Error> {
+    match s {
+      "noeviction" => Ok(EvictionPolicy::NoEviction),
+      "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+      "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+      "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+      _ => Err(()),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryLimit {
+  pub max_bytes: usize,
+  pub policy: EvictionPolicy,
+}
+
+impl MemoryLimit {
+  pub fn new(max_bytes: usize, policy: EvictionPolicy) -> Self {
+    MemoryLimit { max_bytes, policy }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lru_ranks_by_last_access() {
+    let policy = EvictionPolicy::AllKeysLru;
+    assert!(policy.rank(1, 100) < policy.rank(2, 0));
+  }
+
+  #[test]
+  fn lfu_ranks_by_hits_then_last_access() {
+    let policy = EvictionPolicy::AllKeysLfu;
+    assert!(policy.rank(2, 0) < policy.rank(1, 100));
+    assert!(policy.rank(1, 5) < policy.rank(2, 5));
+  }
+
+  #[test]
+  fn only_access_policies_rank() {
+    for policy in [EvictionPolicy::NoEviction, EvictionPolicy::VolatileTtl] {
+      assert!(!policy.evicts_by_access());
+      assert_eq!(None, policy.rank(1, 1));
+    }
+    assert!(EvictionPolicy::AllKeysLru.evicts_by_access());
+    assert!(EvictionPolicy::AllKeysLfu.evicts_by_access());
+  }
+
+  #[test]
+  fn policies_parse() {
+    assert_eq!(
+      Ok(EvictionPolicy::AllKeysLfu),
+      EvictionPolicy::try_from("allkeys-lfu")
+    );
+    assert_eq!(
+      Ok(EvictionPolicy::VolatileTtl),
+      EvictionPolicy::try_from("volatile-ttl")
+    );
+    assert_eq!(Err(()), EvictionPolicy::try_from("allkeys-random"));
+  }
+}
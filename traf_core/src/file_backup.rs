@@ -1,7 +1,12 @@
 use crate::command::Command;
-use crate::storage::Storage;
+use crate::engine::BTreeMapEngine;
+use crate::storage::{
+  now_ms, Entry, KeyT, Keyspace, KeyspaceEngine, NamespaceT, Storage, Value, VersionT,
+  DEFAULT_NAMESPACE,
+};
+use crate::Executor;
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::fs::{self, OpenOptions};
@@ -9,7 +14,8 @@ use std::hash::Hasher;
 use std::io::prelude::*;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use traf_lib::length_prefixed;
 
 // IDEA: Sharding:
 //        - eg when the values file reaches a certain size: half it
@@ -25,6 +31,7 @@ use std::sync::{Arc, Mutex};
 
 const SHARD_BREAK_LIMIT: usize = 1024;
 const CHANGELOG_TRESHOLD_TO_INIT_BACKUP: usize = 1;
+const SHARD_REGISTRY_NAMESPACE_PREFIX: &str = "__traf_shards_ns_";
 
 fn generate_random_name() -> String {
   let mut rng = rand::thread_rng();
@@ -36,9 +43,9 @@ fn generate_random_name() -> String {
   String::from_utf8(bytes).expect("Failed random string generation")
 }
 
-fn hash_for_key(key: &String) -> u64 {
+fn hash_for_key(key: &KeyT) -> u64 {
   let mut hasher = DefaultHasher::new();
-  hasher.write(key.as_bytes());
+  hasher.write(key);
   hasher.finish()
 }
 
@@ -49,20 +56,274 @@ fn append_bytes_with_same_size_padding(v: &mut Vec<u8>, bytes: &[u8]) {
   v.append(&mut padding);
 }
 
+// Writes `bytes` from `offset` (from the end if None), zero padding a gap before offset.
+fn write_at(value: &mut Vec<u8>, offset: Option<usize>, bytes: &[u8]) {
+  let offset = offset.unwrap_or(value.len());
+  let end = offset + bytes.len();
+  if value.len() < end {
+    value.resize(end, 0);
+  }
+  value[offset..end].copy_from_slice(bytes);
+}
+
+// A byte-range write (APPEND if offset is None) of a value that is not in the changeset.
+struct Patch {
+  offset: Option<usize>,
+  bytes: Vec<u8>,
+}
+
 #[derive(Default)]
 struct Changeset {
-  updates: HashMap<String, Vec<u8>>,
-  removals: HashSet<String>,
+  updates: HashMap<KeyT, Vec<u8>>,
+  patches: HashMap<KeyT, Vec<Patch>>,
+  // Collection operations, applied to the stored collection on backup.
+  typed_ops: HashMap<KeyT, Vec<Command>>,
+  removals: HashSet<KeyT>,
+  // Expiry changes of keys. None means the key does not expire (anymore).
+  expirations: HashMap<KeyT, Option<u64>>,
+  // The version and the time (unix ms) of the last change of keys.
+  versions: HashMap<KeyT, (VersionT, u64)>,
 }
 
 #[derive(Default)]
 struct ChangesetCollection(HashMap<String, Changeset>);
 
+impl ChangesetCollection {
+  fn for_key(&mut self, shard_registry: &ShardRegistry, key: &KeyT) -> &mut Changeset {
+    let filehash = shard_registry.filehash_for_key(key);
+    self.0.entry(filehash).or_default()
+  }
+
+  fn set(
+    &mut self,
+    shard_registry: &ShardRegistry,
+    key: &KeyT,
+    value: &[u8],
+    expire_at: Option<u64>,
+    version: VersionT,
+  ) {
+    let changeset = self.for_key(shard_registry, key);
+
+    changeset.updates.insert(key.clone(), value.to_vec());
+    changeset.patches.remove(key);
+    changeset.typed_ops.remove(key);
+    changeset.expirations.insert(key.clone(), expire_at);
+    changeset.versions.insert(key.clone(), (version, now_ms()));
+  }
+
+  fn patch(
+    &mut self,
+    shard_registry: &ShardRegistry,
+    key: &KeyT,
+    offset: Option<usize>,
+    bytes: &[u8],
+    version: VersionT,
+  ) {
+    let changeset = self.for_key(shard_registry, key);
+
+    // A full value is already waiting for the backup, it's cheaper to just patch that.
+    match changeset.updates.get_mut(key) {
+      Some(value) => write_at(value, offset, bytes),
+      None => changeset
+        .patches
+        .entry(key.clone())
+        .or_default()
+        .push(Patch {
+          offset,
+          bytes: bytes.to_vec(),
+        }),
+    };
+    changeset.versions.insert(key.clone(), (version, now_ms()));
+  }
+
+  fn log(&mut self, shard_registry: &ShardRegistry, cmd: &Command, version: VersionT) {
+    match cmd {
+      Command::Delete { key } => {
+        self.delete(shard_registry, key);
+      }
+      Command::Set {
+        key,
+        value,
+        expire_at,
+      } => {
+        self.set(shard_registry, key, value, *expire_at, version);
+      }
+      Command::MSet { pairs } => {
+        for (key, value) in pairs {
+          self.set(shard_registry, key, value, None, version);
+        }
+      }
+      Command::MDel { keys } => {
+        for key in keys {
+          self.delete(shard_registry, key);
+        }
+      }
+      Command::Append { key, value } => {
+        self.patch(shard_registry, key, None, value, version);
+      }
+      Command::SetRange { key, offset, value } => {
+        self.patch(shard_registry, key, Some(*offset), value, version);
+      }
+      Command::LPush { key, .. }
+      | Command::RPush { key, .. }
+      | Command::LPop { key }
+      | Command::RPop { key }
+      | Command::HSet { key, .. }
+      | Command::HDel { key, .. }
+      | Command::SAdd { key, .. }
+      | Command::SRem { key, .. } => {
+        let changeset = self.for_key(shard_registry, key);
+
+        changeset
+          .typed_ops
+          .entry(key.clone())
+          .or_default()
+          .push(cmd.clone());
+        changeset.versions.insert(key.clone(), (version, now_ms()));
+      }
+      Command::Expire { key, expire_at } => {
+        let changeset = self.for_key(shard_registry, key);
+
+        changeset.expirations.insert(key.clone(), Some(*expire_at));
+      }
+      Command::Persist { key } => {
+        let changeset = self.for_key(shard_registry, key);
+
+        changeset.expirations.insert(key.clone(), None);
+      }
+      Command::Batch { commands } => {
+        for cmd in commands {
+          self.log(shard_registry, cmd, version);
+        }
+      }
+      // Readers back up the commands of a sync one by one, as they apply them.
+      Command::Sync { .. } => error!("Skipped backing up a sync, only its commands are backed up"),
+      // The app logs the effective commands only: counters and conditional sets as SET,
+      // transactions as BATCH. Namespaces, flushes and commands moving keys between shards are
+      // resolved by `FileBackup::log` before they get here.
+      Command::Incr { .. }
+      | Command::Decr { .. }
+      | Command::IncrBy { .. }
+      | Command::Cas { .. }
+      | Command::SetNx { .. }
+      | Command::SetXx { .. }
+      | Command::GetSet { .. }
+      | Command::Exec { .. }
+      | Command::FlushNs
+      | Command::FlushAll
+      | Command::Namespaced { .. }
+      | Command::Rename { .. }
+      | Command::Copy { .. }
+      | Command::Move { .. } => error!(
+        "Skipped backing up a non-effective command on keys {:?}",
+        cmd.keys()
+      ),
+      Command::Get { .. }
+      | Command::GetAt { .. }
+      | Command::MGet { .. }
+      | Command::Scan { .. }
+      | Command::Range { .. }
+      | Command::Ttl { .. }
+      | Command::GetRange { .. }
+      | Command::StrLen { .. }
+      | Command::LRange { .. }
+      | Command::HGet { .. }
+      | Command::HGetAll { .. }
+      | Command::SMembers { .. }
+      | Command::SIsMember { .. }
+      | Command::Stat { .. }
+      | Command::History { .. }
+      | Command::DbSize
+      | Command::Keys { .. }
+      | Command::GetLastReplicationId
+      | Command::Hello
+      | Command::Invalid => (),
+    }
+  }
+
+  fn delete(&mut self, shard_registry: &ShardRegistry, key: &KeyT) {
+    let changeset = self.for_key(shard_registry, key);
+
+    // It's fine if it's not in changeset updates, this is for just in case.
+    changeset.updates.remove(key);
+    changeset.patches.remove(key);
+    changeset.typed_ops.remove(key);
+    changeset.expirations.remove(key);
+    changeset.versions.remove(key);
+    changeset.removals.insert(key.clone());
+  }
+}
+
+// Commands `FileBackup::log` handles itself, as they touch whole namespaces or move keys between
+// shards.
+fn is_resolved_by_backup(cmd: &Command) -> bool {
+  matches!(
+    cmd,
+    Command::Rename { .. }
+      | Command::Copy { .. }
+      | Command::Move { .. }
+      | Command::FlushNs
+      | Command::FlushAll
+      | Command::Namespaced { .. }
+  )
+}
+
+// Collections are stored length-prefixed, see `encode_value`.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+enum BackupValueType {
+  #[default]
+  Bytes,
+  List,
+  Hash,
+  Set,
+}
+
+// Lists are stored as their elements, hashes as field/value pairs and sets as members - in order.
+fn encode_value(value: &Value) -> (BackupValueType, Vec<u8>) {
+  let value_type = match value {
+    Value::Bytes(_) => BackupValueType::Bytes,
+    Value::List(_) => BackupValueType::List,
+    Value::Hash(_) => BackupValueType::Hash,
+    Value::Set(_) => BackupValueType::Set,
+  };
+  (value_type, value.to_bytes())
+}
+
+fn decode_value(value_type: BackupValueType, bytes: &[u8]) -> Value {
+  if let BackupValueType::Bytes = value_type {
+    return Value::Bytes(bytes.to_vec());
+  }
+
+  let fields = length_prefixed::decode(bytes).expect("Cannot decode collection value");
+  match value_type {
+    BackupValueType::Bytes => unreachable!(),
+    BackupValueType::List => Value::List(fields.into_iter().collect()),
+    BackupValueType::Hash => Value::Hash(
+      fields
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect(),
+    ),
+    BackupValueType::Set => Value::Set(fields.into_iter().collect()),
+  }
+}
+
 #[derive(Serialize, Deserialize)]
 struct BackupKeyInfo {
   content_size: usize,
   capacity: usize,
   pos: usize,
+  #[serde(default)]
+  expire_at: Option<u64>,
+  #[serde(default)]
+  version: VersionT,
+  #[serde(default)]
+  value_type: BackupValueType,
+  // Unix ms, 0 if unknown (backups from before these were kept).
+  #[serde(default)]
+  created_at: u64,
+  #[serde(default)]
+  modified_at: u64,
 }
 
 impl BackupKeyInfo {
@@ -71,18 +332,106 @@ impl BackupKeyInfo {
       content_size,
       capacity,
       pos,
+      expire_at: None,
+      version: 0,
+      value_type: BackupValueType::Bytes,
+      created_at: 0,
+      modified_at: 0,
     }
   }
 
   fn value_range(&self) -> Range<usize> {
     self.pos..(self.pos + self.content_size)
   }
+
+  // Writes the patch into the value's slot. The value grows into its padding while it fits the
+  // capacity, otherwise it's moved to the end of the values (with padding of its new size).
+  fn apply_patch(&mut self, value_file_content: &mut Vec<u8>, patch: &Patch) {
+    let offset = patch.offset.unwrap_or(self.content_size);
+    let end = offset + patch.bytes.len();
+
+    if end > self.capacity {
+      let value = value_file_content[self.value_range()].to_vec();
+      self.pos = value_file_content.len();
+      self.capacity = end << 1;
+      value_file_content.extend(value);
+      value_file_content.resize(self.pos + self.capacity, 0);
+    }
+
+    // The padding can still hold bytes of a previously longer value.
+    if offset > self.content_size {
+      value_file_content[self.pos + self.content_size..self.pos + offset].fill(0);
+    }
+    value_file_content[self.pos + offset..self.pos + end].copy_from_slice(&patch.bytes);
+    self.content_size = self.content_size.max(end);
+  }
 }
 
-#[derive(Default, Serialize, Deserialize)]
-struct BackupKeys(HashMap<String, BackupKeyInfo>);
+#[derive(Default)]
+struct BackupKeys(HashMap<KeyT, BackupKeyInfo>);
+
+// Keys are binary, so they are stored as a list of (key, info) pairs instead of a JSON object.
+// Backups made while keys were strings (an object) still load.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredBackupKeys {
+  Pairs(Vec<(KeyT, BackupKeyInfo)>),
+  Legacy(HashMap<String, BackupKeyInfo>),
+}
+
+impl Serialize for BackupKeys {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(self.0.iter())
+  }
+}
+
+impl<'de> Deserialize<'de> for BackupKeys {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let keys = match StoredBackupKeys::deserialize(deserializer)? {
+      StoredBackupKeys::Pairs(pairs) => pairs.into_iter().collect(),
+      StoredBackupKeys::Legacy(map) => map
+        .into_iter()
+        .map(|(key, key_info)| (key.into_bytes(), key_info))
+        .collect(),
+    };
+    Ok(BackupKeys(keys))
+  }
+}
 
 impl BackupKeys {
+  // Writes the full value of the key into its slot if it fits, to the end of the values otherwise.
+  fn write(
+    &mut self,
+    value_file_content: &mut Vec<u8>,
+    key: &KeyT,
+    bytes: &[u8],
+    value_type: BackupValueType,
+  ) {
+    // We already have that key/v.
+    if let Some(elem) = self.0.get_mut(key) {
+      elem.value_type = value_type;
+      // The change fits in the current slot.
+      if elem.capacity >= bytes.len() {
+        elem.content_size = bytes.len();
+        let override_range = elem.pos..(elem.pos + bytes.len());
+        value_file_content.splice(override_range, bytes.iter().cloned());
+      // Change needs a bigger spot.
+      } else {
+        elem.content_size = bytes.len();
+        elem.capacity = bytes.len() << 1;
+        elem.pos = value_file_content.len();
+        append_bytes_with_same_size_padding(value_file_content, bytes);
+      }
+    // We do not have this key/v.
+    } else {
+      let mut new_key_info =
+        BackupKeyInfo::new(bytes.len(), bytes.len() << 1, value_file_content.len());
+      new_key_info.value_type = value_type;
+      self.0.insert(key.clone(), new_key_info);
+      append_bytes_with_same_size_padding(value_file_content, bytes);
+    }
+  }
+
   fn size(&self) -> usize {
     self
       .0
@@ -126,7 +475,7 @@ impl ShardRegistry {
     }
   }
 
-  fn filehash_for_key(&self, key: &String) -> String {
+  fn filehash_for_key(&self, key: &KeyT) -> String {
     let key_hash = hash_for_key(key);
 
     let (filehash, _) = self
@@ -139,10 +488,12 @@ impl ShardRegistry {
   }
 }
 
+// Each namespace has its own shard registry (and so its own shard files). Filehashes are unique
+// across namespaces, so pending changesets can share one collection.
 pub struct FileBackup {
   changesets: ChangesetCollection,
   dir: String,
-  shard_registry: ShardRegistry,
+  shard_registries: HashMap<NamespaceT, ShardRegistry>,
   op_mutex: Mutex<()>,
 }
 
@@ -154,17 +505,17 @@ impl Drop for FileBackup {
 
 impl FileBackup {
   pub fn new(dir: String) -> Self {
-    let shard_registry = Self::fetch_shard_registry(&dir);
+    let shard_registries = Self::fetch_shard_registries(&dir);
 
     let instance = Self {
       changesets: ChangesetCollection::default(),
       dir,
-      shard_registry,
+      shard_registries,
       op_mutex: Mutex::new(()),
     };
 
     // IDEA: have a dirty indicator, so only save when needed.
-    instance.save_shard_registry();
+    instance.save_shard_registries();
 
     instance
   }
@@ -177,44 +528,75 @@ impl FileBackup {
       .changesets
       .0
       .values()
-      .map(|changeset| changeset.updates.len() + changeset.removals.len())
+      .map(|changeset| {
+        changeset.updates.len()
+          + changeset.patches.len()
+          + changeset.typed_ops.len()
+          + changeset.removals.len()
+          + changeset.expirations.len()
+      })
       .sum();
 
     change_count % CHANGELOG_TRESHOLD_TO_INIT_BACKUP == 0
   }
 
-  pub fn log(&mut self, cmd: &Command) {
+  pub fn log(&mut self, cmd: &Command, version: VersionT) {
+    let (namespace, cmd) = match cmd {
+      Command::Namespaced { namespace, command } => (namespace.as_str(), command.as_ref()),
+      cmd => (DEFAULT_NAMESPACE, cmd),
+    };
+
+    if let Command::FlushNs = cmd {
+      // Pending changes are written first so no later backup brings back flushed keys.
+      self.backup();
+      self.flush_namespace(namespace);
+      return;
+    }
+    if let Command::FlushAll = cmd {
+      self.flush_all();
+      return;
+    }
+
+    match cmd {
+      Command::Rename { src, dst, .. } => {
+        self.backup();
+        self.copy_key((namespace, src), (namespace, dst), true, version);
+        self.shard();
+        return;
+      }
+      Command::Copy { src, dst } => {
+        self.backup();
+        self.copy_key((namespace, src), (namespace, dst), false, version);
+        self.shard();
+        return;
+      }
+      Command::Move {
+        key,
+        namespace: dst_namespace,
+      } => {
+        self.backup();
+        self.copy_key((namespace, key), (dst_namespace, key), true, version);
+        self.shard();
+        return;
+      }
+      // The commands around a flush, rename or copy are logged in order around it.
+      Command::Batch { commands } if commands.iter().any(is_resolved_by_backup) => {
+        for cmd in commands {
+          self.log(&cmd.clone().in_namespace(namespace), version);
+        }
+        return;
+      }
+      _ => (),
+    }
+
     {
       let _op_guard = self.op_mutex.lock().expect("Cannot gain lock");
 
-      match cmd {
-        Command::Delete { key } => {
-          let filehash = self.shard_registry.filehash_for_key(key);
-          let changeset = self
-            .changesets
-            .0
-            .entry(filehash)
-            .or_insert(Changeset::default());
-
-            // It's fine if it's not in changeset updates, this is for just in case.
-            changeset.updates.remove(key.as_str());
-            changeset.removals.insert(key.clone());
-        }
-        Command::Set { key, value } => {
-          let filehash = self.shard_registry.filehash_for_key(key);
-          let changeset = self
-            .changesets
-            .0
-            .entry(filehash)
-            .or_insert(Changeset::default());
-
-          changeset.updates.insert(key.clone(), value.clone());
-        }
-        Command::Sync { .. } => {
-          unimplemented!("We cannot handle sync here, should be translated to SET/DELETE")
-        }
-        Command::Get { .. } | Command::GetLastReplicationId | Command::Invalid => (),
-      };
+      let shard_registry = self
+        .shard_registries
+        .entry(namespace.into())
+        .or_insert_with(|| ShardRegistry::new(SHARD_BREAK_LIMIT));
+      self.changesets.log(shard_registry, cmd, version);
     }
 
     if self.should_backup() {
@@ -228,20 +610,140 @@ impl FileBackup {
     }
   }
 
-  pub fn restore(&self, storage: Arc<Mutex<Storage>>) {
+  pub fn restore<E: KeyspaceEngine>(&self, storage: Arc<RwLock<Storage<E>>>) {
+    let _op_guard = self.op_mutex.lock().expect("Cannot gain lock");
+
+    let shard_registries = Self::fetch_shard_registries(&self.dir);
+    let mut storage = storage.write().expect("Cannot gain lock to storage");
+
+    for (namespace, shard_registry) in &shard_registries {
+      let keyspace = storage.keyspace_mut(namespace);
+
+      for (filehash, _) in &shard_registry.files {
+        let registered_backup_keys = self.fetch_backup_keys(filehash);
+        let value_file_content: Vec<u8> = self.fetch_backup_values(filehash);
+
+        for (key, info) in &registered_backup_keys.0 {
+          let value_range = info.value_range();
+          let value = &value_file_content[value_range];
+          // Keys that expired while the server was down are left to the writer's expiry sweep, so
+          // readers get the deletes replicated.
+          keyspace.set_value(
+            key.clone(),
+            decode_value(info.value_type, value),
+            info.expire_at,
+            info.version,
+          );
+          // Keys of older backups keep the restore time.
+          if info.created_at > 0 {
+            keyspace.set_timestamps(key, info.created_at, info.modified_at);
+          }
+        }
+      }
+
+      info!("Restored {} keys in {:?}", keyspace.len(), namespace);
+    }
+  }
+
+  // Copies the stored entry of `src` to `dst` (moves it if `is_move`), both given by namespace and
+  // key, so `dst` can belong to another shard or namespace. The entry is read from the shard files,
+  // so pending changes must be backed up before.
+  fn copy_key(
+    &mut self,
+    (src_namespace, src): (&str, &KeyT),
+    (dst_namespace, dst): (&str, &KeyT),
+    is_move: bool,
+    version: VersionT,
+  ) {
     let _op_guard = self.op_mutex.lock().expect("Cannot gain lock");
 
-    let shard_registry = Self::fetch_shard_registry(&self.dir);
-    let mut storage = storage.lock().expect("Cannot gain lock to storage");
+    if (src_namespace, src) == (dst_namespace, dst) {
+      return;
+    }
+    let src_filehash = match self.shard_registries.get(src_namespace) {
+      Some(shard_registry) => shard_registry.filehash_for_key(src),
+      None => return,
+    };
+    let dst_filehash = self
+      .shard_registries
+      .entry(dst_namespace.into())
+      .or_insert_with(|| ShardRegistry::new(SHARD_BREAK_LIMIT))
+      .filehash_for_key(dst);
+
+    let mut src_keys = self.fetch_backup_keys(&src_filehash);
+    let src_info = match src_keys.0.get(src) {
+      Some(src_info) => src_info,
+      None => return,
+    };
+    let bytes = self.fetch_backup_values(&src_filehash)[src_info.value_range()].to_vec();
+    let (value_type, expire_at) = (src_info.value_type, src_info.expire_at);
+
+    // The source is saved first, as the destination can be in the same shard.
+    if is_move {
+      src_keys.0.remove(src);
+      self.save_backup_keys(&src_filehash, src_keys);
+    }
 
-    for (filehash, _) in &shard_registry.files {
-      let registered_backup_keys = self.fetch_backup_keys(filehash);
-      let value_file_content: Vec<u8> = self.fetch_backup_values(filehash);
+    let mut dst_keys = self.fetch_backup_keys(&dst_filehash);
+    let mut value_file_content = self.fetch_backup_values(&dst_filehash);
+    dst_keys.write(&mut value_file_content, dst, &bytes, value_type);
+
+    let dst_info = dst_keys.0.get_mut(dst).expect("Key not found");
+    dst_info.expire_at = expire_at;
+    dst_info.version = version;
+    dst_info.modified_at = now_ms();
+    if dst_info.created_at == 0 {
+      dst_info.created_at = dst_info.modified_at;
+    }
 
-      for (key, info) in &registered_backup_keys.0 {
-        let value_range = info.value_range();
-        let value = &value_file_content[value_range];
-        storage.set(key.clone(), value.to_vec());
+    self.save_backup_keys(&dst_filehash, dst_keys);
+    self.save_backup_values(&dst_filehash, &value_file_content[..]);
+  }
+
+  // Drops all shard files of the namespace and starts it over with an empty registry.
+  fn flush_namespace(&mut self, namespace: &str) {
+    let _op_guard = self.op_mutex.lock().expect("Cannot gain lock");
+
+    if let Some(shard_registry) = self.shard_registries.remove(namespace) {
+      self.delete_shard_files(&shard_registry);
+    }
+
+    self
+      .shard_registries
+      .insert(namespace.into(), ShardRegistry::new(SHARD_BREAK_LIMIT));
+    self.save_shard_registries();
+  }
+
+  // Drops the shard files of all namespaces and starts over with only an empty default namespace.
+  // Pending changes are dropped too, there is nothing left for them to change.
+  fn flush_all(&mut self) {
+    let _op_guard = self.op_mutex.lock().expect("Cannot gain lock");
+
+    self.changesets = ChangesetCollection::default();
+    for (namespace, shard_registry) in std::mem::take(&mut self.shard_registries) {
+      self.delete_shard_files(&shard_registry);
+
+      let shard_registry_file_path = Self::shard_registry_file_path(&self.dir, &namespace);
+      if namespace != DEFAULT_NAMESPACE && shard_registry_file_path.exists() {
+        fs::remove_file(shard_registry_file_path).expect("Cannot delete shard registry file");
+      }
+    }
+
+    self.shard_registries.insert(
+      DEFAULT_NAMESPACE.into(),
+      ShardRegistry::new(SHARD_BREAK_LIMIT),
+    );
+    self.save_shard_registries();
+  }
+
+  fn delete_shard_files(&self, shard_registry: &ShardRegistry) {
+    for filehash in shard_registry.files.keys() {
+      // Shards that were never backed up have no files yet.
+      if self.key_file_path(filehash).exists() {
+        self.delete_backup_keys(filehash);
+      }
+      if self.value_file_path(filehash).exists() {
+        self.delete_backup_values(filehash);
       }
     }
   }
@@ -260,26 +762,64 @@ impl FileBackup {
 
       // Update all updates.
       for (key, bytes) in &changeset.updates {
-        // We already have that key/v.
-        if registered_backup_keys.0.contains_key(key) {
-          let mut elem = registered_backup_keys.0.get_mut(key).unwrap();
-          // The change fits in the current slot.
-          if elem.capacity >= bytes.len() {
-            elem.content_size = bytes.len();
-            let override_range = elem.pos..(elem.pos + bytes.len());
-            value_file_content.splice(override_range, bytes.iter().cloned());
-          // Change needs a bigger spot.
-          } else {
-            elem.content_size = bytes.len();
-            elem.pos = value_file_content.len();
-            append_bytes_with_same_size_padding(&mut value_file_content, &bytes[..]);
+        registered_backup_keys.write(&mut value_file_content, key, bytes, BackupValueType::Bytes);
+      }
+
+      // Apply byte-range writes. Keys created by them start out empty.
+      for (key, patches) in &changeset.patches {
+        let mut key_info = registered_backup_keys
+          .0
+          .remove(key)
+          .unwrap_or_else(|| BackupKeyInfo::new(0, 0, value_file_content.len()));
+
+        for patch in patches {
+          key_info.apply_patch(&mut value_file_content, patch);
+        }
+        registered_backup_keys.0.insert(key.clone(), key_info);
+      }
+
+      // Apply collection operations on the stored collections. A single key needs no hashing.
+      for (key, ops) in &changeset.typed_ops {
+        let mut keyspace = Keyspace::<BTreeMapEngine<Entry>>::new();
+        if let Some(key_info) = registered_backup_keys.0.get(key) {
+          let value = &value_file_content[key_info.value_range()];
+          keyspace.set_value(
+            key.clone(),
+            decode_value(key_info.value_type, value),
+            None,
+            0,
+          );
+        }
+
+        for op in ops {
+          keyspace.execute(op.clone(), 0);
+        }
+
+        // Emptied collections are removed.
+        match keyspace.value(key) {
+          Some(value) => {
+            let (value_type, bytes) = encode_value(value);
+            registered_backup_keys.write(&mut value_file_content, key, &bytes, value_type);
+          }
+          None => {
+            registered_backup_keys.0.remove(key);
+          }
+        }
+      }
+
+      // Update expiries and versions - after the updates so new keys are already registered.
+      for (key, expire_at) in &changeset.expirations {
+        if let Some(elem) = registered_backup_keys.0.get_mut(key) {
+          elem.expire_at = *expire_at;
+        }
+      }
+      for (key, (version, modified_at)) in &changeset.versions {
+        if let Some(elem) = registered_backup_keys.0.get_mut(key) {
+          elem.version = *version;
+          elem.modified_at = *modified_at;
+          if elem.created_at == 0 {
+            elem.created_at = *modified_at;
           }
-        // We do not have this key/v.
-        } else {
-          let new_key_info =
-            BackupKeyInfo::new(bytes.len(), bytes.len() << 1, value_file_content.len());
-          registered_backup_keys.0.insert(key.clone(), new_key_info);
-          append_bytes_with_same_size_padding(&mut value_file_content, &bytes[..]);
         }
       }
 
@@ -287,7 +827,7 @@ impl FileBackup {
       self.save_backup_values(filehash, &value_file_content[..]);
     });
 
-    self.save_shard_registry();
+    self.save_shard_registries();
 
     // Reset changelog.
     self.changesets = ChangesetCollection::default();
@@ -296,18 +836,31 @@ impl FileBackup {
   fn shard(&mut self) {
     let _op_guard = self.op_mutex.lock().expect("Cannot gain lock");
 
+    let namespaces: Vec<NamespaceT> = self.shard_registries.keys().cloned().collect();
+    for namespace in namespaces {
+      let mut shard_registry = self
+        .shard_registries
+        .remove(&namespace)
+        .expect("Namespace not found");
+      self.split_shards(&mut shard_registry);
+      self.shard_registries.insert(namespace, shard_registry);
+    }
+
+    self.save_shard_registries();
+  }
+
+  fn split_shards(&self, shard_registry: &mut ShardRegistry) {
     let mut change_required: Vec<String> = vec![];
 
-    self.shard_registry.files.keys().for_each(|filehash| {
+    shard_registry.files.keys().for_each(|filehash| {
       let key_db = self.fetch_backup_keys(filehash);
-      if key_db.size() >= self.shard_registry.shard_break_limit {
+      if key_db.size() >= shard_registry.shard_break_limit {
         change_required.push(filehash.clone());
       }
     });
 
     for filehash_to_split in &change_required {
-      let old_file_info = self
-        .shard_registry
+      let old_file_info = shard_registry
         .files
         .remove(filehash_to_split)
         .expect("Key not found");
@@ -322,12 +875,10 @@ impl FileBackup {
       let new_file_info_lhs = ShardFileInfo::new(new_mod, new_mod_value_lhs);
       let new_file_info_rhs = ShardFileInfo::new(new_mod, new_mod_value_rhs);
 
-      self
-        .shard_registry
+      shard_registry
         .files
         .insert(new_filehash_lhs.clone(), new_file_info_lhs);
-      self
-        .shard_registry
+      shard_registry
         .files
         .insert(new_filehash_rhs.clone(), new_file_info_rhs);
 
@@ -352,21 +903,22 @@ impl FileBackup {
         let key_hash = hash_for_key(&key);
         let old_value_part = &old_value[value_info.value_range()];
 
+        // Only the content is moved (with same size padding), so the capacity shrinks to that.
         if key_hash % new_mod == new_mod_value_lhs {
-          let new_key_info = BackupKeyInfo::new(
-            value_info.content_size,
-            value_info.capacity,
-            new_content_lhs.len(),
-          );
+          let new_key_info = BackupKeyInfo {
+            pos: new_content_lhs.len(),
+            capacity: value_info.content_size << 1,
+            ..value_info
+          };
           append_bytes_with_same_size_padding(&mut new_content_lhs, old_value_part);
 
           new_keys_lhs.0.insert(key.clone(), new_key_info);
         } else if key_hash % new_mod == new_mod_value_rhs {
-          let new_key_info = BackupKeyInfo::new(
-            value_info.content_size,
-            value_info.capacity,
-            new_content_rhs.len(),
-          );
+          let new_key_info = BackupKeyInfo {
+            pos: new_content_rhs.len(),
+            capacity: value_info.content_size << 1,
+            ..value_info
+          };
           append_bytes_with_same_size_padding(&mut new_content_rhs, old_value_part);
 
           new_keys_rhs.0.insert(key.clone(), new_key_info);
@@ -388,8 +940,6 @@ impl FileBackup {
       self.delete_backup_keys(&filehash_to_split);
       self.delete_backup_values(&filehash_to_split);
     }
-
-    self.save_shard_registry();
   }
 
   fn key_file_path(&self, filehash: &str) -> PathBuf {
@@ -410,19 +960,54 @@ impl FileBackup {
     Path::new(&self.dir).join(filename)
   }
 
-  fn shard_registry_file_path(dir: &str) -> PathBuf {
-    Path::new(dir).join("__traf_shards.db")
+  // The default namespace keeps the original registry file name.
+  fn shard_registry_file_path(dir: &str, namespace: &str) -> PathBuf {
+    if namespace == DEFAULT_NAMESPACE {
+      Path::new(dir).join("__traf_shards.db")
+    } else {
+      Path::new(dir).join(format!(
+        "{}{}.db",
+        SHARD_REGISTRY_NAMESPACE_PREFIX, namespace
+      ))
+    }
   }
 
   // IDEA: File ops could be tokio::fs.
 
-  fn fetch_shard_registry(dir: &str) -> ShardRegistry {
+  fn fetch_shard_registries(dir: &str) -> HashMap<NamespaceT, ShardRegistry> {
+    let mut namespaces: Vec<NamespaceT> = vec![DEFAULT_NAMESPACE.into()];
+
+    for entry in fs::read_dir(dir).expect("Cannot list backup dir") {
+      let filename = entry
+        .expect("Cannot read backup dir entry")
+        .file_name()
+        .to_string_lossy()
+        .to_string();
+
+      if let Some(namespace) = filename
+        .strip_prefix(SHARD_REGISTRY_NAMESPACE_PREFIX)
+        .and_then(|rest| rest.strip_suffix(".db"))
+      {
+        namespaces.push(namespace.into());
+      }
+    }
+
+    namespaces
+      .into_iter()
+      .map(|namespace| {
+        let shard_registry = Self::fetch_shard_registry(dir, &namespace);
+        (namespace, shard_registry)
+      })
+      .collect()
+  }
+
+  fn fetch_shard_registry(dir: &str, namespace: &str) -> ShardRegistry {
     let shard_registry_file = OpenOptions::new()
       .read(true)
       .write(true)
       .create(true)
       .truncate(false)
-      .open(FileBackup::shard_registry_file_path(dir))
+      .open(FileBackup::shard_registry_file_path(dir, namespace))
       .expect("Cannot open shard registry file for read");
 
     // FIXME: do not hardcode shard break limit.
@@ -493,18 +1078,19 @@ impl FileBackup {
     fs::remove_file(self.value_file_path(filehash)).expect("Cannot delete value file");
   }
 
-  fn save_shard_registry(&self) {
-    let mut shard_registry_file = OpenOptions::new()
-      .read(false)
-      .write(true)
-      .create(true)
-      .truncate(true)
-      .open(Self::shard_registry_file_path(&self.dir))
-      .expect("Cannot open shard registry file for write");
-    let blob =
-      serde_json::to_string(&self.shard_registry).expect("Cannot serialize shard registry");
-    shard_registry_file
-      .write_all(blob.as_bytes())
-      .expect("Cannot write shard registry");
+  fn save_shard_registries(&self) {
+    for (namespace, shard_registry) in &self.shard_registries {
+      let mut shard_registry_file = OpenOptions::new()
+        .read(false)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(Self::shard_registry_file_path(&self.dir, namespace))
+        .expect("Cannot open shard registry file for write");
+      let blob = serde_json::to_string(shard_registry).expect("Cannot serialize shard registry");
+      shard_registry_file
+        .write_all(blob.as_bytes())
+        .expect("Cannot write shard registry");
+    }
   }
 }
@@ -0,0 +1,194 @@
+use crate::command::Command;
+use crate::storage::KeyT;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::sync::oneshot;
+use traf_lib::response_frame::ResponseFrame;
+
+// What a request at the app may write. Reads of the connection wait behind the writes they could
+// see, so a client reads its own writes - and only those, other reads are served right away.
+pub enum Writes {
+  Nothing,
+  Keys(Vec<KeyT>),
+  // Writes without given keys, eg. FLUSHNS.
+  Any,
+}
+
+impl Writes {
+  pub fn of(command: &Command) -> Writes {
+    match command {
+      Command::Namespaced { command, .. } => Writes::of(command),
+      Command::Exec { commands, .. } => commands
+        .iter()
+        .map(Writes::of)
+        .fold(Writes::Nothing, Writes::and),
+      Command::FlushAll | Command::Sync { .. } => Writes::Any,
+      Command::Move { key, .. } => Writes::Keys(vec![key.clone()]),
+      command if command.is_mutation() => match command.keys() {
+        keys if keys.is_empty() => Writes::Any,
+        keys => Writes::Keys(keys.into_iter().cloned().collect()),
+      },
+      _ => Writes::Nothing,
+    }
+  }
+
+  fn and(self, other: Writes) -> Writes {
+    match (self, other) {
+      (Writes::Any, _) | (_, Writes::Any) => Writes::Any,
+      (Writes::Nothing, writes) | (writes, Writes::Nothing) => writes,
+      (Writes::Keys(mut keys), Writes::Keys(other_keys)) => {
+        keys.extend(other_keys);
+        Writes::Keys(keys)
+      }
+    }
+  }
+
+  // Namespaces are not compared, a read of the same key in another namespace waits needlessly.
+  fn is_seen_by(&self, read: &Command) -> bool {
+    let read = match read {
+      Command::Namespaced { command, .. } => command.as_ref(),
+      read => read,
+    };
+
+    match self {
+      Writes::Nothing => false,
+      Writes::Any => true,
+      // Reads without given keys (eg. SCAN) may see any key.
+      Writes::Keys(keys) => {
+        let read_keys = read.keys();
+        read_keys.is_empty() || read_keys.iter().any(|key| keys.contains(key))
+      }
+    }
+  }
+}
+
+// The requests of a connection still at the app. Their answers are taken in whatever order they
+// arrive - the app answers in the order it gets the commands.
+#[derive(Default)]
+pub struct InFlight {
+  requests: Vec<(u32, Writes, oneshot::Receiver<ResponseFrame>)>,
+}
+
+impl InFlight {
+  pub fn len(&self) -> usize {
+    self.requests.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.requests.is_empty()
+  }
+
+  pub fn push(
+    &mut self,
+    request_id: u32,
+    writes: Writes,
+    feedback_rx: oneshot::Receiver<ResponseFrame>,
+  ) {
+    self.requests.push((request_id, writes, feedback_rx));
+  }
+
+  // True if the read could see a write still at the app.
+  pub fn is_written(&self, read: &Command) -> bool {
+    self
+      .requests
+      .iter()
+      .any(|(_, writes, _)| writes.is_seen_by(read))
+  }
+
+  // The next answer of the app, with the id of its request. Cancel safe, as it's awaited in the
+  // `select!` of `process`.
+  pub async fn next(&mut self) -> (u32, ResponseFrame) {
+    if self.requests.is_empty() {
+      return std::future::pending().await;
+    }
+
+    std::future::poll_fn(|cx| {
+      for i in 0..self.requests.len() {
+        if let Poll::Ready(response) = Pin::new(&mut self.requests[i].2).poll(cx) {
+          let (request_id, _, _) = self.requests.remove(i);
+          return Poll::Ready((
+            request_id,
+            response.expect("Failed getting process feedback"),
+          ));
+        }
+      }
+      Poll::Pending
+    })
+    .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn get(key: &str) -> Command {
+    Command::Get { key: key.into() }
+  }
+
+  fn set(key: &str) -> Command {
+    Command::Set {
+      key: key.into(),
+      value: vec![1],
+      expire_at: None,
+    }
+  }
+
+  #[test]
+  fn reads_wait_for_the_writes_they_see() {
+    let mut in_app = InFlight::default();
+    let (_feedback_tx, feedback_rx) = oneshot::channel();
+    in_app.push(1, Writes::of(&set("a")), feedback_rx);
+
+    assert!(in_app.is_written(&get("a")));
+    assert!(!in_app.is_written(&get("b")));
+    assert!(in_app.is_written(&Command::DbSize));
+    assert!(in_app.is_written(&get("a").in_namespace("other")));
+
+    let (_feedback_tx, feedback_rx) = oneshot::channel();
+    in_app.push(2, Writes::of(&Command::FlushNs), feedback_rx);
+    assert!(in_app.is_written(&get("b")));
+  }
+
+  #[test]
+  fn reads_do_not_wait_for_reads() {
+    let mut in_app = InFlight::default();
+    let (_feedback_tx, feedback_rx) = oneshot::channel();
+    let history = Command::History {
+      key: "a".into(),
+      limit: None,
+    };
+    in_app.push(1, Writes::of(&history), feedback_rx);
+
+    assert!(!in_app.is_written(&get("a")));
+    assert!(!in_app.is_written(&Command::DbSize));
+  }
+
+  #[test]
+  fn transactions_write_the_keys_of_their_commands() {
+    let exec = Command::Exec {
+      watches: vec![],
+      commands: vec![get("a"), set("b")],
+    };
+    let mut in_app = InFlight::default();
+    let (_feedback_tx, feedback_rx) = oneshot::channel();
+    in_app.push(1, Writes::of(&exec), feedback_rx);
+
+    assert!(!in_app.is_written(&get("a")));
+    assert!(in_app.is_written(&get("b")));
+  }
+
+  #[tokio::test]
+  async fn answers_are_taken_as_they_arrive() {
+    let mut in_app = InFlight::default();
+    let (_slow_tx, slow_rx) = oneshot::channel();
+    let (fast_tx, fast_rx) = oneshot::channel();
+    in_app.push(1, Writes::Nothing, slow_rx);
+    in_app.push(2, Writes::Nothing, fast_rx);
+
+    fast_tx.send(ResponseFrame::Success).unwrap();
+    assert_eq!((2, ResponseFrame::Success), in_app.next().await);
+    assert_eq!(1, in_app.len());
+  }
+}
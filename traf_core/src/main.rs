@@ -1,4 +1,5 @@
 use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
 
 use clap::{self, Arg};
 use command::Command;
@@ -8,9 +9,17 @@ use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::oneshot;
 
 use crate::app::{App, InstanceType};
+use crate::engine::{BTreeMapEngine, EngineKind, HashMapEngine, ShardedEngine};
+use crate::eviction::{EvictionPolicy, MemoryLimit};
+use crate::in_flight::{InFlight, Writes};
 use crate::replicator::ReaderList;
+use crate::command::is_valid_namespace;
+use crate::pubsub::{Change, Publisher, Push, Subscription};
+use crate::storage::{now_ms, Entry, KeyspaceEngine, Storage, VersionT, DEFAULT_NAMESPACE};
+use crate::transaction::Transaction;
 use traf_lib::{
-  frame_reader::{Frame, FramedTcpStream},
+  frame_reader::FramedTcpStream,
+  request::{self, Opcode, Request},
   response_frame::ResponseFrame,
 };
 
@@ -20,22 +29,28 @@ extern crate log;
 mod app;
 mod file_backup;
 mod command;
+mod engine;
+mod eviction;
+mod in_flight;
+mod pubsub;
 mod replicator;
 mod storage;
+mod transaction;
 
-pub struct FrameAndChannel {
-  frame: Frame,
-  channel: oneshot::Sender<Vec<u8>>,
+pub struct CommandAndChannel {
+  command: Command,
+  channel: oneshot::Sender<ResponseFrame>,
 }
 
-impl FrameAndChannel {
-  fn new(frame: Frame, channel: oneshot::Sender<Vec<u8>>) -> Self {
-    FrameAndChannel { frame, channel }
+impl CommandAndChannel {
+  fn new(command: Command, channel: oneshot::Sender<ResponseFrame>) -> Self {
+    CommandAndChannel { command, channel }
   }
 }
 
 pub trait Executor {
-  fn execute(&mut self, command: Command) -> ResponseFrame;
+  // Mutations stamp the values they write with `version`.
+  fn execute(&mut self, command: Command, version: VersionT) -> ResponseFrame;
 }
 
 // IDEA: (BIG) distributed layout
@@ -75,8 +90,43 @@ async fn main() -> Result<(), String> {
         .takes_value(true)
         .default_value(""),
     )
+    .arg(
+      Arg::with_name("maxmemory")
+        .short("m")
+        .value_name("MAXMEMORY_BYTES")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("eviction_policy")
+        .short("e")
+        .value_name("EVICTION_POLICY")
+        .takes_value(true)
+        .default_value("noeviction"),
+    )
+    .arg(
+      Arg::with_name("storage_engine")
+        .short("s")
+        .value_name("STORAGE_ENGINE")
+        .takes_value(true)
+        .default_value("hashmap"),
+    )
     .get_matches();
 
+  let engine = arg_matches
+    .value_of("storage_engine")
+    .map(EngineKind::try_from)
+    .unwrap()
+    .expect("Storage engine can be hashmap, btreemap or sharded");
+
+  match engine {
+    EngineKind::HashMap => serve::<HashMapEngine<Entry>>(&arg_matches).await,
+    EngineKind::BTreeMap => serve::<BTreeMapEngine<Entry>>(&arg_matches).await,
+    EngineKind::Sharded => serve::<ShardedEngine<Entry>>(&arg_matches).await,
+  }
+}
+
+// Serves connections with the keyspaces kept in engine `E`.
+async fn serve<E: KeyspaceEngine>(arg_matches: &clap::ArgMatches<'_>) -> Result<(), String> {
   let instance_type = match arg_matches.value_of("type") {
     Some("writer") => InstanceType::Writer,
     Some("reader") => InstanceType::Reader,
@@ -94,11 +144,32 @@ async fn main() -> Result<(), String> {
   let readers = ReaderList::try_from(readers_raw)
     .expect("Incorrect readers input. Expected: -r IP1:PORT1,IP2:PORT2...");
 
+  let eviction_policy = arg_matches
+    .value_of("eviction_policy")
+    .map(EvictionPolicy::try_from)
+    .unwrap()
+    .expect("Eviction policy can be noeviction, allkeys-lru, allkeys-lfu or volatile-ttl");
+
+  // Without a limit the storage grows as long as the process can allocate.
+  let memory_limit: Option<MemoryLimit> = arg_matches
+    .value_of("maxmemory")
+    .map(|raw| raw.parse().expect("Invalid number format"))
+    .map(|max_bytes| MemoryLimit::new(max_bytes, eviction_policy));
+
   let address = arg_matches.value_of("address").unwrap();
 
   let listener = TcpListener::bind(address).await.unwrap();
-  let (tx, rx): (Sender<FrameAndChannel>, Receiver<FrameAndChannel>) = mpsc::channel(32);
-  let mut app: App = App::new(instance_type, last_replica_id, readers, rx);
+  let (tx, rx): (Sender<CommandAndChannel>, Receiver<CommandAndChannel>) = mpsc::channel(32);
+  let publisher = Publisher::new();
+  let mut app: App<E> = App::new(
+    instance_type,
+    last_replica_id,
+    readers,
+    memory_limit,
+    publisher.clone(),
+    rx,
+  );
+  let storage = app.storage();
 
   let _app_join_handle = spawn(async move {
     app.listen().await;
@@ -107,10 +178,14 @@ async fn main() -> Result<(), String> {
   loop {
     let (socket, _) = listener.accept().await.unwrap();
     let tx = tx.clone();
+    let storage = storage.clone();
+    let publisher = publisher.clone();
 
     spawn(async move {
       info!("socket connected");
-      process(socket, tx).await.expect("Failed processing");
+      process(socket, tx, storage, publisher)
+        .await
+        .expect("Failed processing");
       info!("socket disconnected");
     });
 
@@ -118,30 +193,313 @@ async fn main() -> Result<(), String> {
   }
 }
 
-async fn process(stream: TcpStream, tx: Sender<FrameAndChannel>) -> Result<(), String> {
+async fn process<E: KeyspaceEngine>(
+  stream: TcpStream,
+  tx: Sender<CommandAndChannel>,
+  storage: Arc<RwLock<Storage<E>>>,
+  publisher: Publisher,
+) -> Result<(), String> {
   let mut framed_stream = FramedTcpStream::new(stream);
-  loop {
-    let (feedback_tx, feedback_rx): (oneshot::Sender<Vec<u8>>, oneshot::Receiver<Vec<u8>>) =
-      oneshot::channel();
+  let mut transaction = Transaction::default();
+  let mut namespace: String = DEFAULT_NAMESPACE.into();
+  let mut subscription: Option<Subscription> = None;
+  let mut snapshot: Option<PinnedSnapshot<E>> = None;
+  // Clients may have many requests in flight: the next frame is read while earlier ones are still
+  // at the app. Responses go out as soon as they are ready, with the id of their request - so a
+  // slow request does not hold back the ones after it.
+  let mut in_app = InFlight::default();
 
-    let msg_in = framed_stream.read_frame().await;
+  loop {
+    // Changes of subscribed keys are pushed to the client between responses.
+    let msg_in = tokio::select! {
+      msg_in = framed_stream.read_frame(), if in_app.len() < MAX_PENDING_RESPONSES => msg_in,
+      (request_id, feedback) = in_app.next() => {
+        framed_stream
+          .write_frame(request_id, feedback.into())
+          .await
+          .expect("Failed sending message back to client");
+        info!("socket completed");
+        continue;
+      }
+      (request_ids, push) = next_change(&mut subscription) => {
+        let push_frame: Vec<u8> = match push {
+          Push::Change(Change::Key { key, value, .. }) => ResponseFrame::Change(key, value),
+          Push::Change(Change::Flush { .. }) => ResponseFrame::Flushed,
+          Push::Lagged => {
+            subscription = None;
+            ResponseFrame::ErrorLagged
+          }
+        }
+        .into();
+        for request_id in request_ids {
+          framed_stream
+            .write_frame(request_id, push_frame.clone())
+            .await
+            .expect("Failed pushing change to client");
+        }
+        continue;
+      }
+    };
     if msg_in.is_none() {
       info!("Socket ended");
+      // The client may only have closed its writing half, the answers are still due.
+      let _ = settle(&mut framed_stream, &mut in_app).await;
       return Ok(());
     }
 
-    let msg_in = msg_in.unwrap();
-    let frame_and_channel = FrameAndChannel::new(msg_in, feedback_tx);
-    tx.send(frame_and_channel)
-      .await
-      .unwrap_or_else(|_| panic!("Failed sending input to app channel"));
+    let frame = msg_in.unwrap();
+    let request_id = frame.request_id;
+    let bytes = frame.bytes;
+    let request = Request::try_from(bytes).ok();
+    let opcode = request.as_ref().map(|request| request.opcode);
+
+    // Transaction control and the selected namespace are handled per connection, everything else
+    // goes to the app (or is queued until EXEC within a MULTI block).
+    let feedback: PendingResponse = match (opcode, transaction.is_queuing()) {
+      (Some(Opcode::Multi), false) => {
+        transaction.begin();
+        ResponseFrame::Success.into()
+      }
+      (Some(Opcode::Select), false) => match single_field(request) {
+        Some(selected) if is_valid_namespace(&selected) => {
+          namespace = String::from_utf8(selected).unwrap();
+          ResponseFrame::Success.into()
+        }
+        _ => ResponseFrame::ErrorMalformed.into(),
+      },
+      (Some(Opcode::Subscribe), false) => match single_field(request) {
+        Some(pattern) => {
+          subscription
+            .get_or_insert_with(|| publisher.subscribe())
+            .add(request_id, &namespace, &pattern);
+          ResponseFrame::Success.into()
+        }
+        None => ResponseFrame::ErrorMalformed.into(),
+      },
+      (Some(Opcode::Exec), true) => {
+        let command = transaction.take_exec();
+        submit(&tx, in_selected_namespace(command, &namespace)).await
+      }
+      (Some(Opcode::Discard), true) => {
+        transaction.discard();
+        ResponseFrame::Success.into()
+      }
+      // Versions are taken once the requests in flight are applied.
+      (Some(Opcode::Watch), false) => {
+        settle(&mut framed_stream, &mut in_app)
+          .await
+          .expect("Failed sending message back to client");
+        for key in request.unwrap().fields {
+          let version = fetch_version(&tx, &storage, &namespace, key.clone()).await;
+          transaction.watch(key, version);
+        }
+        ResponseFrame::Success.into()
+      }
+      // Pins a read view for the following reads, replacing any previous one. Answers with the
+      // position of the view, to be used with `GET <key> AT <position>` on any connection.
+      (Some(Opcode::Snapshot), false) => {
+        settle(&mut framed_stream, &mut in_app)
+          .await
+          .expect("Failed sending message back to client");
+        let pinned = PinnedSnapshot::pin(&storage);
+        let position = pinned.position;
+        snapshot = Some(pinned);
+        ResponseFrame::Value(Vec::from(position.to_be_bytes())).into()
+      }
+      (Some(Opcode::SnapshotRelease), false) => match snapshot.take() {
+        Some(_) => ResponseFrame::Success.into(),
+        None => ResponseFrame::ErrorNotFound.into(),
+      },
+      // Clients open with `HELLO <protocol version>`. The answer tells them what this server
+      // speaks, it's up to them to hang up if they cannot talk to it.
+      (Some(Opcode::Hello), false) => {
+        match single_field(request).and_then(|version| request::decode_number(&version)) {
+          Some(version) => {
+            info!("Client speaks protocol version {}", version);
+            submit(&tx, Command::Hello).await
+          }
+          None => ResponseFrame::ErrorMalformed.into(),
+        }
+      }
+      (Some(Opcode::Multi), true)
+      | (Some(Opcode::Exec), false)
+      | (Some(Opcode::Discard), false)
+      | (Some(Opcode::Watch), true)
+      | (Some(Opcode::Snapshot), true)
+      | (Some(Opcode::SnapshotRelease), true)
+      | (Some(Opcode::Hello), true)
+      | (Some(Opcode::Select), true)
+      | (Some(Opcode::Subscribe), true) => ResponseFrame::ErrorInvalidCommand.into(),
+      (_, true) => {
+        transaction.queue(request.map_or(Command::Invalid, Command::from));
+        ResponseFrame::Queued.into()
+      }
+      (_, false) => {
+        let position = snapshot.as_ref().map(|snapshot| snapshot.position);
+        let command = request.map_or(Command::Invalid, Command::from);
+        let behind_app = in_app.is_written(&command);
+        execute(&tx, &storage, &namespace, position, command, behind_app).await
+      }
+    };
+
+    match feedback {
+      PendingResponse::Ready(response) => {
+        framed_stream
+          .write_frame(request_id, response.into())
+          .await
+          .expect("Failed sending message back to client");
+        info!("socket completed");
+      }
+      PendingResponse::Waiting(writes, feedback_rx) => in_app.push(request_id, writes, feedback_rx),
+    }
+  }
+}
+
+// Requests read ahead of their responses. Beyond this the connection stops reading until the app
+// catches up.
+const MAX_PENDING_RESPONSES: usize = 1024;
+
+// The response of a request, answered right away or still at the app.
+enum PendingResponse {
+  Ready(ResponseFrame),
+  Waiting(Writes, oneshot::Receiver<ResponseFrame>),
+}
+
+impl PendingResponse {
+  async fn resolve(self) -> ResponseFrame {
+    match self {
+      PendingResponse::Ready(response) => response,
+      PendingResponse::Waiting(_, feedback_rx) => {
+        feedback_rx.await.expect("Failed getting process feedback")
+      }
+    }
+  }
+}
+
+impl From<ResponseFrame> for PendingResponse {
+  fn from(response: ResponseFrame) -> Self {
+    PendingResponse::Ready(response)
+  }
+}
 
-    let feedback = feedback_rx.await.expect("Failed getting process feedback");
+// Waits for the app to answer all requests of the connection in flight and sends the answers.
+async fn settle(framed_stream: &mut FramedTcpStream, in_app: &mut InFlight) -> std::io::Result<()> {
+  while !in_app.is_empty() {
+    let (request_id, response) = in_app.next().await;
     framed_stream
-      .write_frame(feedback)
-      .await
-      .expect("Failed sending message back to client");
+      .write_frame(request_id, response.into())
+      .await?;
+  }
+  Ok(())
+}
+
+// The only field of a connection level request, eg. the namespace of a SELECT.
+fn single_field(request: Option<Request>) -> Option<Vec<u8>> {
+  let mut fields = request?.fields;
+  if fields.len() != 1 {
+    return None;
+  }
+  fields.pop()
+}
+
+async fn next_change(subscription: &mut Option<Subscription>) -> (Vec<u32>, Push) {
+  match subscription {
+    Some(subscription) => subscription.next_match().await,
+    None => std::future::pending().await,
+  }
+}
+
+// A read view pinned by SNAPSHOT, released when the connection drops it.
+struct PinnedSnapshot<E: KeyspaceEngine> {
+  storage: Arc<RwLock<Storage<E>>>,
+  position: VersionT,
+}
+
+impl<E: KeyspaceEngine> PinnedSnapshot<E> {
+  fn pin(storage: &Arc<RwLock<Storage<E>>>) -> Self {
+    let position = storage.read().unwrap().pin_snapshot();
+    PinnedSnapshot {
+      storage: storage.clone(),
+      position,
+    }
+  }
+}
+
+impl<E: KeyspaceEngine> Drop for PinnedSnapshot<E> {
+  fn drop(&mut self) {
+    self
+      .storage
+      .write()
+      .unwrap()
+      .release_snapshot(self.position);
+  }
+}
+
+// Commands run on the selected namespace. Explicitly namespaced commands are only accepted on the
+// default one, as namespaces do not nest.
+fn in_selected_namespace(command: Command, namespace: &str) -> Command {
+  match command {
+    Command::Namespaced { .. } if namespace != DEFAULT_NAMESPACE => Command::Invalid,
+    command => command.in_namespace(namespace),
+  }
+}
+
+// Reads are served right here, next to the other connections' reads. Everything else (and reads
+// that would see expired keys not yet swept) goes through the app one by one.
+// Within a snapshot reads go to the pinned view - expiries are part of the event log, so the view
+// is not affected by the sweep.
+// Reads of keys that writes still at the app change go through the app too, to see those writes.
+async fn execute<E: KeyspaceEngine>(
+  tx: &Sender<CommandAndChannel>,
+  storage: &Arc<RwLock<Storage<E>>>,
+  namespace: &str,
+  snapshot: Option<VersionT>,
+  command: Command,
+  behind_app: bool,
+) -> PendingResponse {
+  let command = in_selected_namespace(command, namespace);
+  let command = if command.is_read() && (snapshot.is_some() || !behind_app) {
+    let (namespace, read_command) = command.into_namespaced();
+    let storage = storage.read().unwrap();
+    if let Some(position) = snapshot {
+      return storage.read_at(&namespace, read_command, position).into();
+    }
+    if !storage.has_expired_keys(&namespace, now_ms()) {
+      return storage.read(&namespace, read_command).into();
+    }
+    read_command.in_namespace(&namespace)
+  } else {
+    command
+  };
+
+  submit(tx, command).await
+}
+
+async fn submit(tx: &Sender<CommandAndChannel>, command: Command) -> PendingResponse {
+  let (feedback_tx, feedback_rx): (
+    oneshot::Sender<ResponseFrame>,
+    oneshot::Receiver<ResponseFrame>,
+  ) = oneshot::channel();
+
+  let writes = Writes::of(&command);
+  let command_and_channel = CommandAndChannel::new(command, feedback_tx);
+  tx.send(command_and_channel)
+    .await
+    .unwrap_or_else(|_| panic!("Failed sending input to app channel"));
+
+  PendingResponse::Waiting(writes, feedback_rx)
+}
 
-    info!("socket completed");
+// IDEA: WATCH only needs the version, fetching the whole value is wasteful.
+async fn fetch_version<E: KeyspaceEngine>(
+  tx: &Sender<CommandAndChannel>,
+  storage: &Arc<RwLock<Storage<E>>>,
+  namespace: &str,
+  key: Vec<u8>,
+) -> Option<VersionT> {
+  let response = execute(tx, storage, namespace, None, Command::Get { key }, false);
+  match response.await.resolve().await {
+    ResponseFrame::VersionedValue(version, _) => Some(version),
+    _ => None,
   }
 }
@@ -0,0 +1,289 @@
+use crate::command::Command;
+use crate::storage::{KeyT, KeyspaceEngine, NamespaceT, Storage, DEFAULT_NAMESPACE};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// Subscribers falling further behind than this miss changes, which ends their subscription.
+const CHANGE_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub enum Change {
+  // A write of a key, the value is None if the key was deleted.
+  Key {
+    namespace: NamespaceT,
+    key: KeyT,
+    value: Option<Vec<u8>>,
+  },
+  // All keys of the namespace were removed, of every namespace if it's None (FLUSHALL).
+  Flush {
+    namespace: Option<NamespaceT>,
+  },
+}
+
+// What a subscription has for its connection.
+pub enum Push {
+  Change(Change),
+  // The subscriber fell behind and missed changes, the subscription is over.
+  Lagged,
+}
+
+#[derive(Clone)]
+pub struct Publisher {
+  tx: broadcast::Sender<Change>,
+}
+
+impl Publisher {
+  pub fn new() -> Self {
+    let (tx, _) = broadcast::channel(CHANGE_CHANNEL_CAPACITY);
+    Self { tx }
+  }
+
+  pub fn subscribe(&self) -> Subscription {
+    Subscription {
+      patterns: vec![],
+      rx: self.tx.subscribe(),
+    }
+  }
+
+  // Publishes the changes of an applied (effective) command. Changes the command does not carry the
+  // value of (eg. RENAME) are published with the value the key has in the storage by then.
+  pub fn publish<E: KeyspaceEngine>(&self, cmd: &Command, storage: &Storage<E>) {
+    if self.tx.receiver_count() == 0 {
+      return;
+    }
+
+    let mut changes: Vec<Change> = vec![];
+    collect_changes(DEFAULT_NAMESPACE, cmd, storage, &mut changes);
+
+    for change in changes {
+      // Only fails if all subscribers left in the meantime.
+      let _ = self.tx.send(change);
+    }
+  }
+}
+
+fn collect_changes<E: KeyspaceEngine>(
+  namespace: &str,
+  cmd: &Command,
+  storage: &Storage<E>,
+  changes: &mut Vec<Change>,
+) {
+  let mut push = |key: &KeyT, value: Option<&Vec<u8>>| {
+    changes.push(Change::Key {
+      namespace: namespace.into(),
+      key: key.clone(),
+      value: value.cloned(),
+    })
+  };
+  let stored = |namespace: &str, key: &KeyT| storage.value_bytes(namespace, key);
+
+  match cmd {
+    Command::Set { key, value, .. } => push(key, Some(value)),
+    Command::Delete { key } => push(key, None),
+    Command::MSet { pairs } => pairs.iter().for_each(|(key, value)| push(key, Some(value))),
+    Command::MDel { keys } => keys.iter().for_each(|key| push(key, None)),
+    Command::Rename { src, dst, .. } if src != dst => {
+      push(dst, stored(namespace, dst).as_ref());
+      push(src, None);
+    }
+    Command::Copy { dst, .. } => push(dst, stored(namespace, dst).as_ref()),
+    // Collections are published as their elements length prefixed, see `Value::to_bytes`. An
+    // emptied collection is removed, so it's published as a delete.
+    Command::Append { key, .. }
+    | Command::SetRange { key, .. }
+    | Command::LPush { key, .. }
+    | Command::RPush { key, .. }
+    | Command::LPop { key }
+    | Command::RPop { key }
+    | Command::HSet { key, .. }
+    | Command::HDel { key, .. }
+    | Command::SAdd { key, .. }
+    | Command::SRem { key, .. } => push(key, stored(namespace, key).as_ref()),
+    Command::Move {
+      key,
+      namespace: dst_namespace,
+    } => {
+      push(key, None);
+      changes.push(Change::Key {
+        namespace: dst_namespace.clone(),
+        key: key.clone(),
+        value: stored(dst_namespace, key),
+      });
+    }
+    Command::Batch { commands } => {
+      for cmd in commands {
+        collect_changes(namespace, cmd, storage, changes);
+      }
+    }
+    Command::Namespaced { namespace, command } => {
+      collect_changes(namespace, command, storage, changes)
+    }
+    // Flushes do not name the keys they remove, subscribers are told the whole namespace is gone.
+    Command::FlushNs => changes.push(Change::Flush {
+      namespace: Some(namespace.into()),
+    }),
+    Command::FlushAll => changes.push(Change::Flush { namespace: None }),
+    // Expiry changes leave the value as it is.
+    _ => (),
+  }
+}
+
+// The patterns a connection subscribed to, with the request id of the SUBSCRIBE (changes are pushed
+// with it) and the namespace selected at the time.
+pub struct Subscription {
+  patterns: Vec<(u32, NamespaceT, Vec<u8>)>,
+  rx: broadcast::Receiver<Change>,
+}
+
+impl Subscription {
+  pub fn add(&mut self, request_id: u32, namespace: &str, pattern: &[u8]) {
+    self
+      .patterns
+      .push((request_id, namespace.into(), pattern.to_vec()));
+  }
+
+  // Waits for the next change matching any of the patterns, returns it with the request ids of the
+  // matching ones. Missed changes are pushed to all of them as Lagged, the subscription is over
+  // then - it cannot tell which keys the subscriber has stale.
+  pub async fn next_match(&mut self) -> (Vec<u32>, Push) {
+    loop {
+      match self.rx.recv().await {
+        Ok(change) => {
+          let request_ids = self.matches(&change);
+          if !request_ids.is_empty() {
+            return (request_ids, Push::Change(change));
+          }
+        }
+        Err(RecvError::Lagged(count)) => {
+          warn!("Subscriber missed {} changes", count);
+          let request_ids = self.patterns.iter().map(|(id, _, _)| *id).collect();
+          return (request_ids, Push::Lagged);
+        }
+        Err(RecvError::Closed) => std::future::pending::<()>().await,
+      }
+    }
+  }
+
+  fn matches(&self, change: &Change) -> Vec<u32> {
+    self
+      .patterns
+      .iter()
+      .filter(|(_, namespace, pattern)| match change {
+        Change::Key {
+          namespace: changed,
+          key,
+          ..
+        } => namespace == changed && glob_match(pattern, key),
+        Change::Flush { namespace: flushed } => {
+          flushed.as_ref().is_none_or(|flushed| namespace == flushed)
+        }
+      })
+      .map(|(request_id, _, _)| *request_id)
+      .collect()
+  }
+}
+
+// Glob matching where `*` matches any sequence and `?` any single byte. Iterative, on a mismatch
+// it only backtracks to the last `*` (having it match one more byte), so it runs in
+// O(pattern * key) time and constant stack even for huge keys.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+  let mut p = 0;
+  let mut k = 0;
+  // Position of the last `*` seen and of the key byte it was matched up to.
+  let mut last_star: Option<(usize, usize)> = None;
+
+  while k < key.len() {
+    match pattern.get(p) {
+      Some(b'*') => {
+        last_star = Some((p, k));
+        p += 1;
+      }
+      Some(b'?') => {
+        p += 1;
+        k += 1;
+      }
+      Some(byte) if *byte == key[k] => {
+        p += 1;
+        k += 1;
+      }
+      _ => match last_star {
+        Some((star_p, star_k)) => {
+          last_star = Some((star_p, star_k + 1));
+          p = star_p + 1;
+          k = star_k + 1;
+        }
+        None => return false,
+      },
+    }
+  }
+
+  pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key_change(namespace: &str, key: &str) -> Change {
+    Change::Key {
+      namespace: namespace.into(),
+      key: key.into(),
+      value: None,
+    }
+  }
+
+  #[test]
+  fn flushes_match_the_subscriptions_of_the_namespace() {
+    let mut subscription = Publisher::new().subscribe();
+    subscription.add(1, "a", b"user:*");
+    subscription.add(2, "b", b"*");
+
+    assert_eq!(vec![1], subscription.matches(&key_change("a", "user:1")));
+    assert!(subscription.matches(&key_change("a", "order:1")).is_empty());
+    let flush = Change::Flush {
+      namespace: Some("b".into()),
+    };
+    assert_eq!(vec![2], subscription.matches(&flush));
+    let flush_all = Change::Flush { namespace: None };
+    assert_eq!(vec![1, 2], subscription.matches(&flush_all));
+  }
+
+  #[tokio::test]
+  async fn lagging_subscription_is_told() {
+    let publisher = Publisher::new();
+    let mut subscription = publisher.subscribe();
+    subscription.add(1, "a", b"user:*");
+    subscription.add(2, "a", b"order:*");
+
+    for _ in 0..=CHANGE_CHANNEL_CAPACITY {
+      publisher.tx.send(key_change("a", "user:1")).unwrap();
+    }
+    let (request_ids, push) = subscription.next_match().await;
+    assert_eq!(vec![1, 2], request_ids);
+    assert!(matches!(push, Push::Lagged));
+  }
+
+  #[test]
+  fn glob_match_works() {
+    assert!(glob_match(b"", b""));
+    assert!(glob_match(b"*", b""));
+    assert!(glob_match(b"user:*", b"user:1"));
+    assert!(glob_match(b"user:?", b"user:1"));
+    assert!(glob_match(b"*:*:end", b"a:b:c:end"));
+    assert!(glob_match(b"a*b*c", b"aXXbYYbc"));
+    assert!(!glob_match(b"user:?", b"user:"));
+    assert!(!glob_match(b"user:*", b"users"));
+    assert!(!glob_match(b"a*b", b"aXXbY"));
+    assert!(!glob_match(b"", b"a"));
+  }
+
+  #[test]
+  fn glob_match_long_key() {
+    let mut key = vec![b'a'; 4 * 1024 * 1024];
+    assert!(!glob_match(b"*b", &key));
+    assert!(!glob_match(b"*a*a*a*b", &key));
+
+    key.push(b'b');
+    assert!(glob_match(b"*b", &key));
+    assert!(glob_match(b"a*a?b", &key));
+  }
+}
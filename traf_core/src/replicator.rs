@@ -1,14 +1,18 @@
 use crate::command::Command;
-use crate::storage::Storage;
+use crate::engine::BTreeMapEngine;
+use crate::storage::{Entry, KeyT, Keyspace, NamespaceT, Value, ValueT, DEFAULT_NAMESPACE};
 use crate::Executor;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::mem::size_of;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::spawn;
 use traf_client::Client;
+use traf_lib::length_prefixed;
 use traf_lib::response_frame::ResponseFrame;
 
 type EventPtrT = u64;
@@ -80,53 +84,157 @@ struct SyncChunkList(Vec<SyncChunk>);
 impl TryFrom<Vec<u8>> for SyncChunkList {
   type Error = ();
 
-  fn try_from(mut bytes: Vec<u8>) -> Result<Self, Self::Error> {
+  fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
     let mut chunks: Vec<SyncChunk> = vec![];
-    let ptr_size = size_of::<EventPtrT>();
+    let mut rest = &bytes[..];
 
-    loop {
-      if bytes.len() == 0 {
-        break;
-      }
+    while !rest.is_empty() {
+      let (chunk_number, command_bytes, event_len) = split_event(rest).ok_or(())?;
+      let command = Command::from(command_bytes.to_vec());
 
-      // Missing size bytes.
-      if bytes.len() < ptr_size {
-        return Err(());
-      }
+      chunks.push(SyncChunk::new(command, chunk_number));
+      rest = &rest[event_len..];
+    }
 
-      let size_marker: Vec<u8> = bytes.drain(..ptr_size).collect();
-      let chunk_size: EventPtrT = match size_marker.try_into() {
-        Ok(size_bytes) => EventPtrT::from_be_bytes(size_bytes),
-        Err(_) => return Err(()),
-      };
+    Ok(SyncChunkList(chunks))
+  }
+}
 
-      if bytes.len() < ptr_size {
-        return Err(());
-      }
+const EVENT_HEADER_SIZE: usize = 2 * size_of::<EventPtrT>();
 
-      let chunk_number_marker: Vec<u8> = bytes.drain(..ptr_size).collect();
-      let chunk_number: EventPtrT = match chunk_number_marker.try_into() {
-        Ok(chunk_number_bytes) => EventPtrT::from_be_bytes(chunk_number_bytes),
-        Err(_) => return Err(()),
-      };
+// Splits the first event off event log bytes. An event is `<size><number><command bytes>`, size
+// and number being big endian. Returns the number, the command bytes and the size of the event,
+// None if the bytes are cut short.
+fn split_event(bytes: &[u8]) -> Option<(EventPtrT, &[u8], usize)> {
+  let (size, number) = decode_event_header(bytes.get(..EVENT_HEADER_SIZE)?);
+  let event_len = EVENT_HEADER_SIZE + size as usize;
+
+  Some((number, bytes.get(EVENT_HEADER_SIZE..event_len)?, event_len))
+}
+
+fn decode_event_header(header: &[u8]) -> (EventPtrT, EventPtrT) {
+  let (size, number) = header.split_at(size_of::<EventPtrT>());
+  (
+    EventPtrT::from_be_bytes(size.try_into().unwrap()),
+    EventPtrT::from_be_bytes(number.try_into().unwrap()),
+  )
+}
+
+// Splits the first record off key index bytes, None if the bytes are cut short.
+fn split_key_index_record(bytes: &[u8]) -> Option<(EventPtrT, NamespaceT, KeyT, &[u8])> {
+  let offset = EventPtrT::from_be_bytes(bytes.get(..size_of::<EventPtrT>())?.try_into().ok()?);
+  let (namespace, rest) = length_prefixed::split_first(&bytes[size_of::<EventPtrT>()..])?;
+  let (key, rest) = length_prefixed::split_first(rest)?;
 
-      if bytes.len() < chunk_size as usize {
-        return Err(());
+  Some((offset, String::from_utf8(namespace).ok()?, key, rest))
+}
+
+#[derive(Clone, Copy)]
+enum KeyWrite<'a> {
+  Set(&'a ValueT),
+  Delete,
+  // Takes the value another key of the namespace has at that point (RENAME, COPY).
+  CopyOf(&'a KeyT),
+  // Takes the value the key has in another namespace at that point (MOVE).
+  MoveFrom(&'a str),
+  // Changes the value the key had (eg. APPEND, LPUSH).
+  Change(&'a Command),
+}
+
+// The keys a write applies to.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+  Key(&'a str, &'a KeyT),
+  // All keys of the namespace (FLUSHNS).
+  Namespace(&'a str),
+  // All keys of all namespaces (FLUSHALL).
+  All,
+}
+
+impl Target<'_> {
+  fn hits(&self, namespace: &str, key: &KeyT) -> bool {
+    match self {
+      Target::Key(target_namespace, target_key) => {
+        *target_namespace == namespace && *target_key == key
       }
+      Target::Namespace(target_namespace) => *target_namespace == namespace,
+      Target::All => true,
+    }
+  }
 
-      let command_bytes: Vec<u8> = bytes.drain(..chunk_size as usize).collect();
-      let command = Command::from(command_bytes);
+  // The namespace and key fields of its key index records, see `KeyIndex`.
+  fn record(&self) -> (&str, &[u8]) {
+    match self {
+      Target::Key(namespace, key) => (namespace, &key[..]),
+      Target::Namespace(namespace) => ("", namespace.as_bytes()),
+      Target::All => ("", &[]),
+    }
+  }
+}
 
-      chunks.push(SyncChunk::new(command, chunk_number));
+// The writes of an event, in the order they are applied.
+fn key_writes(cmd: &Command) -> Vec<(Target<'_>, KeyWrite<'_>)> {
+  fn writes<'a>(namespace: &'a str, cmd: &'a Command) -> Vec<(Target<'a>, KeyWrite<'a>)> {
+    let key = |key| Target::Key(namespace, key);
+    match cmd {
+      Command::Set { key: k, value, .. } => vec![(key(k), KeyWrite::Set(value))],
+      Command::Delete { key: k } => vec![(key(k), KeyWrite::Delete)],
+      Command::MSet { pairs } => pairs
+        .iter()
+        .map(|(k, value)| (key(k), KeyWrite::Set(value)))
+        .collect(),
+      Command::MDel { keys } => keys.iter().map(|k| (key(k), KeyWrite::Delete)).collect(),
+      // Renaming a key to itself changes nothing.
+      Command::Rename { src, dst, .. } if src != dst => {
+        vec![
+          (key(dst), KeyWrite::CopyOf(src)),
+          (key(src), KeyWrite::Delete),
+        ]
+      }
+      Command::Copy { src, dst } => vec![(key(dst), KeyWrite::CopyOf(src))],
+      Command::Append { key: k, .. }
+      | Command::SetRange { key: k, .. }
+      | Command::LPush { key: k, .. }
+      | Command::RPush { key: k, .. }
+      | Command::LPop { key: k }
+      | Command::RPop { key: k }
+      | Command::HSet { key: k, .. }
+      | Command::HDel { key: k, .. }
+      | Command::SAdd { key: k, .. }
+      | Command::SRem { key: k, .. } => vec![(key(k), KeyWrite::Change(cmd))],
+      Command::FlushNs => vec![(Target::Namespace(namespace), KeyWrite::Delete)],
+      Command::FlushAll => vec![(Target::All, KeyWrite::Delete)],
+      Command::Batch { commands } => commands
+        .iter()
+        .flat_map(|cmd| writes(namespace, cmd))
+        .collect(),
+      _ => vec![],
     }
+  }
 
-    Ok(SyncChunkList(chunks))
+  let (namespace, cmd) = match cmd {
+    Command::Namespaced { namespace, command } => (&namespace[..], &**command),
+    cmd => (DEFAULT_NAMESPACE, cmd),
+  };
+  if let Command::Move {
+    key,
+    namespace: dst_namespace,
+  } = cmd
+  {
+    return vec![
+      (
+        Target::Key(dst_namespace, key),
+        KeyWrite::MoveFrom(namespace),
+      ),
+      (Target::Key(namespace, key), KeyWrite::Delete),
+    ];
   }
+  writes(namespace, cmd)
 }
 
 pub struct RestoreResult {
   pub last_event_id: Option<EventPtrT>,
-  pub applied_commands: Vec<Command>,
+  pub applied_commands: Vec<(EventPtrT, Command)>,
   pub response: ResponseFrame,
 }
 
@@ -140,10 +248,82 @@ impl RestoreResult {
   }
 }
 
+// Offsets of the events that wrote a key and of the flushes, in event order. Kept next to the
+// event log as `[8 bytes: event offset][namespace and key length prefixed]` records. Flushes are
+// recorded with an empty namespace (never a valid one) and the flushed namespace as key, an empty
+// one for FLUSHALL.
+#[derive(Default)]
+struct KeyIndex {
+  keys: HashMap<(NamespaceT, KeyT), Vec<EventPtrT>>,
+  // By flushed namespace, None for FLUSHALL.
+  flushes: HashMap<Option<NamespaceT>, Vec<EventPtrT>>,
+}
+
+impl KeyIndex {
+  // Adds the event of a record, false if it's indexed already (a batch can write a key twice).
+  fn add(&mut self, namespace: &str, key: &[u8], offset: EventPtrT) -> bool {
+    let offsets = if namespace.is_empty() {
+      let flushed = Some(key)
+        .filter(|key| !key.is_empty())
+        .map(|key| String::from_utf8_lossy(key).into_owned());
+      self.flushes.entry(flushed).or_default()
+    } else {
+      self
+        .keys
+        .entry((namespace.into(), key.to_vec()))
+        .or_default()
+    };
+    if offsets.last() == Some(&offset) {
+      return false;
+    }
+    offsets.push(offset);
+    true
+  }
+
+  // The events that wrote the key or flushed it away, in event order. Flushes before the first
+  // write of the key, or with no write of it since the previous flush, had nothing of it to remove.
+  fn offsets(&self, namespace: &str, key: &KeyT) -> Vec<EventPtrT> {
+    let writes = match self.keys.get(&(namespace.into(), key.clone())) {
+      Some(writes) => writes,
+      None => return vec![],
+    };
+    let mut flushes: Vec<EventPtrT> = [Some(namespace.to_string()), None]
+      .iter()
+      .filter_map(|flushed| self.flushes.get(flushed))
+      .flatten()
+      .cloned()
+      .collect();
+    flushes.sort_unstable();
+
+    let mut flushes = flushes.into_iter().peekable();
+    let mut offsets: Vec<EventPtrT> = vec![];
+    for write in writes
+      .iter()
+      .cloned()
+      .chain(std::iter::once(EventPtrT::MAX))
+    {
+      let mut is_flushed = offsets.is_empty();
+      // A flush of the same event as a write is found by its position within the event.
+      while let Some(flush) = flushes.next_if(|flush| *flush <= write) {
+        if !is_flushed && flush < write {
+          offsets.push(flush);
+          is_flushed = true;
+        }
+      }
+      if write != EventPtrT::MAX {
+        offsets.push(write);
+      }
+    }
+    offsets
+  }
+}
+
 pub struct Replicator {
   dir: String,
   readers: ReaderList,
   event_log_mutex: Mutex<()>,
+  // Loaded on first use, then kept up to date by `log`. Only the writer has one.
+  key_index: Option<KeyIndex>,
 }
 
 // IDEA: the sync to readers probably better do batches to avoid always being networked.
@@ -154,12 +334,35 @@ impl Replicator {
       dir,
       readers,
       event_log_mutex: Mutex::new(()),
+      key_index: None,
     }
   }
 
   pub async fn log(&mut self, cmd: &Command) {
     match cmd {
-      Command::Set { .. } | Command::Delete { .. } => {
+      Command::Set { .. }
+      | Command::Delete { .. }
+      | Command::Rename { .. }
+      | Command::Copy { .. }
+      | Command::Move { .. }
+      | Command::MSet { .. }
+      | Command::MDel { .. }
+      | Command::Batch { .. }
+      | Command::Expire { .. }
+      | Command::Persist { .. }
+      | Command::Append { .. }
+      | Command::SetRange { .. }
+      | Command::LPush { .. }
+      | Command::RPush { .. }
+      | Command::LPop { .. }
+      | Command::RPop { .. }
+      | Command::HSet { .. }
+      | Command::HDel { .. }
+      | Command::SAdd { .. }
+      | Command::SRem { .. }
+      | Command::FlushNs
+      | Command::FlushAll
+      | Command::Namespaced { .. } => {
         let bytes: Vec<u8> = cmd.clone().try_into().unwrap();
 
         {
@@ -168,11 +371,25 @@ impl Replicator {
             .lock()
             .expect("Failed locking event ops");
 
+          // Loaded before the event is appended, so the index file never misses earlier events.
+          let event_log_file_path = self.event_log_file_path();
+          let key_index_file_path = self.key_index_file_path();
+          if self.key_index.is_none() {
+            self.key_index = Some(Self::load_key_index(
+              &event_log_file_path,
+              &key_index_file_path,
+            ));
+          }
+
           let next_event_number = self.next_event_log_number();
           let pos = self.event_log_file_size().unwrap_or(0);
 
           self.append_event_log(&bytes[..], next_event_number);
           self.append_event_log_pointers(pos);
+          if let Some(key_index) = self.key_index.as_mut() {
+            let records = Self::index_event(key_index, cmd, pos);
+            Self::append_key_index(&key_index_file_path, &records);
+          }
         }
 
         if self.should_sync() {
@@ -259,9 +476,9 @@ impl Replicator {
 
   // Returns the last applied event ID.
   // FIXME: Pass the current app latest event ID and only apply the missing ones.
-  pub fn restore(
+  pub fn restore<E: Executor>(
     &self,
-    storage: Arc<Mutex<Storage>>,
+    storage: Arc<RwLock<E>>,
     dump: Vec<u8>,
     current_committed_event_id: Option<EventPtrT>,
   ) -> RestoreResult {
@@ -278,18 +495,22 @@ impl Replicator {
           }
 
           match storage
-            .lock()
+            .write()
             .expect("Failed gaining storage lock")
-            .execute(chunk.command.clone())
+            .execute(chunk.command.clone(), chunk.number)
           {
-            ResponseFrame::Success => {
+            // Byte-range writes answer with the new length.
+            ResponseFrame::Success | ResponseFrame::Value(_) => {
               result.last_event_id = Some(chunk.number);
-              result.applied_commands.push(chunk.command);
+              result.applied_commands.push((chunk.number, chunk.command));
               Ok(())
             }
-            ResponseFrame::ValueMissing => Ok(()),
-            _ => {
-              result.response = ResponseFrame::ErrorInvalidCommand;
+            ResponseFrame::ValueMissing | ResponseFrame::ErrorNotFound => Ok(()),
+            response => {
+              result.response = ResponseFrame::ErrorInternal(format!(
+                "Failed applying event {}: {:?}",
+                chunk.number, response
+              ));
               Err(())
             }
           }
@@ -297,13 +518,258 @@ impl Replicator {
       }
       Err(_) => {
         error!("Failed decoding commands from chunk bytes");
-        result.response = ResponseFrame::ErrorInvalidCommand;
+        result.response = ResponseFrame::ErrorMalformed;
       }
     };
 
     result
   }
 
+  // The past writes of the key, newest first: the event numbers and the values written (None for
+  // deletes and flushes). Collections read as their elements length prefixed, see
+  // `Value::to_bytes`. Only the events of the key are read, found by the key index. Fails if the
+  // event log cannot be read where the index points.
+  pub fn history(
+    &mut self,
+    namespace: &str,
+    key: &KeyT,
+    limit: Option<usize>,
+  ) -> Result<Vec<(EventPtrT, Option<ValueT>)>, String> {
+    let _event_mutex = self
+      .event_log_mutex
+      .lock()
+      .expect("Failed locking event ops");
+
+    let event_log_file_path = self.event_log_file_path();
+    let key_index_file_path = self.key_index_file_path();
+    let key_index = self
+      .key_index
+      .get_or_insert_with(|| Self::load_key_index(&event_log_file_path, &key_index_file_path));
+    let offsets = key_index.offsets(namespace, key);
+    let offsets = &offsets[offsets.len().saturating_sub(limit.unwrap_or(usize::MAX))..];
+    if offsets.is_empty() {
+      return Ok(vec![]);
+    }
+
+    let mut event_log_file = OpenOptions::new()
+      .read(true)
+      .open(&event_log_file_path)
+      .map_err(|err| format!("Cannot open event log file: {}", err))?;
+
+    // Oldest first, so changes are replayed on the value of the previous event.
+    let mut previous: Option<(EventPtrT, Option<Value>)> = None;
+    let mut events: Vec<(EventPtrT, Option<ValueT>)> = offsets
+      .iter()
+      .map(|offset| {
+        let (number, _, _) = Self::read_indexed_event(&mut event_log_file, *offset)?;
+        let value = Self::written_value(
+          key_index,
+          &mut event_log_file,
+          previous.take(),
+          namespace,
+          key,
+          *offset,
+        )?;
+        let bytes = value.as_ref().map(Value::to_bytes);
+        previous = Some((*offset, value));
+        Ok((number, bytes))
+      })
+      .collect::<Result<_, String>>()?;
+    events.reverse();
+    Ok(events)
+  }
+
+  // The value of the key after the event at `offset`. Within a batch the last write of the key is
+  // what stayed. Renames, copies and moves are followed back to the key that carried the value,
+  // changes (eg. APPEND) back to the value they changed and then replayed on it, reading earlier
+  // events as needed. `known` is the value of the key after an earlier event, if already found.
+  fn written_value(
+    key_index: &KeyIndex,
+    event_log_file: &mut fs::File,
+    known: Option<(EventPtrT, Option<Value>)>,
+    namespace: &str,
+    key: &KeyT,
+    offset: EventPtrT,
+  ) -> Result<Option<Value>, String> {
+    let mut changes: Vec<Command> = vec![];
+    let mut known = known;
+    let (mut write_namespace, mut write_key, mut write_offset) =
+      (namespace.to_string(), key.clone(), offset);
+    // Only the writes of the event before this many are looked at, all if None.
+    let mut count: Option<usize> = None;
+
+    let value = loop {
+      if count.is_none() && write_namespace == namespace && &write_key == key {
+        if let Some((_, value)) = known.take_if(|(known_offset, _)| *known_offset == write_offset) {
+          break value;
+        }
+      }
+
+      let (_, cmd, _) = Self::read_indexed_event(event_log_file, write_offset)?;
+      let writes = key_writes(&cmd);
+      let pos = writes[..count.unwrap_or(writes.len())]
+        .iter()
+        .rposition(|(target, _)| target.hits(&write_namespace, &write_key));
+
+      match pos.map(|pos| (pos, writes[pos].1)) {
+        Some((_, KeyWrite::Set(value))) => break Some(Value::Bytes(value.clone())),
+        Some((_, KeyWrite::Delete)) => break None,
+        Some((pos, KeyWrite::CopyOf(src))) => {
+          write_key = src.clone();
+          count = Some(pos);
+        }
+        Some((pos, KeyWrite::MoveFrom(src_namespace))) => {
+          write_namespace = src_namespace.into();
+          count = Some(pos);
+        }
+        Some((pos, KeyWrite::Change(change))) => {
+          changes.push(change.clone());
+          count = Some(pos);
+        }
+        None => {
+          let previous = key_index
+            .offsets(&write_namespace, &write_key)
+            .into_iter()
+            .rev()
+            .find(|previous| *previous < write_offset);
+          match previous {
+            Some(previous) => {
+              write_offset = previous;
+              count = None;
+            }
+            None => break None,
+          }
+        }
+      }
+    };
+
+    Ok(changes.iter().rev().fold(value, Self::replay))
+  }
+
+  // The value after applying the change on it, replayed on a keyspace of its own.
+  fn replay(value: Option<Value>, change: &Command) -> Option<Value> {
+    let key = change.keys()[0].clone();
+    let mut keyspace = Keyspace::<BTreeMapEngine<Entry>>::new();
+    if let Some(value) = value {
+      keyspace.set_value(key.clone(), value, None, 0);
+    }
+    keyspace.execute(change.clone(), 0);
+    keyspace.value(&key).cloned()
+  }
+
+  // Reads the key index file, dropping a record cut short by a crash, then indexes the events
+  // logged since the last indexed one - all of them for event logs that predate the index file.
+  fn load_key_index(event_log_file_path: &Path, key_index_file_path: &Path) -> KeyIndex {
+    let mut key_index_file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(key_index_file_path)
+      .expect("Cannot open key index file");
+
+    let mut bytes: Vec<u8> = vec![];
+    key_index_file
+      .read_to_end(&mut bytes)
+      .expect("Failed reading key index file");
+
+    let mut key_index = KeyIndex::default();
+    let mut last_indexed: Option<EventPtrT> = None;
+    let mut rest = &bytes[..];
+    while let Some((offset, namespace, key, record_rest)) = split_key_index_record(rest) {
+      key_index.add(&namespace, &key, offset);
+      last_indexed = Some(offset);
+      rest = record_rest;
+    }
+    if !rest.is_empty() {
+      key_index_file
+        .set_len((bytes.len() - rest.len()) as u64)
+        .expect("Failed truncating key index file");
+    }
+
+    let mut event_log_file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(event_log_file_path)
+      .expect("Cannot open event log file for read");
+
+    // The last indexed event is indexed again, its records may have been cut short.
+    let mut pos = last_indexed.unwrap_or(0);
+    let mut records: Vec<u8> = vec![];
+    while let Some((_, cmd, event_len)) = Self::read_event(&mut event_log_file, pos) {
+      records.extend(Self::index_event(&mut key_index, &cmd, pos));
+      pos += event_len;
+    }
+    Self::append_key_index(key_index_file_path, &records);
+
+    info!("Loaded the key index of {} keys", key_index.keys.len());
+    key_index
+  }
+
+  // Returns the key index records of the writes not indexed yet.
+  fn index_event(key_index: &mut KeyIndex, cmd: &Command, pos: EventPtrT) -> Vec<u8> {
+    let mut records: Vec<u8> = vec![];
+    for (target, _) in key_writes(cmd) {
+      let (namespace, key) = target.record();
+      if key_index.add(namespace, key, pos) {
+        records.extend_from_slice(&pos.to_be_bytes());
+        records.extend(length_prefixed::encode(&[namespace.as_bytes(), key]));
+      }
+    }
+    records
+  }
+
+  fn append_key_index(key_index_file_path: &Path, records: &[u8]) {
+    if records.is_empty() {
+      return;
+    }
+
+    OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(key_index_file_path)
+      .and_then(|mut key_index_file| key_index_file.write_all(records))
+      .expect("Cannot write key index");
+  }
+
+  // The number, command and size of the event at the offset, None past the end of the log or if
+  // the event is cut short.
+  fn read_event(
+    event_log_file: &mut fs::File,
+    offset: EventPtrT,
+  ) -> Option<(EventPtrT, Command, EventPtrT)> {
+    let mut header = [0u8; EVENT_HEADER_SIZE];
+    event_log_file
+      .seek(SeekFrom::Start(offset))
+      .and_then(|_| event_log_file.read_exact(&mut header))
+      .ok()?;
+
+    let (size, number) = decode_event_header(&header);
+    let mut command_bytes = vec![0u8; size as usize];
+    event_log_file.read_exact(&mut command_bytes).ok()?;
+
+    Some((
+      number,
+      Command::from(command_bytes),
+      EVENT_HEADER_SIZE as EventPtrT + size,
+    ))
+  }
+
+  // An event the key index points to, it's an error if it cannot be read.
+  fn read_indexed_event(
+    event_log_file: &mut fs::File,
+    offset: EventPtrT,
+  ) -> Result<(EventPtrT, Command, EventPtrT), String> {
+    Self::read_event(event_log_file, offset).ok_or_else(|| {
+      format!(
+        "Failed reading the event at offset {} of the event log",
+        offset
+      )
+    })
+  }
+
   fn should_sync(&self) -> bool {
     // IDEA: figure out some reasonable frequency/rule for replication.
     true
@@ -399,15 +865,60 @@ impl Replicator {
     Path::new(&self.dir).join("__traf_replicator_event_log.db")
   }
 
+  fn key_index_file_path(&self) -> PathBuf {
+    Path::new(&self.dir).join("__traf_replicator_key_index.db")
+  }
+
   fn event_log_file_size(&self) -> Option<u64> {
     fs::metadata(self.event_log_file_path())
       .map(|metadata| Some(metadata.len()))
       .unwrap_or(None)
   }
 
-  fn next_event_log_number(&self) -> EventPtrT {
+  pub fn next_event_log_number(&self) -> EventPtrT {
     fs::metadata(self.event_log_pointers_file_path())
       .map(|metadata| metadata.len() / size_of::<EventPtrT>() as EventPtrT)
       .unwrap_or(0)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn key_offsets_merge_the_flushes_that_removed_the_key() {
+    let mut key_index = KeyIndex::default();
+    let key: KeyT = "key".into();
+    // A flush before the first write had nothing to remove.
+    key_index.add("", b"ns", 0);
+    key_index.add("ns", &key, 10);
+    key_index.add("", b"ns", 20);
+    // The key was not written since the previous flush.
+    key_index.add("", b"", 30);
+    key_index.add("ns", &key, 40);
+    // A batch flushing and writing the key is a single event.
+    key_index.add("", b"ns", 50);
+    key_index.add("ns", &key, 50);
+    // Flushes of other namespaces and writes of other keys do not count.
+    key_index.add("", b"other", 60);
+    key_index.add("ns", b"other", 70);
+    key_index.add("", b"", 80);
+
+    assert_eq!(vec![10, 20, 40, 50, 80], key_index.offsets("ns", &key));
+    assert!(key_index.offsets("ns", &"never".into()).is_empty());
+  }
+
+  #[test]
+  fn flushes_hit_the_keys_of_their_namespace() {
+    let key: KeyT = "key".into();
+    let writes = key_writes(&Command::FlushNs);
+    assert!(writes[0].0.hits(DEFAULT_NAMESPACE, &key));
+    assert!(!writes[0].0.hits("other", &key));
+    assert_eq!(("", DEFAULT_NAMESPACE.as_bytes()), writes[0].0.record());
+
+    let writes = key_writes(&Command::FlushAll);
+    assert!(writes[0].0.hits("other", &key));
+    assert_eq!(("", &b""[..]), writes[0].0.record());
+  }
+}
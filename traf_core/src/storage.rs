@@ -1,59 +1,1826 @@
-use traf_lib::response_frame::ResponseFrame;
+use traf_lib::length_prefixed;
+use traf_lib::response_frame::{KeyStat, ResponseFrame};
 
-use crate::{command::Command, Executor};
-use std::collections::HashMap;
+use crate::command::{encode_scan_cursor, Command};
+use crate::engine::StorageEngine;
+use crate::eviction::{EvictionPolicy, EvictionRankT, MemoryLimit};
+use crate::pubsub::glob_match;
+use crate::Executor;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::convert::TryInto;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-type KeyT = String;
-type ValueT = Vec<u8>;
+pub type KeyT = Vec<u8>;
+pub type ValueT = Vec<u8>;
+// Versions are the replication event number of the write that produced the value, so readers
+// applying the same event log end up with the same versions as the writer.
+pub type VersionT = u64;
+pub type NamespaceT = String;
 
-pub struct Storage {
-  data: HashMap<KeyT, ValueT>,
+// Upper bound for values grown via SETRANGE or APPEND, so a single command cannot allocate
+// arbitrary memory.
+const MAX_RANGE_VALUE_SIZE: usize = 512 * 1024 * 1024;
+
+// KEYS stops looking after this many keys, matching or not, so a pattern matching few of many keys
+// cannot hold the storage lock for long either.
+const KEYS_MAX_VISITED: usize = 100_000;
+
+// Rough bookkeeping cost (maps, indexes) of an entry and of a collection element, on top of the
+// bytes they hold. Only used to keep the memory use within the limit.
+const ENTRY_OVERHEAD: usize = 64;
+const ELEMENT_OVERHEAD: usize = 16;
+
+// Logical clock of key accesses, shared by all keyspaces so recency is comparable across namespaces.
+static ACCESS_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+// Commands without a namespace (and connections that never SELECT) use this one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+pub fn now_ms() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("System clock is before unix epoch")
+    .as_millis() as u64
 }
 
-impl Storage {
-  pub fn new() -> Self {
+// Values are plain bytes or one of the collection types. Collections are ordered, so replies (and
+// the backups) are the same on every instance.
+#[derive(Clone)]
+pub enum Value {
+  Bytes(ValueT),
+  List(VecDeque<ValueT>),
+  Hash(BTreeMap<ValueT, ValueT>),
+  Set(BTreeSet<ValueT>),
+}
+
+impl Value {
+  pub fn as_bytes(&self) -> Option<&ValueT> {
+    match self {
+      Value::Bytes(bytes) => Some(bytes),
+      _ => None,
+    }
+  }
+
+  // Bytes as they are, collections as their elements length prefixed (hashes as field, value
+  // pairs), see `traf_lib::length_prefixed`.
+  pub fn to_bytes(&self) -> ValueT {
+    match self {
+      Value::Bytes(bytes) => bytes.clone(),
+      Value::List(list) => length_prefixed::encode(&list.iter().collect::<Vec<_>>()),
+      Value::Hash(hash) => length_prefixed::encode(
+        &hash
+          .iter()
+          .flat_map(|(field, value)| vec![field, value])
+          .collect::<Vec<_>>(),
+      ),
+      Value::Set(set) => length_prefixed::encode(&set.iter().collect::<Vec<_>>()),
+    }
+  }
+
+  fn is_bytes(&self) -> bool {
+    matches!(self, Value::Bytes(_))
+  }
+
+  // Estimated memory use of the value.
+  // IDEA: This is linear on collections and gets recalculated on every change of them.
+  fn size(&self) -> usize {
+    match self {
+      Value::Bytes(bytes) => bytes.len(),
+      Value::List(list) => list
+        .iter()
+        .map(|element| element.len() + ELEMENT_OVERHEAD)
+        .sum(),
+      Value::Hash(hash) => hash
+        .iter()
+        .map(|(field, value)| field.len() + value.len() + ELEMENT_OVERHEAD)
+        .sum(),
+      Value::Set(set) => set
+        .iter()
+        .map(|member| member.len() + ELEMENT_OVERHEAD)
+        .sum(),
+    }
+  }
+
+  // Emptied collections are removed, so they read the same as missing keys.
+  fn is_empty_collection(&self) -> bool {
+    match self {
+      Value::Bytes(_) => false,
+      Value::List(list) => list.is_empty(),
+      Value::Hash(hash) => hash.is_empty(),
+      Value::Set(set) => set.is_empty(),
+    }
+  }
+}
+
+pub struct Entry {
+  value: Value,
+  version: VersionT,
+  size: usize,
+  // Access stats for evictions: the access clock at the last access and the number of accesses.
+  // Stats are atomic (as is `accessed_at`), so reads can record their access under a shared lock.
+  last_access: AtomicU64,
+  hits: AtomicU64,
+  // Metadata for STAT, unix ms. These are local times, readers have the time they applied the
+  // change rather than the writer's.
+  created_at: u64,
+  modified_at: u64,
+  accessed_at: AtomicU64,
+}
+
+impl Entry {
+  fn eviction_rank(&self, policy: EvictionPolicy) -> Option<EvictionRankT> {
+    policy.rank(
+      self.last_access.load(Ordering::Relaxed),
+      self.hits.load(Ordering::Relaxed),
+    )
+  }
+
+  // Marks the entry as changed by the write of `version`.
+  fn stamp(&mut self, version: VersionT) {
+    self.version = version;
+    self.modified_at = now_ms();
+  }
+
+  fn duplicate(&self) -> Entry {
+    Entry {
+      value: self.value.clone(),
+      version: self.version,
+      size: self.size,
+      last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+      hits: AtomicU64::new(self.hits.load(Ordering::Relaxed)),
+      created_at: self.created_at,
+      modified_at: self.modified_at,
+      accessed_at: AtomicU64::new(self.accessed_at.load(Ordering::Relaxed)),
+    }
+  }
+}
+
+// The state of a key before a batch, put back if the batch fails.
+struct SavedKey {
+  key: KeyT,
+  entry: Option<Entry>,
+  expire_at: Option<u64>,
+  history: Option<Vec<(VersionT, Option<Value>)>>,
+}
+
+// Read views pinned by SNAPSHOT, shared by all keyspaces. A view is a position of the event log: it
+// sees the events before it, so its reads stay the same while later events are applied.
+#[derive(Clone, Default)]
+pub struct Snapshots {
+  views: Arc<Mutex<Views>>,
+}
+
+#[derive(Default)]
+struct Views {
+  // The next event to apply - a view taken now sees everything applied so far.
+  position: VersionT,
+  // Pinned positions with the number of snapshots pinning them.
+  pinned: BTreeMap<VersionT, usize>,
+}
+
+impl Snapshots {
+  fn pin(&self) -> VersionT {
+    let mut views = self.views.lock().unwrap();
+    let position = views.position;
+    *views.pinned.entry(position).or_insert(0) += 1;
+    position
+  }
+
+  fn release(&self, position: VersionT) {
+    let mut views = self.views.lock().unwrap();
+    if let Some(count) = views.pinned.get_mut(&position) {
+      *count -= 1;
+      if *count == 0 {
+        views.pinned.remove(&position);
+      }
+    }
+  }
+
+  pub fn advance_to(&self, position: VersionT) {
+    let mut views = self.views.lock().unwrap();
+    views.position = views.position.max(position);
+  }
+
+  fn is_pinned(&self, position: VersionT) -> bool {
+    self.views.lock().unwrap().pinned.contains_key(&position)
+  }
+
+  fn is_any_pinned(&self) -> bool {
+    !self.views.lock().unwrap().pinned.is_empty()
+  }
+
+  // True if a view sees the state written by `from` and replaced by `to`.
+  fn is_pinned_between(&self, from: VersionT, to: VersionT) -> bool {
+    self
+      .views
+      .lock()
+      .unwrap()
+      .pinned
+      .range((Bound::Excluded(from), Bound::Included(to)))
+      .next()
+      .is_some()
+  }
+}
+
+fn tick_access_clock() -> u64 {
+  ACCESS_CLOCK.fetch_add(1, Ordering::Relaxed)
+}
+
+fn entry_size(key: &KeyT, value: &Value) -> usize {
+  ENTRY_OVERHEAD + key.len() + value.size()
+}
+
+// The engines keyspaces can keep their entries in, see `EngineKind`.
+pub trait KeyspaceEngine: StorageEngine<Entry> + Default + 'static {}
+
+impl<E: StorageEngine<Entry> + Default + 'static> KeyspaceEngine for E {}
+
+// Namespaces are separate keyspaces - the same key can hold different values in each.
+pub struct Storage<E: KeyspaceEngine> {
+  keyspaces: HashMap<NamespaceT, Keyspace<E>>,
+  memory_limit: Option<MemoryLimit>,
+  snapshots: Snapshots,
+}
+
+impl<E: KeyspaceEngine> Storage<E> {
+  pub fn new(memory_limit: Option<MemoryLimit>) -> Self {
     Storage {
-      data: Default::default(),
+      keyspaces: Default::default(),
+      memory_limit,
+      snapshots: Default::default(),
+    }
+  }
+
+  // Namespaces come to existence on first use.
+  pub fn keyspace_mut(&mut self, namespace: &str) -> &mut Keyspace<E> {
+    let policy = self.eviction_policy();
+    let snapshots = self.snapshots.clone();
+    self
+      .keyspaces
+      .entry(namespace.into())
+      .or_insert_with(|| Keyspace::with_policy(policy, snapshots))
+  }
+
+  pub fn snapshots(&self) -> &Snapshots {
+    &self.snapshots
+  }
+
+  // Pins a read view of everything applied so far. Taken under the storage lock, so no write is
+  // halfway applied.
+  pub fn pin_snapshot(&self) -> VersionT {
+    self.snapshots.pin()
+  }
+
+  // Unpins the view, collecting the versions only it was keeping.
+  pub fn release_snapshot(&mut self, position: VersionT) {
+    self.snapshots.release(position);
+    if !self.snapshots.is_pinned(position) {
+      for keyspace in self.keyspaces.values_mut() {
+        keyspace.collect_versions();
+      }
+    }
+  }
+
+  fn eviction_policy(&self) -> EvictionPolicy {
+    self
+      .memory_limit
+      .map(|limit| limit.policy)
+      .unwrap_or(EvictionPolicy::NoEviction)
+  }
+
+  pub fn used_memory(&self) -> usize {
+    self
+      .keyspaces
+      .values()
+      .map(|keyspace| keyspace.used_memory)
+      .sum()
+  }
+
+  pub fn is_over_memory_limit(&self) -> bool {
+    match self.memory_limit {
+      Some(limit) => self.used_memory() > limit.max_bytes,
+      None => false,
+    }
+  }
+
+  // The next key to evict according to the policy, None if there is nothing the policy allows
+  // to evict.
+  pub fn eviction_candidate(&self) -> Option<(NamespaceT, KeyT)> {
+    self
+      .keyspaces
+      .iter()
+      .filter_map(|(namespace, keyspace)| {
+        keyspace
+          .eviction_candidate()
+          .map(|(rank, key)| (rank, namespace, key))
+      })
+      .min()
+      .map(|(_, namespace, key)| (namespace.clone(), key))
+  }
+
+  // Runs a read command on the namespace. Namespaces never written to read as empty ones.
+  pub fn read(&self, namespace: &str, command: Command) -> ResponseFrame {
+    match self.keyspaces.get(namespace) {
+      Some(keyspace) => keyspace.read(command),
+      None => self.empty_keyspace().read(command),
     }
   }
 
-  pub fn set(&mut self, key: KeyT, value: ValueT) {
-    self.data.insert(key, value);
+  // Runs a read command on the view pinned at `position`.
+  pub fn read_at(&self, namespace: &str, command: Command, position: VersionT) -> ResponseFrame {
+    match self.keyspaces.get(namespace) {
+      Some(keyspace) => keyspace.read_at(command, position),
+      None => self.empty_keyspace().read_at(command, position),
+    }
+  }
+
+  fn empty_keyspace(&self) -> Keyspace<E> {
+    Keyspace::with_policy(EvictionPolicy::NoEviction, self.snapshots.clone())
+  }
+
+  // The value of the key as bytes, see `Value::to_bytes`.
+  pub fn value_bytes(&self, namespace: &str, key: &KeyT) -> Option<ValueT> {
+    self
+      .keyspaces
+      .get(namespace)?
+      .value(key)
+      .map(Value::to_bytes)
+  }
+
+  // Moves the key with its expiry to another namespace. Fails if the key is there already.
+  fn move_key(
+    &mut self,
+    namespace: &str,
+    key: KeyT,
+    dst_namespace: &str,
+    version: VersionT,
+  ) -> ResponseFrame {
+    let contains_key = |namespace: &str| {
+      self
+        .keyspaces
+        .get(namespace)
+        .is_some_and(|keyspace| keyspace.contains_key(&key))
+    };
+    if !contains_key(namespace) {
+      return ResponseFrame::ErrorNotFound;
+    }
+    if contains_key(dst_namespace) {
+      return ResponseFrame::ErrorKeyExists;
+    }
+
+    let keyspace = self.keyspace_mut(namespace);
+    let expire_at = keyspace.expire_at(&key);
+    match keyspace.take(key.clone(), version) {
+      Some(value) => {
+        self
+          .keyspace_mut(dst_namespace)
+          .set_value(key, value, expire_at, version);
+        ResponseFrame::Success
+      }
+      None => ResponseFrame::ErrorNotFound,
+    }
   }
 
-  pub fn get(&self, key: KeyT) -> Option<&ValueT> {
-    self.data.get(&key)
+  pub fn has_expired_keys(&self, namespace: &str, now: u64) -> bool {
+    self
+      .keyspaces
+      .get(namespace)
+      .is_some_and(|keyspace| keyspace.has_expired_keys(now))
   }
 
-  pub fn delete(&mut self, key: KeyT) -> bool {
-    self.data.remove(&key).is_some()
+  pub fn expired_keys(&self, now: u64) -> Vec<(NamespaceT, KeyT)> {
+    self
+      .keyspaces
+      .iter()
+      .flat_map(|(namespace, keyspace)| {
+        keyspace
+          .expired_keys(now)
+          .into_iter()
+          .map(move |key| (namespace.clone(), key))
+      })
+      .collect()
   }
 }
 
-impl Executor for Storage {
-  fn execute(&mut self, command: Command) -> ResponseFrame {
-    match command {
-      Command::Set { key, value } => {
-        info!("SET {:?} {:?}", key, value);
-        self.set(key, value);
+impl<E: KeyspaceEngine> Executor for Storage<E> {
+  fn execute(&mut self, command: Command, version: VersionT) -> ResponseFrame {
+    let (namespace, command) = command.into_namespaced();
+    let response = match command {
+      Command::FlushAll => {
+        info!("FLUSHALL");
+        for keyspace in self.keyspaces.values_mut() {
+          keyspace.execute(Command::FlushNs, version);
+        }
         ResponseFrame::Success
       }
+      Command::Move {
+        key,
+        namespace: dst_namespace,
+      } => {
+        info!("MOVE {:?} {:?} {:?}", key, namespace, dst_namespace);
+        self.move_key(&namespace, key, &dst_namespace, version)
+      }
+      command => self.keyspace_mut(&namespace).execute(command, version),
+    };
+
+    // Events run through here are logged once applied. Failed ones are not, so their version is
+    // reused and must stay unseen by snapshots.
+    if let ResponseFrame::Success | ResponseFrame::Value(_) = response {
+      self.snapshots.advance_to(version + 1);
+    }
+    response
+  }
+}
+
+pub struct Keyspace<E: KeyspaceEngine> {
+  data: E,
+  // Absolute unix ms deadlines, plus the same ordered by deadline so expired keys are cheap to find.
+  expirations: HashMap<KeyT, u64>,
+  deadlines: BTreeSet<(u64, KeyT)>,
+  // Sum of the entry sizes.
+  used_memory: usize,
+  eviction_policy: EvictionPolicy,
+  // Keys in eviction order, only tracked if the policy evicts by access. Reads update it too, so it
+  // has its own lock.
+  eviction_ranks: Mutex<BTreeSet<(EvictionRankT, KeyT)>>,
+  // Earlier states of keys that pinned snapshots still see, oldest first. A state lasts until the
+  // next one (or the current entry), None is a deletion.
+  // IDEA: Retained states are not part of `used_memory`, a long running snapshot can hold a lot.
+  history: HashMap<KeyT, Vec<(VersionT, Option<Value>)>>,
+  snapshots: Snapshots,
+}
+
+impl<E: KeyspaceEngine> Keyspace<E> {
+  pub fn new() -> Self {
+    Keyspace::with_policy(EvictionPolicy::NoEviction, Default::default())
+  }
+
+  pub fn with_policy(eviction_policy: EvictionPolicy, snapshots: Snapshots) -> Self {
+    Keyspace {
+      data: Default::default(),
+      expirations: Default::default(),
+      deadlines: Default::default(),
+      used_memory: 0,
+      eviction_policy,
+      eviction_ranks: Default::default(),
+      history: Default::default(),
+      snapshots,
+    }
+  }
+
+  // A plain set drops any previous expiry of the key.
+  pub fn set(&mut self, key: KeyT, value: ValueT, expire_at: Option<u64>, version: VersionT) {
+    self.set_value(key, Value::Bytes(value), expire_at, version);
+  }
+
+  pub fn set_value(&mut self, key: KeyT, value: Value, expire_at: Option<u64>, version: VersionT) {
+    self.persist(&key);
+    if let Some(expire_at) = expire_at {
+      self.deadlines.insert((expire_at, key.clone()));
+      self.expirations.insert(key.clone(), expire_at);
+    }
+
+    self.insert_entry(key, value, version);
+  }
+
+  pub fn get_versioned(&self, key: KeyT) -> Option<(VersionT, &Value)> {
+    self
+      .data
+      .get(&key)
+      .map(|entry| (entry.version, &entry.value))
+  }
+
+  pub fn delete(&mut self, key: KeyT, version: VersionT) -> bool {
+    self.take(key, version).is_some()
+  }
+
+  // Deletes the key, returning its value.
+  fn take(&mut self, key: KeyT, version: VersionT) -> Option<Value> {
+    self.persist(&key);
+    let entry = self.data.delete(&key)?;
+    self.forget(&key, &entry);
+    // The value is handed out, so a state that snapshots see is copied.
+    if self.snapshots.is_pinned_between(entry.version, version) {
+      self.retain_version(&key, entry.version, entry.value.clone(), version);
+    }
+    // Ends the retained states, without them missing keys read the same anyway.
+    if let Some(history) = self.history.get_mut(&key) {
+      history.push((version, None));
+    }
+    Some(entry.value)
+  }
+
+  // Moves the value of `src` with its expiry to `dst`, replacing any value of `dst`.
+  pub fn rename(&mut self, src: KeyT, dst: KeyT, version: VersionT) -> bool {
+    if src == dst {
+      return self.contains_key(&src);
+    }
+
+    let expire_at = self.expire_at(&src);
+    match self.take(src, version) {
+      Some(value) => {
+        self.set_value(dst, value, expire_at, version);
+        true
+      }
+      None => false,
+    }
+  }
+
+  // Copies the value of `src` with its expiry to `dst`. COPY only runs it on a free `dst`.
+  pub fn copy(&mut self, src: &KeyT, dst: KeyT, version: VersionT) -> bool {
+    match self.value(src) {
+      Some(value) => {
+        let value = value.clone();
+        self.set_value(dst, value, self.expire_at(src), version);
+        true
+      }
+      None => false,
+    }
+  }
+
+  // The value of the key in the view pinned at `position`. Fails if no snapshot pins it, as the
+  // versions it would see may be collected already.
+  fn get_at(
+    &self,
+    key: &KeyT,
+    position: VersionT,
+  ) -> Result<Option<(VersionT, &Value)>, ResponseFrame> {
+    if !self.snapshots.is_pinned(position) {
+      return Err(ResponseFrame::ErrorVersionUnavailable);
+    }
+
+    if let Some(entry) = self.data.get(key) {
+      if entry.version < position {
+        return Ok(Some((entry.version, &entry.value)));
+      }
+    }
+
+    let state = self.history.get(key).and_then(|history| {
+      history
+        .iter()
+        .rev()
+        .find(|(version, _)| *version < position)
+    });
+    Ok(state.and_then(|(version, value)| value.as_ref().map(|value| (*version, value))))
+  }
+
+  // Keeps the state of the key that the write of `version` replaces, if a snapshot sees it.
+  fn retain_version(
+    &mut self,
+    key: &KeyT,
+    previous_version: VersionT,
+    previous: Value,
+    version: VersionT,
+  ) {
+    if previous_version != version && self.snapshots.is_pinned_between(previous_version, version) {
+      self
+        .history
+        .entry(key.clone())
+        .or_default()
+        .push((previous_version, Some(previous)));
+    }
+  }
+
+  // Drops the retained states that no pinned snapshot sees anymore.
+  fn collect_versions(&mut self) {
+    let data = &self.data;
+    let snapshots = &self.snapshots;
+    self.history.retain(|key, history| {
+      let current_version = data.get(key).map(|entry| entry.version);
+      let mut ends = history
+        .iter()
+        .skip(1)
+        .map(|(version, _)| Some(*version))
+        .chain(std::iter::once(current_version))
+        .collect::<Vec<_>>()
+        .into_iter();
+
+      history.retain(|(version, _)| match ends.next().flatten() {
+        Some(end) => snapshots.is_pinned_between(*version, end),
+        // The deletion of a missing key.
+        None => true,
+      });
+      !matches!(history[..], [] | [(_, None)])
+    });
+  }
+
+  // Returns at most `count` entries with `prefix`, following the key `after` (or from the first one).
+  // The returned cursor is the last key of the page, None if there are no more entries.
+  pub fn scan(
+    &self,
+    after: Option<KeyT>,
+    prefix: &[u8],
+    count: usize,
+  ) -> (Vec<(KeyT, ValueT)>, Option<KeyT>) {
+    let start = match after {
+      Some(after) if after[..] >= *prefix => Bound::Excluded(after),
+      _ => Bound::Included(prefix.to_vec()),
+    };
+
+    let mut keys: Vec<&KeyT> = self
+      .data
+      .scan(start.as_ref(), Bound::Unbounded)
+      .take_while(|key| key.starts_with(prefix))
+      .filter(|key| self.value(key).is_some_and(Value::is_bytes))
+      .take(count.saturating_add(1))
+      .collect();
+
+    let has_more = keys.len() > count;
+    keys.truncate(count);
+
+    let cursor = if has_more {
+      keys.last().map(|key| (*key).clone())
+    } else {
+      None
+    };
+    (self.entries_for(keys), cursor)
+  }
+
+  // The first `limit` keys matching the glob pattern, and whether it stopped with keys left to look
+  // at. Only keys starting with the literal prefix of the pattern are visited, at most
+  // `KEYS_MAX_VISITED` of them.
+  pub fn keys_matching(&self, pattern: &[u8], limit: usize) -> (Vec<KeyT>, bool) {
+    let prefix_len = pattern
+      .iter()
+      .position(|ch| ch == &b'*' || ch == &b'?')
+      .unwrap_or(pattern.len());
+    let prefix = pattern[..prefix_len].to_vec();
+
+    let mut candidates = self
+      .data
+      .scan(Bound::Included(&prefix), Bound::Unbounded)
+      .take_while(|key| key.starts_with(&prefix));
+    let mut keys = vec![];
+    for _ in 0..KEYS_MAX_VISITED {
+      if keys.len() == limit {
+        break;
+      }
+      match candidates.next() {
+        Some(key) if glob_match(pattern, key) => keys.push(key.clone()),
+        Some(_) => (),
+        None => return (keys, false),
+      }
+    }
+    (keys, candidates.next().is_some())
+  }
+
+  // All entries from `start` (inclusive) until `end` (exclusive).
+  pub fn range(&self, start: KeyT, end: KeyT) -> Vec<(KeyT, ValueT)> {
+    if start >= end {
+      return vec![];
+    }
+
+    self.entries_for(
+      self
+        .data
+        .scan(Bound::Included(&start), Bound::Excluded(&end))
+        .collect(),
+    )
+  }
+
+  // IDEA: Scans and ranges only return plain byte values, collections have no flat form yet.
+  fn entries_for(&self, keys: Vec<&KeyT>) -> Vec<(KeyT, ValueT)> {
+    keys
+      .into_iter()
+      .filter_map(|key| Some((key.clone(), self.value(key)?.as_bytes()?.clone())))
+      .collect()
+  }
+
+  pub fn value(&self, key: &KeyT) -> Option<&Value> {
+    self.data.get(key).map(|entry| &entry.value)
+  }
+
+  pub fn len(&self) -> usize {
+    self.data.len()
+  }
+
+  fn contains_key(&self, key: &KeyT) -> bool {
+    self.data.get(key).is_some()
+  }
+
+  pub fn stat(&self, key: &KeyT) -> Option<KeyStat> {
+    self.data.get(key).map(|entry| KeyStat {
+      created_at: entry.created_at,
+      modified_at: entry.modified_at,
+      accessed_at: entry.accessed_at.load(Ordering::Relaxed),
+      version: entry.version,
+      size: entry.value.size() as u64,
+    })
+  }
+
+  // Restores the timestamps of a key, eg. from a backup.
+  pub fn set_timestamps(&mut self, key: &KeyT, created_at: u64, modified_at: u64) {
+    if let Some(entry) = self.data.get_mut(key) {
+      entry.created_at = created_at;
+      entry.modified_at = modified_at;
+      entry.accessed_at = AtomicU64::new(modified_at);
+    }
+  }
+
+  // True if the key holds a value `is_expected` does not accept. Missing keys are of any type.
+  fn is_wrong_type(&self, key: &KeyT, is_expected: fn(&Value) -> bool) -> bool {
+    self
+      .value(key)
+      .map(|value| !is_expected(value))
+      .unwrap_or(false)
+  }
+
+  // Runs `update` on the value of the key, starting from `empty` if the key is missing.
+  // Fails with a type error if `update` does not accept the value (returns None).
+  fn update_collection<T>(
+    &mut self,
+    key: KeyT,
+    version: VersionT,
+    empty: Value,
+    update: impl FnOnce(&mut Value) -> Option<T>,
+  ) -> Result<T, ResponseFrame> {
+    // Updates that leave a missing key empty (eg. popping from a missing list) store nothing.
+    if !self.contains_key(&key) {
+      let mut value = empty;
+      let result = update(&mut value).ok_or(ResponseFrame::ErrorWrongType)?;
+      if !value.is_empty_collection() {
+        self.insert_entry(key, value, version);
+      }
+      return Ok(result);
+    }
+
+    let result = self
+      .update_entry(&key, version, |entry| {
+        let result = update(&mut entry.value)?;
+        entry.stamp(version);
+        Some(result)
+      })
+      .flatten()
+      .ok_or(ResponseFrame::ErrorWrongType)?;
+
+    if self.value(&key).is_some_and(Value::is_empty_collection) {
+      self.delete(key, version);
+    }
+    Ok(result)
+  }
+
+  fn update_list<T>(
+    &mut self,
+    key: KeyT,
+    version: VersionT,
+    update: impl FnOnce(&mut VecDeque<ValueT>) -> T,
+  ) -> Result<T, ResponseFrame> {
+    self.update_collection(
+      key,
+      version,
+      Value::List(Default::default()),
+      |value| match value {
+        Value::List(list) => Some(update(list)),
+        _ => None,
+      },
+    )
+  }
+
+  fn update_hash<T>(
+    &mut self,
+    key: KeyT,
+    version: VersionT,
+    update: impl FnOnce(&mut BTreeMap<ValueT, ValueT>) -> T,
+  ) -> Result<T, ResponseFrame> {
+    self.update_collection(
+      key,
+      version,
+      Value::Hash(Default::default()),
+      |value| match value {
+        Value::Hash(hash) => Some(update(hash)),
+        _ => None,
+      },
+    )
+  }
+
+  fn update_set<T>(
+    &mut self,
+    key: KeyT,
+    version: VersionT,
+    update: impl FnOnce(&mut BTreeSet<ValueT>) -> T,
+  ) -> Result<T, ResponseFrame> {
+    self.update_collection(
+      key,
+      version,
+      Value::Set(Default::default()),
+      |value| match value {
+        Value::Set(set) => Some(update(set)),
+        _ => None,
+      },
+    )
+  }
+
+  // Runs `read` on the value of the key, None if the key is missing.
+  // Fails with a type error if `read` does not accept the value (returns None).
+  fn read_value<T>(
+    &self,
+    key: &KeyT,
+    read: impl FnOnce(&Value) -> Option<T>,
+  ) -> Result<Option<T>, ResponseFrame> {
+    match self.value(key) {
+      Some(value) => read(value).map(Some).ok_or(ResponseFrame::ErrorWrongType),
+      None => Ok(None),
+    }
+  }
+
+  // Inserts or replaces the entry of the key. A replaced entry keeps its access stats and creation
+  // time.
+  fn insert_entry(&mut self, key: KeyT, value: Value, version: VersionT) {
+    let now = now_ms();
+    let (hits, created_at) = match self.data.delete(&key) {
+      Some(previous) => {
+        self.forget(&key, &previous);
+        let hits = previous.hits.into_inner();
+        self.retain_version(&key, previous.version, previous.value, version);
+        (hits, previous.created_at)
+      }
+      None => (0, now),
+    };
+
+    let entry = Entry {
+      size: entry_size(&key, &value),
+      value,
+      version,
+      last_access: AtomicU64::new(tick_access_clock()),
+      hits: AtomicU64::new(hits),
+      created_at,
+      modified_at: now,
+      accessed_at: AtomicU64::new(now),
+    };
+    self.remember(&key, &entry);
+    self.data.set(key, entry);
+  }
+
+  // Changes the entry of the key in place by the write of `version`, None if the key is missing.
+  fn update_entry<T>(
+    &mut self,
+    key: &KeyT,
+    version: VersionT,
+    update: impl FnOnce(&mut Entry) -> T,
+  ) -> Option<T> {
+    let mut entry = self.data.delete(key)?;
+    self.forget(key, &entry);
+
+    // The value is changed in place, so a state that snapshots see is copied beforehand. Updates
+    // that turn out to change nothing leave the entry at its version.
+    let previous = (entry.version != version
+      && self.snapshots.is_pinned_between(entry.version, version))
+    .then(|| (entry.version, entry.value.clone()));
+    let result = update(&mut entry);
+    entry.size = entry_size(key, &entry.value);
+    if entry.version == version {
+      if let Some((previous_version, previous)) = previous {
+        self.retain_version(key, previous_version, previous, version);
+      }
+    }
+
+    self.remember(key, &entry);
+    self.data.set(key.clone(), entry);
+    Some(result)
+  }
+
+  // Accounts for an entry about to be stored.
+  fn remember(&mut self, key: &KeyT, entry: &Entry) {
+    self.used_memory += entry.size;
+    if let Some(rank) = entry.eviction_rank(self.eviction_policy) {
+      self
+        .eviction_ranks
+        .lock()
+        .unwrap()
+        .insert((rank, key.clone()));
+    }
+  }
+
+  // Accounts for an entry removed from the storage.
+  fn forget(&mut self, key: &KeyT, entry: &Entry) {
+    self.used_memory -= entry.size;
+    if let Some(rank) = entry.eviction_rank(self.eviction_policy) {
+      self
+        .eviction_ranks
+        .lock()
+        .unwrap()
+        .remove(&(rank, key.clone()));
+    }
+  }
+
+  // Records an access of the key, for STAT and for the eviction policy.
+  fn touch(&self, key: &KeyT) {
+    let policy = self.eviction_policy;
+    let entry = match self.data.get(key) {
+      Some(entry) => entry,
+      None => return,
+    };
+    entry.accessed_at.store(now_ms(), Ordering::Relaxed);
+
+    // Access stats are only kept if the policy evicts by them. They change under the ranks' lock,
+    // so concurrent reads of the key leave a single rank behind.
+    if policy.evicts_by_access() {
+      let mut eviction_ranks = self.eviction_ranks.lock().unwrap();
+      if let Some(rank) = entry.eviction_rank(policy) {
+        eviction_ranks.remove(&(rank, key.clone()));
+      }
+      entry
+        .last_access
+        .store(tick_access_clock(), Ordering::Relaxed);
+      entry.hits.fetch_add(1, Ordering::Relaxed);
+      if let Some(rank) = entry.eviction_rank(policy) {
+        eviction_ranks.insert((rank, key.clone()));
+      }
+    }
+  }
+
+  // The key to evict first from this keyspace with its rank, None if the policy allows none.
+  fn eviction_candidate(&self) -> Option<(EvictionRankT, KeyT)> {
+    match self.eviction_policy {
+      EvictionPolicy::NoEviction => None,
+      EvictionPolicy::VolatileTtl => self
+        .deadlines
+        .iter()
+        .next()
+        .map(|(expire_at, key)| ((*expire_at, 0), key.clone())),
+      EvictionPolicy::AllKeysLru | EvictionPolicy::AllKeysLfu => self
+        .eviction_ranks
+        .lock()
+        .unwrap()
+        .iter()
+        .next()
+        .map(|(rank, key)| (*rank, key.clone())),
+    }
+  }
+
+  pub fn expire(&mut self, key: KeyT, expire_at: u64) -> bool {
+    if !self.contains_key(&key) {
+      return false;
+    }
+
+    self.persist(&key);
+    self.deadlines.insert((expire_at, key.clone()));
+    self.expirations.insert(key, expire_at);
+    true
+  }
+
+  // Returns true if the key had an expiry.
+  pub fn persist(&mut self, key: &KeyT) -> bool {
+    match self.expirations.remove(key) {
+      Some(expire_at) => self.deadlines.remove(&(expire_at, key.clone())),
+      None => false,
+    }
+  }
+
+  pub fn expire_at(&self, key: &KeyT) -> Option<u64> {
+    self.expirations.get(key).cloned()
+  }
+
+  // The keys the commands may change, with their current state. FLUSHNS changes all of them.
+  fn save_keys(&self, commands: &[Command]) -> Vec<SavedKey> {
+    let mut keys: Vec<KeyT> = commands.iter().flat_map(Command::keys).cloned().collect();
+    if commands.iter().any(|cmd| matches!(cmd, Command::FlushNs)) {
+      keys.extend(self.data.scan(Bound::Unbounded, Bound::Unbounded).cloned());
+    }
+    keys.sort();
+    keys.dedup();
+
+    keys
+      .into_iter()
+      .map(|key| SavedKey {
+        entry: self.data.get(&key).map(Entry::duplicate),
+        expire_at: self.expire_at(&key),
+        history: self.history.get(&key).cloned(),
+        key,
+      })
+      .collect()
+  }
+
+  // Puts the keys back to their saved state, as if the writes since did not happen.
+  fn restore_keys(&mut self, saved: Vec<SavedKey>) {
+    for SavedKey {
+      key,
+      entry,
+      expire_at,
+      history,
+    } in saved
+    {
+      self.persist(&key);
+      if let Some(current) = self.data.delete(&key) {
+        self.forget(&key, &current);
+      }
+      match history {
+        Some(history) => self.history.insert(key.clone(), history),
+        None => self.history.remove(&key),
+      };
+      if let Some(expire_at) = expire_at {
+        self.deadlines.insert((expire_at, key.clone()));
+        self.expirations.insert(key.clone(), expire_at);
+      }
+      if let Some(entry) = entry {
+        self.remember(&key, &entry);
+        self.data.set(key, entry);
+      }
+    }
+  }
+
+  fn has_expired_keys(&self, now: u64) -> bool {
+    self
+      .deadlines
+      .iter()
+      .next()
+      .is_some_and(|(expire_at, _)| *expire_at <= now)
+  }
+
+  pub fn expired_keys(&self, now: u64) -> Vec<KeyT> {
+    self
+      .deadlines
+      .iter()
+      .take_while(|(expire_at, _)| *expire_at <= now)
+      .map(|(_, key)| key.clone())
+      .collect()
+  }
+
+  // Appends to the value (an empty one if the key is missing), keeping its expiry.
+  // Returns the new length, None if the key is not a plain value.
+  pub fn append(&mut self, key: KeyT, bytes: &[u8], version: VersionT) -> Option<usize> {
+    self.set_range(key, None, bytes, version)
+  }
+
+  // Overwrites the value from `offset` (from its end if None), zero padding the gap if the value
+  // is shorter than `offset`. Keeps the expiry of the key. Returns the new length, None if the key
+  // is not a plain value.
+  pub fn set_range(
+    &mut self,
+    key: KeyT,
+    offset: Option<usize>,
+    bytes: &[u8],
+    version: VersionT,
+  ) -> Option<usize> {
+    if !self.contains_key(&key) {
+      self.insert_entry(key.clone(), Value::Bytes(vec![]), version);
+    }
+
+    self
+      .update_entry(&key, version, |entry| {
+        let value = match &mut entry.value {
+          Value::Bytes(value) => value,
+          _ => return None,
+        };
+        let offset = offset.unwrap_or(value.len());
+        let end = offset + bytes.len();
+        if value.len() < end {
+          value.resize(end, 0);
+        }
+        value[offset..end].copy_from_slice(bytes);
+        let len = value.len();
+        entry.stamp(version);
+
+        Some(len)
+      })
+      .flatten()
+  }
+
+  // Bytes between the inclusive positions, negative ones counting from the end (-1 is the last
+  // byte). Positions out of the value are clamped.
+  pub fn get_range(&self, key: &KeyT, start: i64, end: i64) -> Option<ValueT> {
+    let value = self.value(key)?.as_bytes()?;
+    Some(value[resolve_range(value.len(), start, end)].to_vec())
+  }
+
+  // Counters are stored as 8 byte little endian integers - the same layout bincode uses for i64,
+  // so they can be read back with a regular GET and decoded on the client.
+  // Fails with WrongType if the value is not a counter and with Overflow if the result would not
+  // fit an i64.
+  pub fn incr_by(
+    &mut self,
+    key: KeyT,
+    delta: i64,
+    version: VersionT,
+  ) -> Result<i64, ResponseFrame> {
+    let current = match self.data.get(&key) {
+      Some(entry) => match entry.value.as_bytes().map(|bytes| bytes[..].try_into()) {
+        Some(Ok(counter_bytes)) => i64::from_le_bytes(counter_bytes),
+        _ => return Err(ResponseFrame::ErrorWrongType),
+      },
+      None => 0,
+    };
+    let next = current
+      .checked_add(delta)
+      .ok_or(ResponseFrame::ErrorOverflow)?;
+
+    let value = Value::Bytes(Vec::from(next.to_le_bytes()));
+    self.insert_entry(key, value, version);
+    Ok(next)
+  }
+
+  // Runs a read command. Reads only need a shared reference, so they can run concurrently.
+  pub fn read(&self, command: Command) -> ResponseFrame {
+    // Inspecting a key is not an access of it.
+    if !matches!(command, Command::Stat { .. }) {
+      for key in command.keys() {
+        self.touch(key);
+      }
+    }
+
+    match command {
       Command::Get { key } => {
         info!("GET {:?}", key);
-        match self.get(key) {
-          Some(v) => ResponseFrame::Value(v.clone()),
+        match self.get_versioned(key) {
+          Some((version, Value::Bytes(v))) => ResponseFrame::VersionedValue(version, v.clone()),
+          Some(_) => ResponseFrame::ErrorWrongType,
+          None => ResponseFrame::ValueMissing,
+        }
+      }
+      Command::GetAt { key, position } => {
+        info!("GET {:?} AT {:?}", key, position);
+        get_at_response(self.get_at(&key, position))
+      }
+      Command::MGet { keys } => {
+        info!("MGET {:?}", keys);
+        let values = keys
+          .into_iter()
+          .map(|key| self.value(&key).and_then(Value::as_bytes).cloned())
+          .collect();
+        ResponseFrame::Values(values)
+      }
+      Command::Scan {
+        cursor,
+        prefix,
+        count,
+      } => {
+        info!("SCAN {:?} {:?} {:?}", cursor, prefix, count);
+        let (entries, next_cursor) = self.scan(cursor, &prefix, count);
+        ResponseFrame::ScanPage(encode_scan_cursor(next_cursor).into_bytes(), entries)
+      }
+      Command::Range { start, end } => {
+        info!("RANGE {:?} {:?}", start, end);
+        ResponseFrame::KeyValues(self.range(start, end))
+      }
+      Command::GetRange { key, .. } if self.is_wrong_type(&key, Value::is_bytes) => {
+        ResponseFrame::ErrorWrongType
+      }
+      Command::GetRange { key, start, end } => {
+        info!("GETRANGE {:?} {:?} {:?}", key, start, end);
+        match self.get_range(&key, start, end) {
+          Some(bytes) => ResponseFrame::Value(bytes),
+          None => ResponseFrame::ValueMissing,
+        }
+      }
+      Command::StrLen { key } => {
+        info!("STRLEN {:?}", key);
+        match self.value(&key) {
+          Some(Value::Bytes(value)) => length_response(value.len()),
+          Some(_) => ResponseFrame::ErrorWrongType,
           None => ResponseFrame::ValueMissing,
         }
       }
+      Command::LRange { key, start, stop } => {
+        info!("LRANGE {:?} {:?} {:?}", key, start, stop);
+        let result = self.read_value(&key, |value| match value {
+          Value::List(list) => Some(
+            list
+              .range(resolve_range(list.len(), start, stop))
+              .cloned()
+              .collect(),
+          ),
+          _ => None,
+        });
+        result
+          .map(|elements| ResponseFrame::Elements(elements.unwrap_or_default()))
+          .unwrap_or_else(|error| error)
+      }
+      Command::HGet { key, field } => {
+        info!("HGET {:?} {:?}", key, field);
+        let result = self.read_value(&key, |value| match value {
+          Value::Hash(hash) => Some(hash.get(&field).cloned()),
+          _ => None,
+        });
+        result
+          .map(|value| value_response(value.flatten()))
+          .unwrap_or_else(|error| error)
+      }
+      Command::HGetAll { key } => {
+        info!("HGETALL {:?}", key);
+        let result = self.read_value(&key, |value| match value {
+          Value::Hash(hash) => Some(
+            hash
+              .iter()
+              .map(|(field, value)| (field.clone(), value.clone()))
+              .collect(),
+          ),
+          _ => None,
+        });
+        result
+          .map(|pairs| ResponseFrame::KeyValues(pairs.unwrap_or_default()))
+          .unwrap_or_else(|error| error)
+      }
+      Command::SMembers { key } => {
+        info!("SMEMBERS {:?}", key);
+        let result = self.read_value(&key, |value| match value {
+          Value::Set(set) => Some(set.iter().cloned().collect()),
+          _ => None,
+        });
+        result
+          .map(|members| ResponseFrame::Elements(members.unwrap_or_default()))
+          .unwrap_or_else(|error| error)
+      }
+      Command::SIsMember { key, member } => {
+        info!("SISMEMBER {:?} {:?}", key, member);
+        let result = self.read_value(&key, |value| match value {
+          Value::Set(set) => Some(set.contains(&member)),
+          _ => None,
+        });
+        result
+          .map(|is_member| ResponseFrame::Value(vec![is_member.unwrap_or(false) as u8]))
+          .unwrap_or_else(|error| error)
+      }
+      Command::Ttl { key } => {
+        info!("TTL {:?}", key);
+        if !self.contains_key(&key) {
+          return ResponseFrame::ErrorNotFound;
+        }
+
+        // Remaining milliseconds, or -1 when the key never expires.
+        let ttl: i64 = match self.expire_at(&key) {
+          Some(expire_at) => expire_at.saturating_sub(now_ms()) as i64,
+          None => -1,
+        };
+        ResponseFrame::Value(Vec::from(ttl.to_le_bytes()))
+      }
+      Command::Stat { key } => {
+        info!("STAT {:?}", key);
+        match self.stat(&key) {
+          Some(stat) => ResponseFrame::Stat(stat),
+          None => ResponseFrame::ErrorNotFound,
+        }
+      }
+      Command::DbSize => {
+        info!("DBSIZE");
+        length_response(self.len())
+      }
+      Command::Keys { pattern, limit } => {
+        info!("KEYS {:?} {:?}", pattern, limit);
+        let (keys, truncated) = self.keys_matching(&pattern, limit);
+        ResponseFrame::Keys(keys, truncated)
+      }
+      _ => ResponseFrame::ErrorInvalidCommand,
+    }
+  }
+
+  // Runs a read command on the view pinned at `position`. Only point reads can see older versions.
+  // IDEA: Scans, ranges and collection reads could merge the retained states in too. Until then
+  //        they are refused within a snapshot, rather than reading the latest state.
+  pub fn read_at(&self, command: Command, position: VersionT) -> ResponseFrame {
+    match command {
+      Command::Get { key } => {
+        info!("GET {:?} AT {:?}", key, position);
+        get_at_response(self.get_at(&key, position))
+      }
+      Command::MGet { keys } => {
+        info!("MGET {:?} AT {:?}", keys, position);
+        let values: Result<Vec<_>, ResponseFrame> = keys
+          .iter()
+          .map(|key| {
+            let value = self.get_at(key, position)?;
+            Ok(value.and_then(|(_, value)| value.as_bytes()).cloned())
+          })
+          .collect();
+        values
+          .map(ResponseFrame::Values)
+          .unwrap_or_else(|error| error)
+      }
+      command @ Command::GetAt { .. } => self.read(command),
+      _ => ResponseFrame::ErrorInvalidCommand,
+    }
+  }
+}
+
+impl<E: KeyspaceEngine> Executor for Keyspace<E> {
+  fn execute(&mut self, command: Command, version: VersionT) -> ResponseFrame {
+    if command.is_read() {
+      return self.read(command);
+    }
+    for key in command.keys() {
+      self.touch(key);
+    }
+
+    match command {
+      Command::Set {
+        key,
+        value,
+        expire_at,
+      } => {
+        info!("SET {:?} {:?} {:?}", key, value, expire_at);
+        self.set(key, value, expire_at, version);
+        ResponseFrame::Success
+      }
+      Command::Cas {
+        key,
+        expected_version,
+        value,
+      } => {
+        info!("CAS {:?} {:?} {:?}", key, expected_version, value);
+        match self.get_versioned(key.clone()) {
+          Some((current_version, _)) if current_version == expected_version => {
+            self.set(key, value, None, version);
+            ResponseFrame::Success
+          }
+          Some(_) => ResponseFrame::ErrorVersionMismatch,
+          None => ResponseFrame::ErrorNotFound,
+        }
+      }
+      Command::SetNx { key, value } => {
+        info!("SETNX {:?} {:?}", key, value);
+        if self.contains_key(&key) {
+          return ResponseFrame::ErrorKeyExists;
+        }
+        self.set(key, value, None, version);
+        ResponseFrame::Success
+      }
+      Command::SetXx { key, value } => {
+        info!("SETXX {:?} {:?}", key, value);
+        if !self.contains_key(&key) {
+          return ResponseFrame::ErrorNotFound;
+        }
+        self.set(key, value, None, version);
+        ResponseFrame::Success
+      }
+      // Answers with the replaced value, or Success if there was none.
+      Command::GetSet { key, value } => {
+        info!("GETSET {:?} {:?}", key, value);
+        let previous = match self.value(&key) {
+          Some(Value::Bytes(previous)) => Some(previous.clone()),
+          Some(_) => return ResponseFrame::ErrorWrongType,
+          None => None,
+        };
+        self.set(key, value, None, version);
+        previous
+          .map(ResponseFrame::Value)
+          .unwrap_or(ResponseFrame::Success)
+      }
       Command::Delete { key } => {
         info!("DELETE {:?}", key);
-        if self.delete(key) {
+        if self.delete(key, version) {
+          ResponseFrame::Success
+        } else {
+          ResponseFrame::ErrorNotFound
+        }
+      }
+      Command::Rename { src, dst, nx } => {
+        info!("RENAME {:?} {:?} {:?}", src, dst, nx);
+        if !self.contains_key(&src) {
+          return ResponseFrame::ErrorNotFound;
+        }
+        if nx && self.contains_key(&dst) {
+          return ResponseFrame::ErrorKeyExists;
+        }
+        self.rename(src, dst, version);
+        ResponseFrame::Success
+      }
+      Command::Copy { src, dst } => {
+        info!("COPY {:?} {:?}", src, dst);
+        if !self.contains_key(&src) {
+          return ResponseFrame::ErrorNotFound;
+        }
+        if self.contains_key(&dst) {
+          return ResponseFrame::ErrorKeyExists;
+        }
+        self.copy(&src, dst, version);
+        ResponseFrame::Success
+      }
+      // All pairs are set within the single lock held by the caller, so nobody sees a partial MSET.
+      Command::MSet { pairs } => {
+        info!("MSET {:?}", pairs);
+        for (key, value) in pairs {
+          self.set(key, value, None, version);
+        }
+        ResponseFrame::Success
+      }
+      Command::MDel { keys } => {
+        info!("MDEL {:?}", keys);
+        let deleted_count = keys
+          .into_iter()
+          .filter(|key| self.delete(key.clone(), version))
+          .count();
+        if deleted_count > 0 {
+          ResponseFrame::Success
+        } else {
+          ResponseFrame::ErrorNotFound
+        }
+      }
+      // A batch applies all or nothing. Its commands all took effect on the writer, so one failing
+      // here means this keyspace went astray - the keys the batch changed so far are put back and
+      // it fails with an internal error.
+      Command::Batch { commands } => {
+        info!("BATCH {:?} commands", commands.len());
+        let saved = self.save_keys(&commands);
+        for (i, cmd) in commands.into_iter().enumerate() {
+          match self.execute(cmd, version) {
+            ResponseFrame::Success | ResponseFrame::Value(_) => (),
+            failure => {
+              self.restore_keys(saved);
+              return ResponseFrame::ErrorInternal(format!(
+                "Failed applying command {} of the batch: {:?}",
+                i, failure
+              ));
+            }
+          }
+        }
+        ResponseFrame::Success
+      }
+      Command::Incr { key } | Command::Decr { key } | Command::IncrBy { key, .. }
+        if self.is_wrong_type(&key, Value::is_bytes) =>
+      {
+        ResponseFrame::ErrorWrongType
+      }
+      Command::Incr { key } => {
+        info!("INCR {:?}", key);
+        counter_response(self.incr_by(key, 1, version))
+      }
+      Command::Decr { key } => {
+        info!("DECR {:?}", key);
+        counter_response(self.incr_by(key, -1, version))
+      }
+      Command::IncrBy { key, delta } => {
+        info!("INCRBY {:?} {:?}", key, delta);
+        counter_response(self.incr_by(key, delta, version))
+      }
+      Command::Append { key, value } => {
+        info!("APPEND {:?} {:?}", key, value);
+        let len = self
+          .value(&key)
+          .and_then(Value::as_bytes)
+          .map_or(0, Vec::len);
+        if len + value.len() > MAX_RANGE_VALUE_SIZE {
+          return ResponseFrame::ErrorTooLarge;
+        }
+        match self.append(key, &value, version) {
+          Some(len) => length_response(len),
+          None => ResponseFrame::ErrorWrongType,
+        }
+      }
+      Command::SetRange { key, offset, value } => {
+        info!("SETRANGE {:?} {:?} {:?}", key, offset, value);
+        match offset.checked_add(value.len()) {
+          Some(end) if end <= MAX_RANGE_VALUE_SIZE => (),
+          _ => return ResponseFrame::ErrorTooLarge,
+        }
+        match self.set_range(key, Some(offset), &value, version) {
+          Some(len) => length_response(len),
+          None => ResponseFrame::ErrorWrongType,
+        }
+      }
+      Command::LPush { key, elements } => {
+        info!("LPUSH {:?} {:?}", key, elements);
+        let result = self.update_list(key, version, |list| {
+          elements
+            .into_iter()
+            .for_each(|element| list.push_front(element));
+          list.len()
+        });
+        result.map(length_response).unwrap_or_else(|error| error)
+      }
+      Command::RPush { key, elements } => {
+        info!("RPUSH {:?} {:?}", key, elements);
+        let result = self.update_list(key, version, |list| {
+          list.extend(elements);
+          list.len()
+        });
+        result.map(length_response).unwrap_or_else(|error| error)
+      }
+      Command::LPop { key } => {
+        info!("LPOP {:?}", key);
+        let result = self.update_list(key, version, |list| list.pop_front());
+        result.map(value_response).unwrap_or_else(|error| error)
+      }
+      Command::RPop { key } => {
+        info!("RPOP {:?}", key);
+        let result = self.update_list(key, version, |list| list.pop_back());
+        result.map(value_response).unwrap_or_else(|error| error)
+      }
+      Command::HSet { key, pairs } => {
+        info!("HSET {:?} {:?}", key, pairs);
+        let result = self.update_hash(key, version, |hash| {
+          pairs
+            .into_iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count()
+        });
+        result.map(length_response).unwrap_or_else(|error| error)
+      }
+      // Removing nothing is not a write, it's not versioned or logged.
+      Command::HDel { key, fields } => {
+        info!("HDEL {:?} {:?}", key, fields);
+        match self.value(&key) {
+          Some(Value::Hash(hash)) if fields.iter().any(|field| hash.contains_key(field)) => (),
+          Some(Value::Hash(_)) | None => return ResponseFrame::ValueMissing,
+          Some(_) => return ResponseFrame::ErrorWrongType,
+        }
+        let result = self.update_hash(key, version, |hash| {
+          fields
+            .iter()
+            .filter(|field| hash.remove(*field).is_some())
+            .count()
+        });
+        result.map(length_response).unwrap_or_else(|error| error)
+      }
+      Command::SAdd { key, members } => {
+        info!("SADD {:?} {:?}", key, members);
+        let result = self.update_set(key, version, |set| {
+          members
+            .into_iter()
+            .filter(|member| set.insert(member.clone()))
+            .count()
+        });
+        result.map(length_response).unwrap_or_else(|error| error)
+      }
+      Command::SRem { key, members } => {
+        info!("SREM {:?} {:?}", key, members);
+        match self.value(&key) {
+          Some(Value::Set(set)) if members.iter().any(|member| set.contains(member)) => (),
+          Some(Value::Set(_)) | None => return ResponseFrame::ValueMissing,
+          Some(_) => return ResponseFrame::ErrorWrongType,
+        }
+        let result = self.update_set(key, version, |set| {
+          members.iter().filter(|member| set.remove(*member)).count()
+        });
+        result.map(length_response).unwrap_or_else(|error| error)
+      }
+      Command::Expire { key, expire_at } => {
+        info!("EXPIRE {:?} {:?}", key, expire_at);
+        if self.expire(key, expire_at) {
           ResponseFrame::Success
         } else {
-          ResponseFrame::ValueMissing
+          ResponseFrame::ErrorNotFound
         }
       }
+      Command::Persist { key } => {
+        info!("PERSIST {:?}", key);
+        if self.persist(&key) {
+          ResponseFrame::Success
+        } else {
+          ResponseFrame::ErrorNotFound
+        }
+      }
+      Command::FlushNs => {
+        info!("FLUSHNS");
+        if self.snapshots.is_any_pinned() {
+          // Deleted one by one, so snapshots keep seeing the flushed keys.
+          let keys: Vec<KeyT> = self
+            .data
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .cloned()
+            .collect();
+          for key in keys {
+            self.delete(key, version);
+          }
+        } else {
+          *self = Keyspace::with_policy(self.eviction_policy, self.snapshots.clone());
+        }
+        ResponseFrame::Success
+      }
       _ => ResponseFrame::ErrorInvalidCommand,
     }
   }
 }
+
+fn get_at_response(value: Result<Option<(VersionT, &Value)>, ResponseFrame>) -> ResponseFrame {
+  match value {
+    Ok(Some((version, Value::Bytes(v)))) => ResponseFrame::VersionedValue(version, v.clone()),
+    Ok(Some(_)) => ResponseFrame::ErrorWrongType,
+    Ok(None) => ResponseFrame::ValueMissing,
+    Err(error) => error,
+  }
+}
+
+// Range of the inclusive positions in a sequence of `len`, negative positions counting from the
+// end (-1 is the last one). Positions out of the sequence are clamped.
+fn resolve_range(len: usize, start: i64, end: i64) -> std::ops::Range<usize> {
+  let len = len as i64;
+  let resolve = |pos: i64| {
+    if pos < 0 {
+      (len + pos).max(0)
+    } else {
+      pos.min(len)
+    }
+  };
+  let start = resolve(start);
+  let end = (resolve(end) + 1).min(len);
+
+  if start >= end {
+    return 0..0;
+  }
+  start as usize..end as usize
+}
+
+fn value_response(value: Option<ValueT>) -> ResponseFrame {
+  match value {
+    Some(value) => ResponseFrame::Value(value),
+    None => ResponseFrame::ValueMissing,
+  }
+}
+
+// Lengths (and counts) are sent as 8 byte little endian unsigned integers.
+fn length_response(len: usize) -> ResponseFrame {
+  ResponseFrame::Value(Vec::from((len as u64).to_le_bytes()))
+}
+
+fn counter_response(counter: Result<i64, ResponseFrame>) -> ResponseFrame {
+  match counter {
+    Ok(value) => ResponseFrame::Value(Vec::from(value.to_le_bytes())),
+    Err(error) => error,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::BTreeMapEngine;
+
+  fn set(key: &str) -> Command {
+    Command::Set {
+      key: key.into(),
+      value: vec![1],
+      expire_at: None,
+    }
+  }
+
+  #[test]
+  fn batch_stops_at_a_failing_command() {
+    let mut keyspace = Keyspace::<BTreeMapEngine<Entry>>::new();
+    let batch = Command::Batch {
+      commands: vec![
+        set("a"),
+        Command::Delete {
+          key: "missing".into(),
+        },
+        set("b"),
+      ],
+    };
+
+    assert!(matches!(
+      keyspace.execute(batch, 0),
+      ResponseFrame::ErrorInternal(_)
+    ));
+    assert!(keyspace.value(&"a".into()).is_none());
+    assert!(keyspace.value(&"b".into()).is_none());
+
+    let batch = Command::Batch {
+      commands: vec![set("b"), set("c")],
+    };
+    assert_eq!(ResponseFrame::Success, keyspace.execute(batch, 1));
+  }
+
+  #[test]
+  fn failed_batch_leaves_the_keyspace_unchanged() {
+    let mut keyspace = Keyspace::<BTreeMapEngine<Entry>>::new();
+    keyspace.set("a".into(), vec![1], Some(u64::MAX), 0);
+    keyspace.set("b".into(), vec![1], None, 1);
+    keyspace.execute(
+      Command::LPush {
+        key: "list".into(),
+        elements: vec![vec![1]],
+      },
+      2,
+    );
+    let used_memory = keyspace.used_memory;
+
+    let batch = Command::Batch {
+      commands: vec![
+        Command::Set {
+          key: "a".into(),
+          value: vec![2],
+          expire_at: None,
+        },
+        Command::Delete { key: "b".into() },
+        Command::FlushNs,
+        set("c"),
+        Command::Incr { key: "c".into() },
+      ],
+    };
+    assert!(matches!(
+      keyspace.execute(batch, 3),
+      ResponseFrame::ErrorInternal(_)
+    ));
+
+    assert!(matches!(
+      keyspace.get_versioned("a".into()),
+      Some((0, Value::Bytes(value))) if value == &vec![1]
+    ));
+    assert_eq!(Some(u64::MAX), keyspace.expire_at(&"a".into()));
+    assert!(matches!(keyspace.get_versioned("b".into()), Some((1, _))));
+    assert!(keyspace.value(&"c".into()).is_none());
+    assert!(matches!(
+      keyspace.value(&"list".into()),
+      Some(Value::List(_))
+    ));
+    assert_eq!(3, keyspace.len());
+    assert_eq!(used_memory, keyspace.used_memory);
+  }
+
+  #[test]
+  fn keys_tell_when_they_stopped_looking_early() {
+    let mut keyspace = Keyspace::<BTreeMapEngine<Entry>>::new();
+    for i in 0..KEYS_MAX_VISITED as u64 {
+      keyspace.set(format!("k{:06}", i).into(), vec![1], None, i);
+    }
+
+    assert_eq!((vec![], false), keyspace.keys_matching(b"k*x", 10));
+    assert_eq!(
+      (vec!["k000000".into(), "k000001".into()], true),
+      keyspace.keys_matching(b"k*", 2)
+    );
+
+    keyspace.set("k999999x".into(), vec![1], None, KEYS_MAX_VISITED as u64);
+    assert_eq!((vec![], true), keyspace.keys_matching(b"k*x", 10));
+    assert_eq!(
+      (vec!["k999999x".into()], false),
+      keyspace.keys_matching(b"k999999*", 10)
+    );
+  }
+
+  fn storage_with(policy: EvictionPolicy) -> Storage<BTreeMapEngine<Entry>> {
+    Storage::new(Some(MemoryLimit::new(0, policy)))
+  }
+
+  fn get(key: &str) -> Command {
+    Command::Get { key: key.into() }
+  }
+
+  #[test]
+  fn lru_evicts_the_least_recently_used_key_of_any_namespace() {
+    let mut storage = storage_with(EvictionPolicy::AllKeysLru);
+    storage
+      .keyspace_mut("a")
+      .set("old".into(), vec![1], None, 0);
+    storage
+      .keyspace_mut("b")
+      .set("new".into(), vec![1], None, 1);
+    assert_eq!(
+      Some(("a".into(), "old".into())),
+      storage.eviction_candidate()
+    );
+
+    storage.read("a", get("old"));
+    assert_eq!(
+      Some(("b".into(), "new".into())),
+      storage.eviction_candidate()
+    );
+  }
+
+  #[test]
+  fn lfu_evicts_the_least_used_key() {
+    let mut storage = storage_with(EvictionPolicy::AllKeysLfu);
+    storage
+      .keyspace_mut("a")
+      .set("hot".into(), vec![1], None, 0);
+    storage
+      .keyspace_mut("a")
+      .set("cold".into(), vec![1], None, 1);
+    storage.read("a", get("hot"));
+    storage.read("a", get("hot"));
+    storage.read("a", get("cold"));
+    assert_eq!(
+      Some(("a".into(), "cold".into())),
+      storage.eviction_candidate()
+    );
+
+    // Rewrites keep the hits of the key.
+    storage
+      .keyspace_mut("a")
+      .set("hot".into(), vec![2], None, 2);
+    assert_eq!(
+      Some(("a".into(), "cold".into())),
+      storage.eviction_candidate()
+    );
+  }
+
+  #[test]
+  fn volatile_ttl_evicts_the_nearest_expiry_only() {
+    let mut storage = storage_with(EvictionPolicy::VolatileTtl);
+    storage
+      .keyspace_mut("a")
+      .set("persisted".into(), vec![1], None, 0);
+    assert_eq!(None, storage.eviction_candidate());
+
+    storage
+      .keyspace_mut("a")
+      .set("late".into(), vec![1], Some(u64::MAX), 1);
+    storage
+      .keyspace_mut("b")
+      .set("soon".into(), vec![1], Some(u64::MAX - 1), 2);
+    assert_eq!(
+      Some(("b".into(), "soon".into())),
+      storage.eviction_candidate()
+    );
+  }
+
+  #[test]
+  fn no_eviction_evicts_nothing() {
+    let mut storage = storage_with(EvictionPolicy::NoEviction);
+    storage
+      .keyspace_mut("a")
+      .set("key".into(), vec![1], Some(1), 0);
+    assert!(storage.is_over_memory_limit());
+    assert_eq!(None, storage.eviction_candidate());
+  }
+}
@@ -0,0 +1,130 @@
+use crate::command::Command;
+use crate::storage::{KeyT, Keyspace, KeyspaceEngine, VersionT};
+
+// MULTI/EXEC state of a connection. Queued commands are sent to the app as a single EXEC command on
+// commit, so the app can run them as one unit.
+#[derive(Default)]
+pub struct Transaction {
+  queued: Option<Vec<Command>>,
+  watches: Vec<(KeyT, Option<VersionT>)>,
+}
+
+impl Transaction {
+  pub fn is_queuing(&self) -> bool {
+    self.queued.is_some()
+  }
+
+  pub fn begin(&mut self) {
+    self.queued = Some(vec![]);
+  }
+
+  // Invalid commands are kept too, they fail on their own when the transaction runs.
+  pub fn queue(&mut self, command: Command) {
+    self.queued.get_or_insert_with(Vec::new).push(command);
+  }
+
+  // Version is the one the key had at WATCH time, None if the key did not exist.
+  pub fn watch(&mut self, key: KeyT, version: Option<VersionT>) {
+    self.watches.push((key, version));
+  }
+
+  pub fn discard(&mut self) {
+    *self = Transaction::default();
+  }
+
+  // Ends the transaction and returns the EXEC command for the app.
+  pub fn take_exec(&mut self) -> Command {
+    let transaction = std::mem::take(self);
+    Command::Exec {
+      watches: transaction.watches,
+      commands: transaction.queued.unwrap_or_default(),
+    }
+  }
+}
+
+// A transaction runs only if none of its watched keys changed (or appeared, or went away) since
+// WATCH.
+pub fn is_watch_intact<E: KeyspaceEngine>(
+  watches: &[(KeyT, Option<VersionT>)],
+  keyspace: &Keyspace<E>,
+) -> bool {
+  watches.iter().all(|(key, watched_version)| {
+    keyspace
+      .get_versioned(key.clone())
+      .map(|(version, _)| version)
+      == *watched_version
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::BTreeMapEngine;
+  use crate::storage::Entry;
+
+  fn get(key: &str) -> Command {
+    Command::Get { key: key.into() }
+  }
+
+  #[test]
+  fn exec_takes_the_queued_commands_and_watches() {
+    let mut transaction = Transaction::default();
+    transaction.watch("a".into(), Some(1));
+    transaction.begin();
+    transaction.queue(get("a"));
+    transaction.queue(Command::Invalid);
+    assert!(transaction.is_queuing());
+
+    match transaction.take_exec() {
+      Command::Exec { watches, commands } => {
+        assert_eq!(vec![("a".into(), Some(1))], watches);
+        assert_eq!(2, commands.len());
+        assert!(matches!(commands[1], Command::Invalid));
+      }
+      _ => panic!("not an EXEC"),
+    }
+    assert!(!transaction.is_queuing());
+    assert!(matches!(
+      transaction.take_exec(),
+      Command::Exec { watches, commands } if watches.is_empty() && commands.is_empty()
+    ));
+  }
+
+  #[test]
+  fn discard_drops_the_commands_and_watches() {
+    let mut transaction = Transaction::default();
+    transaction.watch("a".into(), None);
+    transaction.begin();
+    transaction.queue(get("a"));
+    transaction.discard();
+
+    assert!(!transaction.is_queuing());
+    assert!(matches!(
+      transaction.take_exec(),
+      Command::Exec { watches, commands } if watches.is_empty() && commands.is_empty()
+    ));
+  }
+
+  #[test]
+  fn watch_breaks_on_any_change_of_the_key() {
+    let mut keyspace = Keyspace::<BTreeMapEngine<Entry>>::new();
+    keyspace.set("a".into(), vec![1], None, 1);
+
+    assert!(is_watch_intact(&[], &keyspace));
+    assert!(is_watch_intact(&[("a".into(), Some(1))], &keyspace));
+    assert!(is_watch_intact(&[("b".into(), None)], &keyspace));
+
+    // Rewritten, even with the same value.
+    keyspace.set("a".into(), vec![1], None, 2);
+    assert!(!is_watch_intact(&[("a".into(), Some(1))], &keyspace));
+    // Created since WATCH.
+    keyspace.set("b".into(), vec![1], None, 3);
+    assert!(!is_watch_intact(&[("b".into(), None)], &keyspace));
+    // Deleted since WATCH.
+    keyspace.delete("a".into(), 4);
+    assert!(!is_watch_intact(
+      &[("b".into(), Some(3)), ("a".into(), Some(2))],
+      &keyspace
+    ));
+  }
+}
@@ -1,92 +1,109 @@
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 
+// Frames are `[1 byte: size of size (1, 2 or 4)][size][4 bytes: request id][bytes]`. Responses carry
+// the id of their request, so a connection can have many requests in flight and get the answers in
+// any order. Pushed changes carry the id of the SUBSCRIBE.
+const REQUEST_ID_SIZE: usize = 4;
+
 pub struct Frame {
+  pub request_id: u32,
   pub bytes: Vec<u8>,
 }
 
 impl Frame {
-  fn new(bytes: Vec<u8>) -> Self {
-    Frame { bytes }
+  fn new(request_id: u32, bytes: Vec<u8>) -> Self {
+    Frame { request_id, bytes }
   }
 }
 
-pub struct FramedTcpStream {
+pub struct FramedTcpStream<S = TcpStream> {
   buffer: Vec<u8>,
-  stream: TcpStream,
+  stream: S,
 }
 
 impl FramedTcpStream {
   pub fn new(stream: TcpStream) -> Self {
+    // Frames go out in one write each. Waiting to coalesce them (Nagle) only stalls pipelined
+    // frames behind the peer's delayed acks.
+    let _ = stream.set_nodelay(true);
+
     Self {
       buffer: vec![],
       stream,
     }
   }
 
+  // Separate halves, eg. to read responses on a background task while others write requests.
+  pub fn into_split(
+    self,
+  ) -> (
+    FramedTcpStream<OwnedReadHalf>,
+    FramedTcpStream<OwnedWriteHalf>,
+  ) {
+    let (read_half, write_half) = self.stream.into_split();
+    let reader = FramedTcpStream {
+      buffer: self.buffer,
+      stream: read_half,
+    };
+    let writer = FramedTcpStream {
+      buffer: vec![],
+      stream: write_half,
+    };
+    (reader, writer)
+  }
+}
+
+impl<S: AsyncRead + Unpin> FramedTcpStream<S> {
+  // Nothing is consumed from the buffer until the whole frame arrived, so a cancelled read (eg. as
+  // a `select!` branch) does not lose data.
   pub async fn read_frame(&mut self) -> Option<Frame> {
     let read_len_res = self.read_frame_size().await;
     if read_len_res.is_none() {
       return None;
     }
 
-    let read_len = read_len_res.unwrap();
+    let (header_len, read_len) = read_len_res.unwrap();
 
-    if !self.read_until_buffer_size(read_len).await {
+    if !self.read_until_buffer_size(header_len + read_len).await {
       return None;
     }
 
+    let mut request_id_bytes = [0u8; REQUEST_ID_SIZE];
+    request_id_bytes.copy_from_slice(&self.buffer[header_len - REQUEST_ID_SIZE..header_len]);
+    self.buffer.drain(..header_len);
     let frame_msg: Vec<_> = self.buffer.drain(..read_len).collect();
 
-    Some(Frame::new(frame_msg))
+    Some(Frame::new(u32::from_be_bytes(request_id_bytes), frame_msg))
   }
 
-  pub async fn write_frame(&mut self, mut bytes: Vec<u8>) -> io::Result<()> {
-    let len = bytes.len();
-    if len <= 0xff as usize {
-      bytes.insert(0, len as u8);
-      bytes.insert(0, 1u8);
-    } else if len <= 0xffff {
-      bytes.insert(0, (len & 0xff) as u8);
-      bytes.insert(0, ((len >> 8) & 0xff) as u8);
-      bytes.insert(0, 2u8);
-    } else if len <= 0xffff_ffff {
-      bytes.insert(0, (len & 0xff) as u8);
-      bytes.insert(0, ((len >> 8) & 0xff) as u8);
-      bytes.insert(0, ((len >> 16) & 0xff) as u8);
-      bytes.insert(0, ((len >> 24) & 0xff) as u8);
-      bytes.insert(0, 4u8);
-    } else {
-      panic!("Too large sequence: {}", len);
-    }
-
-    self.stream.write_all(bytes.as_slice()).await
-  }
-
-  async fn read_frame_size(&mut self) -> Option<usize> {
+  // Returns the size of the frame header (including the request id) and of the frame content.
+  async fn read_frame_size(&mut self) -> Option<(usize, usize)> {
     if !self.read_until_buffer_size(1).await {
       return None;
     }
 
-    let byte_size = self.buffer.drain(..1).collect::<Vec<_>>()[0] as usize;
+    let byte_size = self.buffer[0] as usize;
 
     match byte_size {
       1 | 2 | 4 => {
-        if !self.read_until_buffer_size(byte_size).await {
+        if !self.read_until_buffer_size(1 + byte_size).await {
           None
         } else {
-          let len_bytes = self.buffer.drain(..byte_size).collect::<Vec<_>>();
-          match byte_size {
-            1 => Some(len_bytes[0] as usize),
-            2 => Some(((len_bytes[0] as usize) << 8) | len_bytes[1] as usize),
-            4 => Some(
+          let len_bytes = &self.buffer[1..1 + byte_size];
+          let len = match byte_size {
+            1 => len_bytes[0] as usize,
+            2 => ((len_bytes[0] as usize) << 8) | len_bytes[1] as usize,
+            4 => {
               ((len_bytes[0] as usize) << 24)
                 | ((len_bytes[1] as usize) << 16)
                 | ((len_bytes[2] as usize) << 8)
-                | len_bytes[3] as usize,
-            ),
+                | len_bytes[3] as usize
+            }
             _ => unreachable!(),
-          }
+          };
+          Some((1 + byte_size + REQUEST_ID_SIZE, len))
         }
       }
       _ => panic!("Incompatible stream size: {}", byte_size),
@@ -115,3 +132,31 @@ impl FramedTcpStream {
     true
   }
 }
+
+impl<S: AsyncWrite + Unpin> FramedTcpStream<S> {
+  pub async fn write_frame(&mut self, request_id: u32, mut bytes: Vec<u8>) -> io::Result<()> {
+    let len = bytes.len();
+    for byte in request_id.to_be_bytes().iter().rev() {
+      bytes.insert(0, *byte);
+    }
+
+    if len <= 0xff as usize {
+      bytes.insert(0, len as u8);
+      bytes.insert(0, 1u8);
+    } else if len <= 0xffff {
+      bytes.insert(0, (len & 0xff) as u8);
+      bytes.insert(0, ((len >> 8) & 0xff) as u8);
+      bytes.insert(0, 2u8);
+    } else if len <= 0xffff_ffff {
+      bytes.insert(0, (len & 0xff) as u8);
+      bytes.insert(0, ((len >> 8) & 0xff) as u8);
+      bytes.insert(0, ((len >> 16) & 0xff) as u8);
+      bytes.insert(0, ((len >> 24) & 0xff) as u8);
+      bytes.insert(0, 4u8);
+    } else {
+      panic!("Too large sequence: {}", len);
+    }
+
+    self.stream.write_all(bytes.as_slice()).await
+  }
+}
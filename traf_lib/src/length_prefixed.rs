@@ -0,0 +1,73 @@
+use std::convert::TryInto;
+use std::mem::size_of;
+
+type SizeT = u64;
+
+// Encodes a list of byte fields as ([8 bytes: u64 size of field][bytes: field])*, so fields can hold
+// any bytes - including spaces.
+pub fn encode<F: AsRef<[u8]>>(fields: &[F]) -> Vec<u8> {
+  let mut bytes: Vec<u8> = vec![];
+  for field in fields {
+    let field = field.as_ref();
+    bytes.extend_from_slice(&(field.len() as SizeT).to_be_bytes());
+    bytes.extend_from_slice(field);
+  }
+  bytes
+}
+
+pub fn decode(mut bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+  let mut fields: Vec<Vec<u8>> = vec![];
+
+  while !bytes.is_empty() {
+    let (field, rest) = split_first(bytes)?;
+    fields.push(field);
+    bytes = rest;
+  }
+
+  Some(fields)
+}
+
+// Decodes the first field, returning it with the bytes following it.
+pub fn split_first(bytes: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+  let size_len = size_of::<SizeT>();
+  if bytes.len() < size_len {
+    return None;
+  }
+
+  let (size_bytes, rest) = bytes.split_at(size_len);
+  let size = SizeT::from_be_bytes(size_bytes.try_into().ok()?) as usize;
+  if rest.len() < size {
+    return None;
+  }
+
+  let (field, rest) = rest.split_at(size);
+  Some((field.to_vec(), rest))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    let fields: Vec<&[u8]> = vec![b"foo bar", b"", b"\x00\xff"];
+    let decoded = decode(&encode(&fields)[..]).unwrap();
+    assert_eq!(fields, decoded);
+  }
+
+  #[test]
+  fn truncated_input_is_error() {
+    let mut bytes = encode(&[b"foo"]);
+    bytes.pop();
+    assert!(decode(&bytes[..]).is_none());
+  }
+
+  #[test]
+  fn split_first_keeps_rest() {
+    let mut bytes = encode(&[b"a key"]);
+    bytes.extend_from_slice(b" rest");
+    let (field, rest) = split_first(&bytes[..]).unwrap();
+    assert_eq!(b"a key".to_vec(), field);
+    assert_eq!(b" rest", rest);
+  }
+}
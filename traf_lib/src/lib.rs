@@ -2,6 +2,8 @@
 extern crate log;
 
 pub mod frame_reader;
+pub mod length_prefixed;
+pub mod request;
 pub mod response_frame;
 
 #[cfg(test)]
@@ -0,0 +1,278 @@
+use crate::length_prefixed;
+use std::convert::{TryFrom, TryInto};
+
+// Requests are encoded as `[1 byte: opcode]([8 bytes: u64 size of field][bytes: field])*`, see
+// `length_prefixed`. Numbers are 8 byte big endian fields, optional trailing fields can be left off.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Opcode {
+  // Connection level requests.
+  // [protocol version]
+  Hello = 1,
+  Multi = 2,
+  Exec = 3,
+  Discard = 4,
+  // [key]*
+  Watch = 5,
+  // [namespace]
+  Select = 6,
+  // [glob pattern]
+  Subscribe = 7,
+  Snapshot = 8,
+  SnapshotRelease = 9,
+  // [namespace][request]
+  Namespaced = 10,
+  LastReplicationId = 11,
+  // [event log bytes]
+  Sync = 12,
+  // [request]* - mutations only.
+  Batch = 13,
+
+  // [key][value]([expire at unix ms])
+  Set = 16,
+  // [key][value][seconds]
+  SetEx = 17,
+  // [key]([position])
+  Get = 18,
+  // [key][expected version][value]
+  Cas = 19,
+  // [key][value]
+  SetNx = 20,
+  SetXx = 21,
+  GetSet = 22,
+  // [key]
+  Delete = 23,
+  // [src][dst]
+  Rename = 24,
+  RenameNx = 25,
+  Copy = 26,
+  // [key]
+  Incr = 27,
+  Decr = 28,
+  // [key][i64 delta]
+  IncrBy = 29,
+  // [key]*
+  MGet = 30,
+  // ([key][value])*
+  MSet = 31,
+  // [key]*
+  MDel = 32,
+  // [cursor]([prefix]([count]))
+  Scan = 33,
+  // [start][end]
+  Range = 34,
+  // [key][bytes]
+  Append = 35,
+  // [key][i64 start][i64 end]
+  GetRange = 36,
+  // [key][offset][bytes]
+  SetRange = 37,
+  // [key]
+  StrLen = 38,
+  // [key][element]+
+  LPush = 39,
+  RPush = 40,
+  // [key]
+  LPop = 41,
+  RPop = 42,
+  // [key][i64 start][i64 stop]
+  LRange = 43,
+  // [key]([field][value])+
+  HSet = 44,
+  // [key][field]
+  HGet = 45,
+  // [key][field]+
+  HDel = 46,
+  // [key]
+  HGetAll = 47,
+  // [key][member]+
+  SAdd = 48,
+  SRem = 49,
+  // [key]
+  SMembers = 50,
+  // [key][member]
+  SIsMember = 51,
+  // [key][seconds]
+  Expire = 52,
+  // [key][unix ms]
+  PExpireAt = 53,
+  // [key]
+  Ttl = 54,
+  Persist = 55,
+  Stat = 56,
+  // [key]([limit])
+  History = 57,
+  FlushNs = 58,
+  FlushAll = 59,
+  DbSize = 60,
+  // [glob pattern]([limit])
+  Keys = 61,
+  // [key][namespace]
+  Move = 62,
+}
+
+impl TryFrom<u8> for Opcode {
+  type Error = ();
+
+  fn try_from(byte: u8) -> Result<Opcode, Self::Error> {
+    let opcode = match byte {
+      1 => Opcode::Hello,
+      2 => Opcode::Multi,
+      3 => Opcode::Exec,
+      4 => Opcode::Discard,
+      5 => Opcode::Watch,
+      6 => Opcode::Select,
+      7 => Opcode::Subscribe,
+      8 => Opcode::Snapshot,
+      9 => Opcode::SnapshotRelease,
+      10 => Opcode::Namespaced,
+      11 => Opcode::LastReplicationId,
+      12 => Opcode::Sync,
+      13 => Opcode::Batch,
+      16 => Opcode::Set,
+      17 => Opcode::SetEx,
+      18 => Opcode::Get,
+      19 => Opcode::Cas,
+      20 => Opcode::SetNx,
+      21 => Opcode::SetXx,
+      22 => Opcode::GetSet,
+      23 => Opcode::Delete,
+      24 => Opcode::Rename,
+      25 => Opcode::RenameNx,
+      26 => Opcode::Copy,
+      27 => Opcode::Incr,
+      28 => Opcode::Decr,
+      29 => Opcode::IncrBy,
+      30 => Opcode::MGet,
+      31 => Opcode::MSet,
+      32 => Opcode::MDel,
+      33 => Opcode::Scan,
+      34 => Opcode::Range,
+      35 => Opcode::Append,
+      36 => Opcode::GetRange,
+      37 => Opcode::SetRange,
+      38 => Opcode::StrLen,
+      39 => Opcode::LPush,
+      40 => Opcode::RPush,
+      41 => Opcode::LPop,
+      42 => Opcode::RPop,
+      43 => Opcode::LRange,
+      44 => Opcode::HSet,
+      45 => Opcode::HGet,
+      46 => Opcode::HDel,
+      47 => Opcode::HGetAll,
+      48 => Opcode::SAdd,
+      49 => Opcode::SRem,
+      50 => Opcode::SMembers,
+      51 => Opcode::SIsMember,
+      52 => Opcode::Expire,
+      53 => Opcode::PExpireAt,
+      54 => Opcode::Ttl,
+      55 => Opcode::Persist,
+      56 => Opcode::Stat,
+      57 => Opcode::History,
+      58 => Opcode::FlushNs,
+      59 => Opcode::FlushAll,
+      60 => Opcode::DbSize,
+      61 => Opcode::Keys,
+      62 => Opcode::Move,
+      _ => return Err(()),
+    };
+    Ok(opcode)
+  }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Request {
+  pub opcode: Opcode,
+  pub fields: Vec<Vec<u8>>,
+}
+
+impl Request {
+  pub fn new(opcode: Opcode) -> Self {
+    Request {
+      opcode,
+      fields: vec![],
+    }
+  }
+
+  pub fn field(mut self, field: impl AsRef<[u8]>) -> Self {
+    self.fields.push(field.as_ref().to_vec());
+    self
+  }
+
+  pub fn fields<F: AsRef<[u8]>>(mut self, fields: &[F]) -> Self {
+    self
+      .fields
+      .extend(fields.iter().map(|field| field.as_ref().to_vec()));
+    self
+  }
+
+  pub fn number(self, number: u64) -> Self {
+    self.field(number.to_be_bytes())
+  }
+
+  pub fn signed_number(self, number: i64) -> Self {
+    self.field(number.to_be_bytes())
+  }
+}
+
+pub fn decode_number(field: &[u8]) -> Option<u64> {
+  Some(u64::from_be_bytes(field.try_into().ok()?))
+}
+
+pub fn decode_signed_number(field: &[u8]) -> Option<i64> {
+  Some(i64::from_be_bytes(field.try_into().ok()?))
+}
+
+impl From<Request> for Vec<u8> {
+  fn from(request: Request) -> Vec<u8> {
+    let mut bytes = vec![request.opcode as u8];
+    bytes.append(&mut length_prefixed::encode(&request.fields));
+    bytes
+  }
+}
+
+impl TryFrom<Vec<u8>> for Request {
+  type Error = ();
+
+  fn try_from(bytes: Vec<u8>) -> Result<Request, Self::Error> {
+    let (opcode, fields) = bytes.split_first().ok_or(())?;
+    Ok(Request {
+      opcode: Opcode::try_from(*opcode)?,
+      fields: length_prefixed::decode(fields).ok_or(())?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    let request = Request::new(Opcode::Set)
+      .field(b"a key")
+      .field(b"")
+      .number(42);
+    let bytes: Vec<u8> = request.clone().into();
+    assert_eq!(Ok(request), Request::try_from(bytes));
+  }
+
+  #[test]
+  fn opcodes_round_trip() {
+    for byte in 0..=u8::MAX {
+      if let Ok(opcode) = Opcode::try_from(byte) {
+        assert_eq!(byte, opcode as u8);
+      }
+    }
+  }
+
+  #[test]
+  fn malformed_is_error() {
+    assert!(Request::try_from(vec![]).is_err());
+    assert!(Request::try_from(vec![0]).is_err());
+    let mut bytes: Vec<u8> = Request::new(Opcode::Get).field(b"key").into();
+    bytes.pop();
+    assert!(Request::try_from(bytes).is_err());
+  }
+}
@@ -1,10 +1,170 @@
-use std::convert::TryFrom;
+use crate::length_prefixed;
+use std::convert::{TryFrom, TryInto};
 
+pub type KeyValuesT = Vec<(Vec<u8>, Vec<u8>)>;
+
+// The version of the wire protocol, exchanged with HELLO. Bumped on changes that peers of the
+// previous version cannot follow.
+pub const PROTOCOL_VERSION: u64 = 4;
+
+// Metadata of a key. Timestamps are unix ms.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct KeyStat {
+  pub created_at: u64,
+  pub modified_at: u64,
+  // Last read or write of the key.
+  pub accessed_at: u64,
+  // The replication event that last changed the key - on readers the event they applied.
+  pub version: u64,
+  // Bytes held by the value.
+  pub size: u64,
+}
+
+impl KeyStat {
+  const ENCODED_LEN: usize = 40;
+
+  fn encode(&self) -> Vec<u8> {
+    [
+      self.created_at,
+      self.modified_at,
+      self.accessed_at,
+      self.version,
+      self.size,
+    ]
+    .iter()
+    .flat_map(|field| field.to_be_bytes())
+    .collect()
+  }
+
+  fn decode(bytes: &[u8]) -> Result<Self, ()> {
+    if bytes.len() != Self::ENCODED_LEN {
+      return Err(());
+    }
+
+    let fields: Vec<u64> = bytes
+      .chunks(8)
+      .map(|field| u64::from_be_bytes(field.try_into().unwrap()))
+      .collect();
+    Ok(KeyStat {
+      created_at: fields[0],
+      modified_at: fields[1],
+      accessed_at: fields[2],
+      version: fields[3],
+      size: fields[4],
+    })
+  }
+}
+
+// What a server tells about itself in answer to HELLO.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ServerInfo {
+  pub protocol_version: u64,
+  // The build of the server, eg. "0.1.0".
+  pub version: String,
+  // "reader" or "writer".
+  pub role: String,
+  // The last replication event the server logged (writer) or applied (reader), None before the
+  // first one.
+  pub last_replication_id: Option<u64>,
+  // The optional features the server supports, eg. "snapshots".
+  pub capabilities: Vec<String>,
+}
+
+impl ServerInfo {
+  // Fields are length-prefixed: protocol version, version, role, last replication id (empty if
+  // None) and the capabilities.
+  fn encode(self) -> Vec<u8> {
+    let mut fields: Vec<Vec<u8>> = vec![
+      Vec::from(self.protocol_version.to_be_bytes()),
+      self.version.into_bytes(),
+      self.role.into_bytes(),
+      self
+        .last_replication_id
+        .map(|id| Vec::from(id.to_be_bytes()))
+        .unwrap_or_default(),
+    ];
+    fields.extend(self.capabilities.into_iter().map(String::into_bytes));
+    length_prefixed::encode(&fields)
+  }
+
+  fn decode(bytes: &[u8]) -> Result<Self, ()> {
+    let mut fields = length_prefixed::decode(bytes).ok_or(())?.into_iter();
+    let mut next_field = || fields.next().ok_or(());
+
+    let protocol_version = u64::from_be_bytes(next_field()?.try_into().map_err(|_| ())?);
+    let version = String::from_utf8(next_field()?).map_err(|_| ())?;
+    let role = String::from_utf8(next_field()?).map_err(|_| ())?;
+    let last_replication_id = match next_field()? {
+      id if id.is_empty() => None,
+      id => Some(u64::from_be_bytes(id.try_into().map_err(|_| ())?)),
+    };
+    let capabilities = fields
+      .map(|capability| String::from_utf8(capability).map_err(|_| ()))
+      .collect::<Result<Vec<_>, ()>>()?;
+
+    Ok(ServerInfo {
+      protocol_version,
+      version,
+      role,
+      last_replication_id,
+      capabilities,
+    })
+  }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum ResponseFrame {
   Success,
   ErrorInvalidCommand,
   Value(Vec<u8>),
   ValueMissing,
+  VersionedValue(u64, Vec<u8>),
+  ErrorVersionMismatch,
+  // Result of multi-key reads, None for keys that are missing.
+  Values(Vec<Option<Vec<u8>>>),
+  KeyValues(KeyValuesT),
+  // A page of a scan with the cursor to continue from ("0" if the scan is complete).
+  ScanPage(Vec<u8>, KeyValuesT),
+  // A command was queued in a MULTI block.
+  Queued,
+  // The responses of the commands of an EXEC, in order.
+  Results(Vec<ResponseFrame>),
+  // Pushed to subscribers when a matching key changes: the key and its new value (None if deleted).
+  Change(Vec<u8>, Option<Vec<u8>>),
+  // The key holds a different type of value than the command works on.
+  ErrorWrongType,
+  // Elements of a list or members of a set.
+  Elements(Vec<Vec<u8>>),
+  // The write was refused as the storage is over its memory limit and cannot evict keys.
+  ErrorOutOfMemory,
+  Stat(KeyStat),
+  // A set only if absent (SETNX) found the key already there.
+  ErrorKeyExists,
+  // A read at a point of the event log that no snapshot pins, so its versions may be gone.
+  ErrorVersionUnavailable,
+  // Past writes of a key, newest first: the event number and the value set (None if deleted).
+  History(Vec<(u64, Option<Vec<u8>>)>),
+  // The answer to HELLO.
+  Hello(ServerInfo),
+  // A write sent to a reader.
+  ErrorReadOnly,
+  // The request could not be decoded, eg. a missing field or a corrupt SYNC payload.
+  ErrorMalformed,
+  // The key (or pinned snapshot) the command works on does not exist.
+  ErrorNotFound,
+  // The command would grow a value beyond what the server allows.
+  ErrorTooLarge,
+  // The server failed on its side, with what went wrong.
+  ErrorInternal(String),
+  // A counter update would go beyond the range of a 64 bit signed integer.
+  ErrorOverflow,
+  // Pushed to subscribers when all keys of the subscribed namespace were removed (a flush).
+  Flushed,
+  // Pushed to a subscriber that fell behind and missed changes. Its subscriptions end with it.
+  ErrorLagged,
+  // The keys matching a KEYS pattern, and whether the server stopped looking with keys left to look
+  // at - more of them may match, SCAN goes through all keys.
+  Keys(Vec<Vec<u8>>, bool),
 }
 
 impl Into<Vec<u8>> for ResponseFrame {
@@ -17,6 +177,106 @@ impl Into<Vec<u8>> for ResponseFrame {
         v
       }
       ResponseFrame::ValueMissing => vec![3],
+      ResponseFrame::VersionedValue(version, mut v) => {
+        let mut bytes = vec![4];
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.append(&mut v);
+        bytes
+      }
+      ResponseFrame::ErrorVersionMismatch => vec![5],
+      ResponseFrame::Values(values) => {
+        // Each value is encoded as a presence marker + the value itself (empty if missing).
+        let fields: Vec<Vec<u8>> = values
+          .into_iter()
+          .flat_map(|value| match value {
+            Some(v) => vec![vec![1], v],
+            None => vec![vec![0], vec![]],
+          })
+          .collect();
+
+        let mut bytes = vec![6];
+        bytes.append(&mut length_prefixed::encode(&fields));
+        bytes
+      }
+      ResponseFrame::KeyValues(entries) => {
+        let mut bytes = vec![7];
+        bytes.append(&mut encode_key_values(entries, vec![]));
+        bytes
+      }
+      ResponseFrame::ScanPage(cursor, entries) => {
+        let mut bytes = vec![8];
+        bytes.append(&mut encode_key_values(entries, vec![cursor]));
+        bytes
+      }
+      ResponseFrame::Queued => vec![9],
+      ResponseFrame::Results(results) => {
+        let fields: Vec<Vec<u8>> = results.into_iter().map(|result| result.into()).collect();
+
+        let mut bytes = vec![10];
+        bytes.append(&mut length_prefixed::encode(&fields));
+        bytes
+      }
+      ResponseFrame::Change(key, value) => {
+        let mut fields = vec![key];
+        fields.extend(value);
+
+        let mut bytes = vec![11];
+        bytes.append(&mut length_prefixed::encode(&fields));
+        bytes
+      }
+      ResponseFrame::ErrorWrongType => vec![12],
+      ResponseFrame::Elements(elements) => {
+        let mut bytes = vec![13];
+        bytes.append(&mut length_prefixed::encode(&elements));
+        bytes
+      }
+      ResponseFrame::ErrorOutOfMemory => vec![14],
+      ResponseFrame::Stat(stat) => {
+        let mut bytes = vec![15];
+        bytes.append(&mut stat.encode());
+        bytes
+      }
+      ResponseFrame::ErrorKeyExists => vec![16],
+      ResponseFrame::ErrorVersionUnavailable => vec![17],
+      ResponseFrame::History(events) => {
+        // Each event is encoded as its number + a presence marker + the value (empty if deleted).
+        let fields: Vec<Vec<u8>> = events
+          .into_iter()
+          .flat_map(|(number, value)| {
+            let number = Vec::from(number.to_be_bytes());
+            match value {
+              Some(v) => vec![number, vec![1], v],
+              None => vec![number, vec![0], vec![]],
+            }
+          })
+          .collect();
+
+        let mut bytes = vec![18];
+        bytes.append(&mut length_prefixed::encode(&fields));
+        bytes
+      }
+      ResponseFrame::Hello(server_info) => {
+        let mut bytes = vec![19];
+        bytes.append(&mut server_info.encode());
+        bytes
+      }
+      ResponseFrame::ErrorReadOnly => vec![20],
+      ResponseFrame::ErrorMalformed => vec![21],
+      ResponseFrame::ErrorNotFound => vec![22],
+      ResponseFrame::ErrorTooLarge => vec![23],
+      ResponseFrame::ErrorInternal(message) => {
+        let mut bytes = vec![24];
+        bytes.append(&mut message.into_bytes());
+        bytes
+      }
+      ResponseFrame::ErrorOverflow => vec![25],
+      ResponseFrame::Flushed => vec![26],
+      ResponseFrame::ErrorLagged => vec![27],
+      ResponseFrame::Keys(keys, truncated) => {
+        let mut bytes = vec![28, truncated as u8];
+        bytes.append(&mut length_prefixed::encode(&keys));
+        bytes
+      }
     }
   }
 }
@@ -25,6 +285,10 @@ impl TryFrom<Vec<u8>> for ResponseFrame {
   type Error = ();
 
   fn try_from(mut v: Vec<u8>) -> Result<ResponseFrame, Self::Error> {
+    if v.is_empty() {
+      return Err(());
+    }
+
     let type_byte: u8 = v.remove(0);
 
     match type_byte {
@@ -32,7 +296,271 @@ impl TryFrom<Vec<u8>> for ResponseFrame {
       1 => Ok(Self::ErrorInvalidCommand),
       2 => Ok(Self::Value(v)),
       3 => Ok(Self::ValueMissing),
+      4 => {
+        if v.len() < 8 {
+          return Err(());
+        }
+
+        let value = v.split_off(8);
+        let version = u64::from_be_bytes(v.try_into().map_err(|_| ())?);
+        Ok(Self::VersionedValue(version, value))
+      }
+      5 => Ok(Self::ErrorVersionMismatch),
+      6 => {
+        let fields = length_prefixed::decode(&v[..]).ok_or(())?;
+        if fields.len() % 2 == 1 {
+          return Err(());
+        }
+
+        fields
+          .chunks(2)
+          .map(|pair| match pair[0][..] {
+            [1] => Ok(Some(pair[1].clone())),
+            [0] => Ok(None),
+            _ => Err(()),
+          })
+          .collect::<Result<Vec<_>, ()>>()
+          .map(Self::Values)
+      }
+      7 => {
+        let fields = length_prefixed::decode(&v[..]).ok_or(())?;
+        decode_key_values(fields).map(Self::KeyValues)
+      }
+      8 => {
+        let mut fields = length_prefixed::decode(&v[..]).ok_or(())?;
+        if fields.is_empty() {
+          return Err(());
+        }
+
+        let cursor = fields.remove(0);
+        decode_key_values(fields).map(|entries| Self::ScanPage(cursor, entries))
+      }
+      9 => Ok(Self::Queued),
+      10 => length_prefixed::decode(&v[..])
+        .ok_or(())?
+        .into_iter()
+        .map(ResponseFrame::try_from)
+        .collect::<Result<Vec<_>, ()>>()
+        .map(Self::Results),
+      11 => {
+        let mut fields = length_prefixed::decode(&v[..]).ok_or(())?;
+        match fields.len() {
+          1 => Ok(Self::Change(fields.remove(0), None)),
+          2 => {
+            let value = fields.remove(1);
+            Ok(Self::Change(fields.remove(0), Some(value)))
+          }
+          _ => Err(()),
+        }
+      }
+      12 => Ok(Self::ErrorWrongType),
+      13 => length_prefixed::decode(&v[..])
+        .ok_or(())
+        .map(Self::Elements),
+      14 => Ok(Self::ErrorOutOfMemory),
+      15 => KeyStat::decode(&v[..]).map(Self::Stat),
+      16 => Ok(Self::ErrorKeyExists),
+      17 => Ok(Self::ErrorVersionUnavailable),
+      18 => {
+        let fields = length_prefixed::decode(&v[..]).ok_or(())?;
+        if fields.len() % 3 != 0 {
+          return Err(());
+        }
+
+        fields
+          .chunks(3)
+          .map(|event| {
+            let number = u64::from_be_bytes(event[0][..].try_into().map_err(|_| ())?);
+            match event[1][..] {
+              [1] => Ok((number, Some(event[2].clone()))),
+              [0] => Ok((number, None)),
+              _ => Err(()),
+            }
+          })
+          .collect::<Result<Vec<_>, ()>>()
+          .map(Self::History)
+      }
+      19 => ServerInfo::decode(&v[..]).map(Self::Hello),
+      20 => Ok(Self::ErrorReadOnly),
+      21 => Ok(Self::ErrorMalformed),
+      22 => Ok(Self::ErrorNotFound),
+      23 => Ok(Self::ErrorTooLarge),
+      24 => String::from_utf8(v)
+        .map_err(|_| ())
+        .map(Self::ErrorInternal),
+      25 => Ok(Self::ErrorOverflow),
+      26 => Ok(Self::Flushed),
+      27 => Ok(Self::ErrorLagged),
+      28 => {
+        let (truncated, keys) = v.split_first().ok_or(())?;
+        let truncated = match truncated {
+          1 => true,
+          0 => false,
+          _ => return Err(()),
+        };
+        length_prefixed::decode(keys)
+          .ok_or(())
+          .map(|keys| Self::Keys(keys, truncated))
+      }
       _ => Err(()),
     }
   }
 }
+
+fn encode_key_values(entries: KeyValuesT, mut fields: Vec<Vec<u8>>) -> Vec<u8> {
+  for (key, value) in entries {
+    fields.push(key);
+    fields.push(value);
+  }
+  length_prefixed::encode(&fields)
+}
+
+fn decode_key_values(fields: Vec<Vec<u8>>) -> Result<KeyValuesT, ()> {
+  if fields.len() % 2 == 1 {
+    return Err(());
+  }
+
+  Ok(
+    fields
+      .chunks(2)
+      .map(|pair| (pair[0].clone(), pair[1].clone()))
+      .collect(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn versioned_value_round_trip() {
+    let bytes: Vec<u8> = ResponseFrame::VersionedValue(42, vec![1, 2, 3]).into();
+    assert_eq!(
+      Ok(ResponseFrame::VersionedValue(42, vec![1, 2, 3])),
+      ResponseFrame::try_from(bytes)
+    );
+  }
+
+  #[test]
+  fn values_round_trip() {
+    let values = vec![Some(vec![1, 2]), None, Some(vec![])];
+    let bytes: Vec<u8> = ResponseFrame::Values(values.clone()).into();
+    assert_eq!(
+      Ok(ResponseFrame::Values(values)),
+      ResponseFrame::try_from(bytes)
+    );
+  }
+
+  #[test]
+  fn results_round_trip() {
+    let results = vec![ResponseFrame::Success, ResponseFrame::Value(vec![1])];
+    let bytes: Vec<u8> = ResponseFrame::Results(results).into();
+    assert_eq!(
+      Ok(ResponseFrame::Results(vec![
+        ResponseFrame::Success,
+        ResponseFrame::Value(vec![1])
+      ])),
+      ResponseFrame::try_from(bytes)
+    );
+  }
+
+  #[test]
+  fn scan_page_round_trip() {
+    let entries = vec![(b"foo".to_vec(), vec![1]), (b"bar".to_vec(), vec![])];
+    let bytes: Vec<u8> = ResponseFrame::ScanPage(b"0".to_vec(), entries.clone()).into();
+    assert_eq!(
+      Ok(ResponseFrame::ScanPage(b"0".to_vec(), entries)),
+      ResponseFrame::try_from(bytes)
+    );
+  }
+
+  #[test]
+  fn change_round_trip() {
+    for value in [Some(vec![1, 2]), Some(vec![]), None] {
+      let bytes: Vec<u8> = ResponseFrame::Change(b"foo".to_vec(), value.clone()).into();
+      assert_eq!(
+        Ok(ResponseFrame::Change(b"foo".to_vec(), value)),
+        ResponseFrame::try_from(bytes)
+      );
+    }
+    let bytes: Vec<u8> = ResponseFrame::Flushed.into();
+    assert_eq!(Ok(ResponseFrame::Flushed), ResponseFrame::try_from(bytes));
+  }
+
+  #[test]
+  fn history_round_trip() {
+    let events = vec![(3, Some(vec![1, 2])), (2, None), (0, Some(vec![]))];
+    let bytes: Vec<u8> = ResponseFrame::History(events.clone()).into();
+    assert_eq!(
+      Ok(ResponseFrame::History(events)),
+      ResponseFrame::try_from(bytes)
+    );
+  }
+
+  #[test]
+  fn hello_round_trip() {
+    for last_replication_id in [Some(7), None] {
+      let server_info = ServerInfo {
+        protocol_version: PROTOCOL_VERSION,
+        version: "0.1.0".into(),
+        role: "writer".into(),
+        last_replication_id,
+        capabilities: vec!["snapshots".into(), "history".into()],
+      };
+      let bytes: Vec<u8> = ResponseFrame::Hello(server_info.clone()).into();
+      assert_eq!(
+        Ok(ResponseFrame::Hello(server_info)),
+        ResponseFrame::try_from(bytes)
+      );
+    }
+  }
+
+  #[test]
+  fn stat_round_trip() {
+    let stat = KeyStat {
+      created_at: 1,
+      modified_at: 2,
+      accessed_at: 3,
+      version: 4,
+      size: 5,
+    };
+    let bytes: Vec<u8> = ResponseFrame::Stat(stat).into();
+    assert_eq!(
+      Ok(ResponseFrame::Stat(stat)),
+      ResponseFrame::try_from(bytes)
+    );
+  }
+
+  #[test]
+  fn keys_round_trip() {
+    for truncated in [false, true] {
+      let keys = vec![b"a key".to_vec(), vec![]];
+      let bytes: Vec<u8> = ResponseFrame::Keys(keys.clone(), truncated).into();
+      assert_eq!(
+        Ok(ResponseFrame::Keys(keys, truncated)),
+        ResponseFrame::try_from(bytes)
+      );
+    }
+    assert!(ResponseFrame::try_from(vec![28, 2]).is_err());
+  }
+
+  #[test]
+  fn errors_round_trip() {
+    let errors = || {
+      vec![
+        ResponseFrame::ErrorReadOnly,
+        ResponseFrame::ErrorMalformed,
+        ResponseFrame::ErrorNotFound,
+        ResponseFrame::ErrorTooLarge,
+        ResponseFrame::ErrorInternal("disk full".into()),
+        ResponseFrame::ErrorInternal("".into()),
+        ResponseFrame::ErrorOverflow,
+        ResponseFrame::ErrorLagged,
+      ]
+    };
+    for (error, expected) in errors().into_iter().zip(errors()) {
+      let bytes: Vec<u8> = error.into();
+      assert_eq!(Ok(expected), ResponseFrame::try_from(bytes));
+    }
+  }
+}